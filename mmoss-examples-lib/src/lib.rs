@@ -1,12 +1,10 @@
-use async_trait::async_trait;
 use bevy::ecs::component::Component;
 use mmoss::{
     self,
     physics::TransformComponent,
-    replication::{ComponentType, Id, Replicated as _},
+    replication::{ComponentType, Id},
 };
 
-use mmoss::replication;
 use mmoss_proc_macros::Replicated;
 use sdl2::rect::Rect;
 use sdl2::{pixels::Color, render::Canvas};
@@ -18,7 +16,7 @@ use anyhow::{Result, anyhow};
 
 const RENDER_COMPONENT_TYPE: ComponentType = ComponentType(100);
 
-#[derive(Debug, Clone, Component, Replicated)]
+#[derive(Debug, Clone, Default, Component, Replicated)]
 #[component_type(RENDER_COMPONENT_TYPE)]
 pub struct RenderComponent {
     #[replication_id]
@@ -60,26 +58,7 @@ pub fn register_factory_components(
         impl mmoss::core::WorldContainer,
     >,
 ) {
-    factory.register_component(RENDER_COMPONENT_TYPE, RenderComponentFactory);
-}
-
-pub struct RenderComponentFactory;
-
-#[async_trait(?Send)]
-impl<W: mmoss::core::WorldContainer> mmoss::replication::client::factory::component::Entry<W>
-    for RenderComponentFactory
-{
-    async fn add_component(
-        &self,
-        mut entity: bevy::ecs::world::EntityWorldMut<'_>,
-        replication_id: Id,
-        data: &Vec<u8>,
-    ) -> Result<()> {
-        let mut component = RenderComponent::new(replication_id);
-        component.replicate(data)?;
-        entity.insert(component);
-        Ok(())
-    }
+    factory.register_replicated::<RenderComponent>();
 }
 
 pub mod mob {
@@ -101,7 +80,11 @@ pub mod mob {
 
     #[async_trait(?Send)]
     impl<W: core::WorldContainer> MobFactoryEntry<W> for SquareClient {
-        async fn construct(&self, world: &mut W) -> anyhow::Result<Entity> {
+        async fn construct(
+            &self,
+            world: &mut W,
+            _payload: Option<&[u8]>,
+        ) -> anyhow::Result<Entity> {
             Ok(world.world_mut().spawn(SQUARE_TYPE).id())
         }
     }
@@ -49,7 +49,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let plane_component = physics_world
         .create_plane(
             plane_entity.id(),
-            Id(1),
+            Id::UNASSIGNED,
             &material,
             &PlaneShape {
                 normal: Vec3::Y,
@@ -66,7 +66,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Wait for connection
     let tcp = tcp::Listener::bind("127.0.0.1:8080").await.unwrap();
-    let (connection, addr) = tcp.accept(MessageFactoryNew).await.unwrap();
+    let (accepted, addr) = tcp.accept().await.unwrap();
+    let connection = accepted.establish(MessageFactoryNew).await.unwrap();
     info!("Client connected from {}", addr);
 
     let mut manager = Manager::new();
@@ -83,7 +84,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut canvas = window.into_canvas().build().unwrap();
 
-    let mut id: Id = Id(1);
     let mut rng = rand::rng();
     let mut event_pump = sdl_context.event_pump().unwrap();
     let mut to_replicate = EntityHashSet::new();
@@ -96,22 +96,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     ..
                 } => break 'running,
                 Event::MouseButtonDown { x, y, .. } => {
-                    let id0 = id;
-                    id.0 += 1;
-                    let id1 = id;
-                    id.0 += 1;
-
                     let entity = square_server(
                         &mut world,
                         (
-                            id0,
+                            Id::UNASSIGNED,
                             Transform {
                                 translation: bevy::math::Vec3::new(x as f32 / 10.0 + rng.random::<f32>(), y as f32 / 10.0, rng.random::<f32>()),
                                 ..Default::default()
                             },
                         ),
                         (
-                            id1,
+                            Id::UNASSIGNED,
                             (rng.random::<u8>(), rng.random::<u8>(), rng.random::<u8>()),
                         ),
                     )
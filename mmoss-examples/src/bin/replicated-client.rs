@@ -1,19 +1,19 @@
 use bevy::ecs::world::World;
-use bevy_trait_query::{One, RegisterExt};
+use bevy_trait_query::One;
 use log::error;
 use mmoss::net::transport::tcp;
 use mmoss::physics::TransformComponent;
-use mmoss::physics::proxy::DynamicActorComponentProxy;
+use mmoss::physics::proxy::register_proxy_components;
+use mmoss::replication::MessageFactoryNew;
 use mmoss::replication::client::factory;
 use mmoss::replication::client::{
     Manager, NoopUpdateCallbacks, factory::component::Factory as ComponentFactory,
     factory::mob::Factory as MobFactory,
 };
-use mmoss::replication::{MessageFactoryNew, Replicated};
 
 use env_logger;
+use mmoss_examples_lib::RenderComponent;
 use mmoss_examples_lib::mob::SQUARE_TYPE;
-use mmoss_examples_lib::{RenderComponent, register_factory_components};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
@@ -40,9 +40,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut mob_factory = MobFactory::new();
     mob_factory.register_mob(SQUARE_TYPE, mmoss_examples_lib::mob::SquareClient);
 
+    let mut world = World::new();
+    register_proxy_components(&mut world);
+
     let mut component_factory = ComponentFactory::new();
     factory::component::register_default_factory_components(&mut component_factory);
-    register_factory_components(&mut component_factory);
+    factory::component::register_replicated::<RenderComponent, _>(
+        &mut world,
+        &mut component_factory,
+    );
 
     let (mut manager, mut incoming) = Manager::new(
         Box::new(connection),
@@ -58,11 +64,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
-    let mut world = World::new();
-    world.register_component_as::<dyn TransformComponent, DynamicActorComponentProxy>();
-    world.register_component_as::<dyn Replicated, DynamicActorComponentProxy>();
-    world.register_component_as::<dyn Replicated, RenderComponent>();
-
     let mut event_pump = sdl_context.event_pump().unwrap();
     'running: loop {
         for event in event_pump.poll_iter() {
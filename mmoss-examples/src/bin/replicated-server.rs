@@ -34,7 +34,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Wait for connection
     let tcp = tcp::Listener::bind("127.0.0.1:8080").await.unwrap();
-    let (connection, addr) = tcp.accept(MessageFactoryNew).await.unwrap();
+    let (accepted, addr) = tcp.accept().await.unwrap();
+    let connection = accepted.establish(MessageFactoryNew).await.unwrap();
     info!("Client connected from {}", addr);
 
     let mut world = World::new();
@@ -46,20 +47,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     manager.add_client(Box::new(connection));
 
     let mut rng = rand::rng();
-    let mut render_component = RenderComponent::new(Id(2));
+    let mut render_component = RenderComponent::new(Id::UNASSIGNED);
     render_component.color = (rng.random::<u8>(), rng.random::<u8>(), rng.random::<u8>());
 
     let mouse_entity = world
         .spawn((
             SQUARE_TYPE,
-            DynamicActorComponentProxy::new(Id(1)),
+            DynamicActorComponentProxy::new(Id::UNASSIGNED),
             render_component,
         ))
         .id();
     manager.register_new_entity(mouse_entity);
 
-    let mut id: Id = Id(3);
-
     let mut event_pump = sdl_context.event_pump().unwrap();
     'running: loop {
         for event in event_pump.poll_iter() {
@@ -70,22 +69,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     ..
                 } => break 'running,
                 Event::MouseButtonDown { x, y, .. } => {
-                    let id0 = id;
-                    id.0 += 1;
-                    let id1 = id;
-                    id.0 += 1;
-
                     let entity = square_server_no_physics(
                         &mut world,
                         (
-                            id0,
+                            Id::UNASSIGNED,
                             Transform {
                                 translation: bevy::math::Vec3::new(x as f32, y as f32, 0.0),
                                 ..Default::default()
                             },
                         ),
                         (
-                            id1,
+                            Id::UNASSIGNED,
                             (rng.random::<u8>(), rng.random::<u8>(), rng.random::<u8>()),
                         ),
                     )
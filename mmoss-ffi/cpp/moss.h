@@ -64,7 +64,28 @@ void mmoss_client_world_update(struct WorldPtr *world,
                                void (*on_component_added)(uint64_t entity,
                                                           uint32_t spawn_id,
                                                           uint32_t component_type,
-                                                          uint32_t id));
+                                                          uint32_t id),
+                               void (*on_parent_changed)(uint64_t entity,
+                                                         uint64_t parent,
+                                                         bool has_parent),
+                               void (*on_despawn)(uint64_t entity));
+
+bool mmoss_client_world_entity_by_spawn_id(struct WorldPtr *world,
+                                           uint32_t spawn_id,
+                                           uint64_t *out_entity);
+
+bool mmoss_client_world_spawn_id_by_entity(struct WorldPtr *world,
+                                           uint64_t entity,
+                                           uint32_t *out_spawn_id);
+
+bool mmoss_client_world_entity_by_replicated_id(struct WorldPtr *world,
+                                                uint32_t id,
+                                                uint64_t *out_entity);
+
+bool mmoss_client_world_component_by_replicated_id(struct WorldPtr *world,
+                                                   uint32_t id,
+                                                   uint64_t *out_entity,
+                                                   uint32_t *out_component_type);
 
 void mmoss_dynamic_actor_proxy_get_tranform(struct WorldPtr *world,
                                             uint64_t entity,
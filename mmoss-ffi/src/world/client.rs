@@ -4,12 +4,11 @@ use std::{
 };
 
 use bevy::ecs::entity::Entity;
-use bevy_trait_query::RegisterExt as _;
 use log::error;
 use mmoss::{
     core::{self, WorldContainer}, net,
-    physics::{TransformComponent, proxy::DynamicActorComponentProxy},
-    replication::{self, MessageFactoryNew, Replicated, SpawnId, client::UpdateCallbacks},
+    physics::proxy::{DynamicActorComponentProxy, register_proxy_components},
+    replication::{self, MessageFactoryNew, SpawnId, client::UpdateCallbacks},
 };
 
 use crate::types::{Quat, Vec3};
@@ -158,8 +157,7 @@ pub extern "C" fn mmoss_client_world_new(
     }
 
     let mut bevy_world = bevy::ecs::world::World::new();
-    bevy_world.register_component_as::<dyn TransformComponent, DynamicActorComponentProxy>();
-    bevy_world.register_component_as::<dyn Replicated, DynamicActorComponentProxy>();
+    register_proxy_components(&mut bevy_world);
 
     let mob_factory = unsafe { &*(mob_factory as *const MobFactoryObj) };
     let component_factory = unsafe { &*(component_factory as *const ComponentFactoryObj) };
@@ -174,6 +172,9 @@ pub extern "C" fn mmoss_client_world_new(
         let mut incoming = incoming;
         loop {
             if let Err(e) = incoming.process_incoming().await {
+                if incoming.is_closed() {
+                    break;
+                }
                 error!("Error processing incoming message: {:?}", e);
             }
         }
@@ -204,6 +205,8 @@ pub struct ClientWorldUpdateCallbacks {
     pub on_component_updated: Option<unsafe extern "C" fn(entity: u64, id: u32)>,
     pub on_component_added:
         Option<unsafe extern "C" fn(entity: u64, spawn_id: u32, component_type: u32, id: u32)>,
+    pub on_parent_changed: Option<unsafe extern "C" fn(entity: u64, parent: u64, has_parent: bool)>,
+    pub on_despawn: Option<unsafe extern "C" fn(entity: u64)>,
 }
 
 impl UpdateCallbacks for ClientWorldUpdateCallbacks {
@@ -241,6 +244,26 @@ impl UpdateCallbacks for ClientWorldUpdateCallbacks {
             }
         }
     }
+
+    fn on_parent_changed(&mut self, entity: Entity, _spawn_id: SpawnId, parent: Option<Entity>) {
+        if let Some(callback) = self.on_parent_changed {
+            unsafe {
+                callback(
+                    entity.to_bits(),
+                    parent.unwrap_or(Entity::PLACEHOLDER).to_bits(),
+                    parent.is_some(),
+                );
+            }
+        }
+    }
+
+    fn on_despawn(&mut self, entity: Entity, _spawn_id: SpawnId) {
+        if let Some(callback) = self.on_despawn {
+            unsafe {
+                callback(entity.to_bits());
+            }
+        }
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -251,6 +274,8 @@ pub extern "C" fn mmoss_client_world_update(
     on_component_added: Option<
         unsafe extern "C" fn(entity: u64, spawn_id: u32, component_type: u32, id: u32),
     >,
+    on_parent_changed: Option<unsafe extern "C" fn(entity: u64, parent: u64, has_parent: bool)>,
+    on_despawn: Option<unsafe extern "C" fn(entity: u64)>,
 ) {
     if world.is_null() {
         error!("Null world passed to client_world_update");
@@ -261,6 +286,8 @@ pub extern "C" fn mmoss_client_world_update(
         on_spawn: on_spawn,
         on_component_updated,
         on_component_added,
+        on_parent_changed,
+        on_despawn,
     };
 
     let world = unsafe { &mut *(world as *mut WorldObj) };
@@ -272,6 +299,103 @@ pub extern "C" fn mmoss_client_world_update(
     });
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn mmoss_client_world_entity_by_spawn_id(
+    world: *mut WorldPtr,
+    spawn_id: u32,
+    out_entity: *mut u64,
+) -> bool {
+    if world.is_null() || out_entity.is_null() {
+        error!("Null pointer passed to mmoss_client_world_entity_by_spawn_id");
+        return false;
+    }
+
+    let world = unsafe { &*(world as *const WorldObj) };
+    match world.replication_manager.entity_by_spawn_id(SpawnId(spawn_id)) {
+        Some(entity) => {
+            unsafe { *out_entity = entity.to_bits() };
+            true
+        }
+        None => false,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mmoss_client_world_spawn_id_by_entity(
+    world: *mut WorldPtr,
+    entity: u64,
+    out_spawn_id: *mut u32,
+) -> bool {
+    if world.is_null() || out_spawn_id.is_null() {
+        error!("Null pointer passed to mmoss_client_world_spawn_id_by_entity");
+        return false;
+    }
+
+    let world = unsafe { &*(world as *const WorldObj) };
+    match world
+        .replication_manager
+        .spawn_id_by_entity(Entity::from_bits(entity))
+    {
+        Some(spawn_id) => {
+            unsafe { *out_spawn_id = spawn_id.0 };
+            true
+        }
+        None => false,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mmoss_client_world_entity_by_replicated_id(
+    world: *mut WorldPtr,
+    id: u32,
+    out_entity: *mut u64,
+) -> bool {
+    if world.is_null() || out_entity.is_null() {
+        error!("Null pointer passed to mmoss_client_world_entity_by_replicated_id");
+        return false;
+    }
+
+    let world = unsafe { &*(world as *const WorldObj) };
+    match world
+        .replication_manager
+        .entity_by_replicated_id(replication::Id(id))
+    {
+        Some(entity) => {
+            unsafe { *out_entity = entity.to_bits() };
+            true
+        }
+        None => false,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn mmoss_client_world_component_by_replicated_id(
+    world: *mut WorldPtr,
+    id: u32,
+    out_entity: *mut u64,
+    out_component_type: *mut u32,
+) -> bool {
+    if world.is_null() || out_entity.is_null() || out_component_type.is_null() {
+        error!("Null pointer passed to mmoss_client_world_component_by_replicated_id");
+        return false;
+    }
+
+    let world = unsafe { &*(world as *const WorldObj) };
+    match world
+        .replication_manager
+        .component_by_replicated_id(replication::Id(id))
+    {
+        Some((entity, component_type)) => {
+            unsafe {
+                *out_entity = entity.to_bits();
+                *out_component_type = component_type.0;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn mmoss_dynamic_actor_proxy_get_tranform(
     world: *mut WorldPtr,
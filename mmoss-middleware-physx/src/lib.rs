@@ -12,7 +12,7 @@ use mmoss::{
         STATIC_ACTOR_COMPONENT_TYPE, STATIC_ACTOR_PROXY_COMPONENT_TYPE,
     },
     physics::{self, DynamicActorComponent as _, Shape, Transform},
-    replication::{self, Id},
+    replication::Id,
 };
 use mmoss_proc_macros::Replicated;
 use physx::{prelude::*, traits::Class as _};
@@ -1,16 +1,350 @@
-use quote::quote;
-use syn::{Data, DeriveInput, Expr, Meta, parse_macro_input};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote, quote_spanned};
+use syn::{
+    Data, DeriveInput, Expr, ExprLit, Fields, Index, Lit, Member, Meta, Path, Type,
+    parse_macro_input, spanned::Spanned,
+};
 
+/// How a replicated field is encoded
+enum Encoding {
+    Plain,
+    /// Converted to and from the given wrapper type
+    IntoFrom(Path),
+    /// `convert::Half`
+    Half,
+    /// `convert::SmallestThree`
+    SmallestThree,
+    /// `convert::Quantized` with the given `convert::FixedPoint` parameters
+    Quantize(Box<QuantizeArgs>),
+    /// Packed into the leading bit block with the given width, see `bits::BitPacked`
+    Bits(Expr),
+}
+
+/// Parameters of `#[replicated(quantize(min = ..., max = ..., precision = ...))]`
+struct QuantizeArgs {
+    min: Expr,
+    max: Expr,
+    precision: Expr,
+}
+
+/// A field marked with `#[replicated]`
+struct ReplicatedField {
+    member: Member,
+    encoding: Encoding,
+}
+
+/// The replication relevant fields of a struct or enum variant
+struct FieldSet {
+    id: Member,
+    replicated: Vec<ReplicatedField>,
+    /// Every field of the struct or variant with its type, in declaration order
+    all: Vec<(Member, Type)>,
+}
+
+fn parse_fields(fields: &Fields, name: &dyn quote::ToTokens) -> syn::Result<FieldSet> {
+    let mut id = None;
+    let mut replicated = Vec::new();
+    let mut all = Vec::new();
+
+    for (index, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(index)),
+        };
+        all.push((member.clone(), field.ty.clone()));
+
+        for attr in &field.attrs {
+            if attr.path().is_ident("replication_id") {
+                if id.is_some() {
+                    return Err(syn::Error::new_spanned(
+                        field,
+                        "Only one field can be marked with replication_id",
+                    ));
+                }
+                id = Some(member.clone());
+            }
+
+            if attr.path().is_ident("replicated") {
+                let mut encoding = Encoding::Plain;
+
+                if let Meta::List(_) = attr.meta {
+                    attr.parse_nested_meta(|meta| {
+                        if meta.path.is_ident("into_from") {
+                            match meta.value()?.parse::<Expr>()? {
+                                Expr::Path(path) => encoding = Encoding::IntoFrom(path.path),
+                                e => {
+                                    return Err(syn::Error::new_spanned(
+                                        e,
+                                        "into_from must be a path",
+                                    ));
+                                }
+                            }
+                        } else if meta.path.is_ident("half") {
+                            encoding = Encoding::Half;
+                        } else if meta.path.is_ident("bits") {
+                            encoding = Encoding::Bits(meta.value()?.parse::<Expr>()?);
+                        } else if meta.path.is_ident("smallest_three") {
+                            encoding = Encoding::SmallestThree;
+                        } else if meta.path.is_ident("quantize") {
+                            let mut min = None;
+                            let mut max = None;
+                            let mut precision = None;
+                            meta.parse_nested_meta(|inner| {
+                                let target = if inner.path.is_ident("min") {
+                                    &mut min
+                                } else if inner.path.is_ident("max") {
+                                    &mut max
+                                } else if inner.path.is_ident("precision") {
+                                    &mut precision
+                                } else {
+                                    return Err(inner.error("expected min, max or precision"));
+                                };
+                                *target = Some(inner.value()?.parse::<Expr>()?);
+                                Ok(())
+                            })?;
+
+                            match (min, max, precision) {
+                                (Some(min), Some(max), Some(precision)) => {
+                                    encoding = Encoding::Quantize(Box::new(QuantizeArgs {
+                                        min,
+                                        max,
+                                        precision,
+                                    }))
+                                }
+                                _ => {
+                                    return Err(
+                                        meta.error("quantize requires min, max and precision")
+                                    );
+                                }
+                            }
+                        }
+
+                        Ok(())
+                    })?;
+                }
+
+                replicated.push(ReplicatedField {
+                    member: member.clone(),
+                    encoding,
+                });
+            }
+        }
+    }
+
+    let id =
+        id.ok_or_else(|| syn::Error::new_spanned(name, "No field marked with #[replication_id]"))?;
+
+    Ok(FieldSet {
+        id,
+        replicated,
+        all,
+    })
+}
+
+/// Path to the mmoss crate, `::mmoss` unless overridden with `#[mmoss(crate = ...)]`
+fn crate_path(input: &DeriveInput) -> syn::Result<Path> {
+    let mut path = syn::parse_quote!(::mmoss);
+
+    for attr in &input.attrs {
+        if attr.path().is_ident("mmoss") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("crate") {
+                    path = match meta.value()?.parse::<Expr>()? {
+                        Expr::Path(expr) => expr.path,
+                        Expr::Lit(ExprLit {
+                            lit: Lit::Str(lit), ..
+                        }) => lit.parse()?,
+                        e => {
+                            return Err(syn::Error::new_spanned(e, "crate must be a path"));
+                        }
+                    };
+                    Ok(())
+                } else {
+                    Err(meta.error("expected crate = <path>"))
+                }
+            })?;
+        }
+    }
+
+    Ok(path)
+}
+
+fn serialize_field(field: &ReplicatedField, place: &TokenStream, krate: &Path) -> TokenStream {
+    let bincode = quote! { #krate::__private::bincode };
+    let convert = quote! { #krate::replication::convert };
+    let clone = quote! { ::core::clone::Clone::clone(&#place) };
+    let value = match &field.encoding {
+        Encoding::Plain => quote! { &#place },
+        Encoding::IntoFrom(path) => quote! { &#path::from(#place) },
+        Encoding::Half => quote! { &#convert::Half(#clone) },
+        Encoding::SmallestThree => quote! { &#convert::SmallestThree(#clone) },
+        Encoding::Quantize(args) => {
+            let QuantizeArgs {
+                min,
+                max,
+                precision,
+            } = &**args;
+            quote! {
+            &#convert::Quantized(
+                #clone,
+                #convert::FixedPoint::new(#min, #max, #precision),
+            )
+            }
+        }
+        Encoding::Bits(width) => {
+            return quote! { bits.write(&#place, (#width) as u32)?; };
+        }
+    };
+
+    quote! {
+        cursor += #bincode::encode_into_slice(
+            #value,
+            &mut data[cursor..],
+            #bincode::config::standard(),
+        )?;
+    }
+}
+
+fn deserialize_field(field: &ReplicatedField, place: &TokenStream, krate: &Path) -> TokenStream {
+    let bincode = quote! { #krate::__private::bincode };
+    let convert = quote! { #krate::replication::convert };
+    let decode = |ty: TokenStream, assign: TokenStream| {
+        quote! {
+            let (value, bytes_read): (#ty, _) = #bincode::decode_from_slice(
+                &data[cursor..],
+                #bincode::config::standard(),
+            )?;
+            #place = #assign;
+            cursor += bytes_read;
+        }
+    };
+
+    match &field.encoding {
+        Encoding::Plain => decode(quote! { _ }, quote! { value }),
+        Encoding::IntoFrom(path) => decode(quote! { #path }, quote! { value.into() }),
+        Encoding::Half => decode(quote! { #convert::Half<_> }, quote! { value.0 }),
+        Encoding::SmallestThree => decode(quote! { #convert::SmallestThree }, quote! { value.0 }),
+        Encoding::Quantize(args) => {
+            let QuantizeArgs {
+                min,
+                max,
+                precision,
+            } = &**args;
+            quote! {
+            let (value, bytes_read): (#convert::Quantized<_>, _) =
+                #bincode::decode_from_slice_with_context(
+                    &data[cursor..],
+                    #bincode::config::standard(),
+                    #convert::FixedPoint::new(#min, #max, #precision),
+                )?;
+            #place = value.0;
+            cursor += bytes_read;
+            }
+        }
+        Encoding::Bits(width) => quote! { #place = bits.read((#width) as u32)?; },
+    }
+}
+
+/// Bit packed fields first, in a block rounded up to whole bytes, then the byte-aligned ones
+fn serialize_fields(
+    fields: &[ReplicatedField],
+    places: &[TokenStream],
+    krate: &Path,
+) -> TokenStream {
+    let (packed, aligned): (Vec<_>, Vec<_>) = fields
+        .iter()
+        .zip(places)
+        .partition(|(field, _)| matches!(field.encoding, Encoding::Bits(_)));
+    let packed = packed
+        .into_iter()
+        .map(|(field, place)| serialize_field(field, place, krate))
+        .collect::<Vec<_>>();
+    let aligned = aligned
+        .into_iter()
+        .map(|(field, place)| serialize_field(field, place, krate));
+
+    let block = if packed.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            let mut bits = #krate::replication::bits::BitWriter::new(&mut data[cursor..]);
+            #(#packed)*
+            cursor += bits.finish();
+        }
+    };
+
+    quote! {
+        #block
+        #(#aligned)*
+    }
+}
+
+fn deserialize_fields(
+    fields: &[ReplicatedField],
+    places: &[TokenStream],
+    krate: &Path,
+) -> TokenStream {
+    let (packed, aligned): (Vec<_>, Vec<_>) = fields
+        .iter()
+        .zip(places)
+        .partition(|(field, _)| matches!(field.encoding, Encoding::Bits(_)));
+    let packed = packed
+        .into_iter()
+        .map(|(field, place)| deserialize_field(field, place, krate))
+        .collect::<Vec<_>>();
+    let aligned = aligned
+        .into_iter()
+        .map(|(field, place)| deserialize_field(field, place, krate));
+
+    let block = if packed.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            let mut bits = #krate::replication::bits::BitReader::new(&data[cursor..]);
+            #(#packed)*
+            cursor += bits.finish();
+        }
+    };
+
+    quote! {
+        #block
+        #(#aligned)*
+    }
+}
+
+/// Derives `replication::Replicated` for structs, tuple structs and enums
+///
+/// Enums replicate a variant tag ahead of the variant's fields. When an update carries a
+/// different variant than the local one, the new variant is built with
+/// [`Default::default()`] for every field except the `#[replication_id]` one before the
+/// replicated fields are applied, so all other fields of enum variants must implement
+/// [`Default`].
 #[proc_macro_derive(
     Replicated,
-    attributes(replicated, replication_id, component_type, replicated_component_type)
+    attributes(
+        replicated,
+        replication_id,
+        component_type,
+        replicated_component_type,
+        update_policy,
+        size_hint,
+        mmoss
+    )
 )]
 pub fn derive_replicated(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
 
-    let name = input.ident;
+    let krate = match crate_path(&input) {
+        Ok(krate) => krate,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let replication = quote! { #krate::replication };
+
+    let name = &input.ident;
     let mut component_type = None;
     let mut replicated_component_type = None;
+    let mut update_policy = Vec::new();
+    let mut size_hint = None;
 
     for attr in &input.attrs {
         if attr.path().is_ident("component_type") {
@@ -43,6 +377,47 @@ pub fn derive_replicated(input: proc_macro::TokenStream) -> proc_macro::TokenStr
 
             replicated_component_type = Some(expr.unwrap());
         }
+
+        if attr.path().is_ident("size_hint") {
+            match attr.parse_args::<Expr>() {
+                Ok(expr) => size_hint = Some(expr),
+                Err(e) => {
+                    return syn::Error::new_spanned(
+                        attr,
+                        format!("size_hint must be a valid expression: {}", e),
+                    )
+                    .to_compile_error()
+                    .into();
+                }
+            }
+        }
+
+        if attr.path().is_ident("update_policy") {
+            let result = attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("max_rate") {
+                    let rate = meta.value()?.parse::<Expr>()?;
+                    update_policy.push(quote! { .with_max_rate((#rate) as f64) });
+                } else if meta.path.is_ident("unreliable") {
+                    update_policy.push(quote! {
+                        .with_reliability(#replication::policy::Reliability::Unreliable)
+                    });
+                } else if meta.path.is_ident("on_change_only") {
+                    update_policy.push(quote! { .with_on_change_only(true) });
+                } else if meta.path.is_ident("owner_only") {
+                    update_policy.push(quote! { .with_owner_only(true) });
+                } else {
+                    return Err(meta.error(
+                        "expected one of max_rate = <expr>, unreliable, on_change_only, owner_only",
+                    ));
+                }
+
+                Ok(())
+            });
+
+            if let Err(e) = result {
+                return e.to_compile_error().into();
+            }
+        }
     }
 
     if component_type.is_none() {
@@ -58,130 +433,269 @@ pub fn derive_replicated(input: proc_macro::TokenStream) -> proc_macro::TokenStr
 
     let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
 
-    let mut replication_id_field = None;
-    let mut serialize = Vec::new();
-    let mut deserialize = Vec::new();
+    let id;
+    let set_id;
+    let serialize;
+    let deserialize;
 
-    match input.data {
+    match &input.data {
         Data::Struct(data) => {
-            for field in data.fields.iter() {
-                for attr in &field.attrs {
-                    if attr.path().is_ident("replication_id") {
-                        if replication_id_field.is_some() {
-                            return syn::Error::new_spanned(
-                                field,
-                                "Only one field can be marked with replication_id",
-                            )
-                            .to_compile_error()
-                            .into();
-                        }
-                        replication_id_field = Some(field.ident.clone());
-                    }
+            let fields = match parse_fields(&data.fields, name) {
+                Ok(fields) => fields,
+                Err(e) => return e.to_compile_error().into(),
+            };
 
-                    if attr.path().is_ident("replicated") {
-                        let mut into_from_path = None;
-
-                        if let Meta::List(_) = attr.meta {
-                            let result = attr.parse_nested_meta(|meta| {
-                                if meta.path.is_ident("into_from") {
-                                    match meta.value()?.parse::<Expr>()? {
-                                        Expr::Path(path) => into_from_path = Some(path.path),
-                                        e => {
-                                            return Err(syn::Error::new_spanned(
-                                                e,
-                                                "into_from must be a path",
-                                            ));
-                                        }
-                                    }
-                                }
+            let id_member = &fields.id;
+            id = quote! { self.#id_member };
+            set_id = quote! { self.#id_member = id; };
 
-                                Ok(())
-                            });
+            let places = fields
+                .replicated
+                .iter()
+                .map(|field| {
+                    let member = &field.member;
+                    quote! { self.#member }
+                })
+                .collect::<Vec<_>>();
+            serialize = serialize_fields(&fields.replicated, &places, &krate);
+            deserialize = deserialize_fields(&fields.replicated, &places, &krate);
+        }
+        Data::Enum(data) => {
+            if data.variants.is_empty() {
+                return syn::Error::new_spanned(
+                    name,
+                    "Replicated can't be derived for empty enums",
+                )
+                .to_compile_error()
+                .into();
+            }
 
-                            if result.is_err() {
-                                return result.unwrap_err().to_compile_error().into();
-                            }
-                        }
+            let bincode = quote! { #krate::__private::bincode };
+            let mut id_arms = Vec::new();
+            let mut set_id_arms = Vec::new();
+            let mut serialize_arms = Vec::new();
+            let mut deserialize_arms = Vec::new();
+
+            for (tag, variant) in data.variants.iter().enumerate() {
+                let tag = tag as u32;
+                let variant_name = &variant.ident;
+                let fields = match parse_fields(&variant.fields, variant) {
+                    Ok(fields) => fields,
+                    Err(e) => return e.to_compile_error().into(),
+                };
+
+                let id_member = &fields.id;
+                id_arms.push(quote! { Self::#variant_name { #id_member: id, .. } => *id });
+                set_id_arms.push(quote! {
+                    Self::#variant_name { #id_member: current, .. } => *current = id
+                });
 
-                        let ident = &field.ident;
-                        if let Some(path) = into_from_path {
-                            serialize.push(quote! {
-                                cursor += bincode::encode_into_slice(
-                                    &#path::from(self.#ident),
-                                    &mut data[cursor..],
-                                    bincode::config::standard(),
-                                )?;
-                            });
-                            deserialize.push(quote! {
-                                let (value, bytes_read): (#path, _) = bincode::decode_from_slice(
-                                    &data[cursor..],
-                                    bincode::config::standard(),
-                                )?;
-                                self.#ident = value.into();
-                                cursor += bytes_read;
-                            });
-                        } else {
-                            serialize.push(quote! {
-                                cursor += bincode::encode_into_slice(
-                                    &self.#ident,
-                                    &mut data[cursor..],
-                                    bincode::config::standard(),
-                                )?;
-                            });
-                            deserialize.push(quote! {
-                                let (value, bytes_read) = bincode::decode_from_slice(
-                                    &data[cursor..],
-                                    bincode::config::standard(),
-                                )?;
-                                self.#ident = value;
-                                cursor += bytes_read;
-                            });
+                let members = fields
+                    .replicated
+                    .iter()
+                    .map(|field| &field.member)
+                    .collect::<Vec<_>>();
+                let bindings = (0..members.len())
+                    .map(|index| format_ident!("__field{}", index))
+                    .collect::<Vec<_>>();
+                let places = bindings
+                    .iter()
+                    .map(|binding| quote! { (*#binding) })
+                    .collect::<Vec<_>>();
+
+                let fields_serialize = serialize_fields(&fields.replicated, &places, &krate);
+                serialize_arms.push(quote! {
+                    Self::#variant_name { #(#members: #bindings,)* .. } => {
+                        cursor += #bincode::encode_into_slice(
+                            &#tag,
+                            &mut data[cursor..],
+                            #bincode::config::standard(),
+                        )?;
+                        #fields_serialize
+                    }
+                });
+
+                // Switching variants starts from defaults, keeping the replication ID. Spanned
+                // on the field so a missing `Default` points at its type.
+                let defaults = fields
+                    .all
+                    .iter()
+                    .filter(|(member, _)| member != id_member)
+                    .map(|(member, ty)| {
+                        quote_spanned! {ty.span()=>
+                            #member: <#ty as ::core::default::Default>::default()
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let fields_deserialize = deserialize_fields(&fields.replicated, &places, &krate);
+                deserialize_arms.push(quote! {
+                    #tag => {
+                        if !matches!(self, Self::#variant_name { .. }) {
+                            *self = Self::#variant_name {
+                                #id_member: id,
+                                #(#defaults,)*
+                            };
+                        }
+                        if let Self::#variant_name { #(#members: #bindings,)* .. } = self {
+                            #fields_deserialize
                         }
                     }
-                }
+                });
             }
+
+            id = quote! {
+                match self {
+                    #(#id_arms,)*
+                }
+            };
+            set_id = quote! {
+                match self {
+                    #(#set_id_arms,)*
+                }
+            };
+            serialize = quote! {
+                match self {
+                    #(#serialize_arms)*
+                }
+            };
+            deserialize = quote! {
+                let (tag, bytes_read): (u32, _) = #bincode::decode_from_slice(
+                    &data[cursor..],
+                    #bincode::config::standard(),
+                )?;
+                cursor += bytes_read;
+
+                let id = #replication::Replicated::id(self);
+                match tag {
+                    #(#deserialize_arms)*
+                    tag => {
+                        return Err(#krate::__private::anyhow::anyhow!(
+                            "Unknown variant tag {} for {}",
+                            tag,
+                            stringify!(#name)
+                        ));
+                    }
+                }
+            };
         }
-        _ => {
-            return syn::Error::new_spanned(name, "Replicated can only be derived for structs")
+        Data::Union(_) => {
+            return syn::Error::new_spanned(name, "Replicated can't be derived for unions")
                 .to_compile_error()
                 .into();
         }
     }
 
-    if replication_id_field.is_none() {
-        return syn::Error::new_spanned(name, "No field marked with #[replication_id]")
-            .to_compile_error()
-            .into();
-    }
+    let update_policy = if update_policy.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            fn update_policy(&self) -> #replication::policy::UpdatePolicy {
+                #replication::policy::UpdatePolicy::default() #(#update_policy)*
+            }
+        }
+    };
 
-    let serialize = quote! { #(#serialize)* };
-    let deserialize = quote! { #(#deserialize)* };
+    // Varint encoding rarely exceeds the in-memory size, except for heap allocated data
+    let size_hint = match size_hint {
+        Some(expr) => quote! { (#expr) as usize },
+        None => quote! { ::core::mem::size_of::<Self>() + 8 },
+    };
 
     let expanded = quote! {
-        impl #impl_generics replication::Replicated for #name #ty_generics #where_clause {
-            fn id(&self) -> replication::Id {
-                self.#replication_id_field
+        impl #impl_generics #replication::Replicated for #name #ty_generics #where_clause {
+            fn id(&self) -> #replication::Id {
+                #id
+            }
+
+            fn set_id(&mut self, id: #replication::Id) {
+                #set_id
             }
 
-            fn replicated_component_type(&self) -> replication::ComponentType {
+            fn replicated_component_type(&self) -> #replication::ComponentType {
                 #replicated_component_type
             }
 
-            fn component_type(&self) -> replication::ComponentType {
+            fn component_type(&self) -> #replication::ComponentType {
                 #component_type
             }
 
-            fn serialize(&self, data: &mut [u8]) -> ::anyhow::Result<usize> {
+            fn serialize(&self, data: &mut [u8]) -> #krate::__private::anyhow::Result<usize> {
                 let mut cursor = 0;
-                #serialize;
+                #serialize
                 Ok(cursor)
             }
 
-            fn replicate(&mut self, data: &[u8]) -> ::anyhow::Result<usize> {
+            fn replicate(&mut self, data: &[u8]) -> #krate::__private::anyhow::Result<usize> {
                 let mut cursor = 0;
-                #deserialize;
+                #deserialize
                 Ok(cursor)
             }
+
+            fn size_hint(&self) -> usize {
+                #size_hint
+            }
+
+            #update_policy
+        }
+    };
+
+    expanded.into()
+}
+
+/// Derives `bits::BitPacked` for fieldless enums, packing the variant's position
+#[proc_macro_derive(BitPacked, attributes(mmoss))]
+pub fn derive_bit_packed(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let krate = match crate_path(&input) {
+        Ok(krate) => krate,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let anyhow = quote! { #krate::__private::anyhow };
+
+    let name = &input.ident;
+    let Data::Enum(data) = &input.data else {
+        return syn::Error::new_spanned(name, "BitPacked can only be derived for enums")
+            .to_compile_error()
+            .into();
+    };
+    if let Some(variant) = data
+        .variants
+        .iter()
+        .find(|variant| !matches!(variant.fields, Fields::Unit))
+    {
+        return syn::Error::new_spanned(variant, "BitPacked variants can't have fields")
+            .to_compile_error()
+            .into();
+    }
+
+    let variants = data
+        .variants
+        .iter()
+        .map(|variant| &variant.ident)
+        .collect::<Vec<_>>();
+    let indices = (0..variants.len() as u64).collect::<Vec<_>>();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let expanded = quote! {
+        impl #impl_generics #krate::replication::bits::BitPacked for #name #ty_generics #where_clause {
+            fn to_bits(&self, width: u32) -> #anyhow::Result<u64> {
+                let index: u64 = match self {
+                    #(Self::#variants => #indices,)*
+                };
+                #krate::replication::bits::BitPacked::to_bits(&index, width)
+            }
+
+            fn from_bits(bits: u64, _width: u32) -> #anyhow::Result<Self> {
+                match bits {
+                    #(#indices => Ok(Self::#variants),)*
+                    _ => Err(#anyhow::anyhow!(
+                        "Invalid {} variant {}",
+                        stringify!(#name),
+                        bits
+                    )),
+                }
+            }
         }
     };
 
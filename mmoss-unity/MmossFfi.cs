@@ -44,6 +44,12 @@ public static class MmossFfi
     [UnmanagedFunctionPointer(CallingConvention.Cdecl)]
     public delegate void OnComponentAddedCallback(ulong entity, uint spawnId, uint componentType, uint id);
 
+    [UnmanagedFunctionPointer(CallingConvention.Cdecl)]
+    public delegate void OnParentChangedCallback(ulong entity, ulong parent, [MarshalAs(UnmanagedType.I1)] bool hasParent);
+
+    [UnmanagedFunctionPointer(CallingConvention.Cdecl)]
+    public delegate void OnDespawnCallback(ulong entity);
+
     [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
     public static extern void mmoss_init_log(byte level, LogCallback callback);
 
@@ -70,7 +76,9 @@ public static class MmossFfi
         WorldPtr world,
         OnSpawnCallback onSpawn,
         OnComponentUpdatedCallback onComponentUpdated,
-        OnComponentAddedCallback onComponentAdded);
+        OnComponentAddedCallback onComponentAdded,
+        OnParentChangedCallback onParentChanged,
+        OnDespawnCallback onDespawn);
 
     // FFI-compatible structs
     [StructLayout(LayoutKind.Sequential)]
@@ -90,6 +98,22 @@ public static class MmossFfi
         public float w;
     }
 
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    [return: MarshalAs(UnmanagedType.I1)]
+    public static extern bool mmoss_client_world_entity_by_spawn_id(WorldPtr world, uint spawnId, out ulong outEntity);
+
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    [return: MarshalAs(UnmanagedType.I1)]
+    public static extern bool mmoss_client_world_spawn_id_by_entity(WorldPtr world, ulong entity, out uint outSpawnId);
+
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    [return: MarshalAs(UnmanagedType.I1)]
+    public static extern bool mmoss_client_world_entity_by_replicated_id(WorldPtr world, uint id, out ulong outEntity);
+
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    [return: MarshalAs(UnmanagedType.I1)]
+    public static extern bool mmoss_client_world_component_by_replicated_id(WorldPtr world, uint id, out ulong outEntity, out uint outComponentType);
+
     [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
     public static extern void mmoss_dynamic_actor_proxy_get_tranform(
         WorldPtr world,
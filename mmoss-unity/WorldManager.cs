@@ -48,6 +48,35 @@ public class WorldManager : MonoBehaviour
         }
     }
 
+    void OnParentChangedCallback(ulong entity, ulong parent, bool hasParent)
+    {
+        GameObject child;
+        if (!spawnedEntities.TryGetValue(entity, out child))
+        {
+            return;
+        }
+
+        GameObject parentGo;
+        if (hasParent && spawnedEntities.TryGetValue(parent, out parentGo))
+        {
+            child.transform.SetParent(parentGo.transform, true);
+        }
+        else
+        {
+            child.transform.SetParent(null, true);
+        }
+    }
+
+    void OnDespawnCallback(ulong entity)
+    {
+        GameObject go;
+        if (spawnedEntities.TryGetValue(entity, out go))
+        {
+            spawnedEntities.Remove(entity);
+            Destroy(go);
+        }
+    }
+
     // Start is called once before the first execution of Update after the MonoBehaviour is created
     void Start()
     {
@@ -74,7 +103,9 @@ public class WorldManager : MonoBehaviour
             this.world,
             OnSpawnCallback,
             OnComponentUpdatedCallback,
-            OnComponentAddedCallback);
+            OnComponentAddedCallback,
+            OnParentChangedCallback,
+            OnDespawnCallback);
     }
 
     void OnDestroy()
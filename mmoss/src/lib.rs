@@ -1,4 +1,14 @@
+// Lets `#[derive(Replicated)]` refer to `::mmoss` from within this crate too
+extern crate self as mmoss;
+
 pub mod core;
 pub mod net;
 pub mod physics;
 pub mod replication;
+
+/// Dependencies of the code generated by `mmoss-proc-macros`
+#[doc(hidden)]
+pub mod __private {
+    pub use anyhow;
+    pub use bincode;
+}
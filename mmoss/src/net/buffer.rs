@@ -0,0 +1,110 @@
+//! Growable, pooled buffers for serializing messages of any size
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use bincode::error::EncodeError;
+
+/// Size hint used when a message or component doesn't provide one
+pub const DEFAULT_SIZE_HINT: usize = 512;
+
+/// Largest message a transport will send or accept
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Buffers kept around for reuse
+const MAX_POOLED: usize = 64;
+
+/// Buffers that grew larger than this are dropped instead of pooled
+const MAX_POOLED_CAPACITY: usize = 1024 * 1024;
+
+static POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// Error for serializers that ran out of space, [`serialize`] retries with a larger buffer
+#[derive(Debug)]
+pub struct BufferTooSmall;
+
+impl fmt::Display for BufferTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Buffer too small")
+    }
+}
+
+impl std::error::Error for BufferTooSmall {}
+
+fn is_too_small(error: &anyhow::Error) -> bool {
+    error.is::<BufferTooSmall>()
+        || matches!(
+            error.downcast_ref::<EncodeError>(),
+            Some(EncodeError::UnexpectedEnd)
+        )
+}
+
+/// A buffer taken from the pool, returned to it when dropped
+pub struct Buffer(Vec<u8>);
+
+impl Deref for Buffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if self.0.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+
+        let mut buffer = std::mem::take(&mut self.0);
+        buffer.clear();
+        if let Ok(mut pool) = POOL.lock()
+            && pool.len() < MAX_POOLED
+        {
+            pool.push(buffer);
+        }
+    }
+}
+
+/// Take an empty buffer from the pool
+pub fn get() -> Buffer {
+    Buffer(
+        POOL.lock()
+            .ok()
+            .and_then(|mut pool| pool.pop())
+            .unwrap_or_default(),
+    )
+}
+
+/// Run `serialize` on `buffer`, starting at `size_hint` bytes and doubling the size for as long
+/// as it runs out of space, up to [`MAX_MESSAGE_SIZE`]
+pub fn serialize(
+    buffer: &mut Vec<u8>,
+    size_hint: usize,
+    mut serialize: impl FnMut(&mut [u8]) -> Result<usize>,
+) -> Result<usize> {
+    let mut size = size_hint.clamp(1, MAX_MESSAGE_SIZE);
+    loop {
+        if buffer.len() < size {
+            buffer.resize(size, 0);
+        }
+
+        match serialize(buffer) {
+            Ok(len) => return Ok(len),
+            Err(e) if is_too_small(&e) && buffer.len() < MAX_MESSAGE_SIZE => {
+                size = (buffer.len() * 2).min(MAX_MESSAGE_SIZE);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,594 @@
+//! Logical channels multiplexed over a single transport
+//!
+//! A [`Multiplexer`] owns a transport carrying [`Frame`]s and hands out one [`Channel`] per
+//! [`ChannelId`], each with its own message type, factory and [`Guarantee`]. Reliable channels are
+//! acknowledged and resent by the multiplexer unless the underlying transport is already reliable.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::poll_fn,
+    marker::PhantomData,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bincode::{Decode, Encode};
+use log::warn;
+use tokio::sync::mpsc::{
+    Receiver, Sender, UnboundedReceiver, UnboundedSender, channel, error::TryRecvError,
+    unbounded_channel,
+};
+
+use crate::net::{
+    buffer,
+    transport::{Message, MessageFactory, ReceiveHalf, Reliable, SendHalf, Unreliable},
+};
+
+/// Number of sequences past the next expected one a reliable channel accepts, and the number of
+/// frames it leaves unacknowledged before holding back new messages
+pub const RECEIVE_WINDOW: u32 = 1024;
+
+/// Messages queued by each channel before sending on it waits for the multiplexer
+const OUTGOING_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Decode, Encode)]
+#[repr(transparent)]
+pub struct ChannelId(pub u8);
+
+/// Delivery guarantee of a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Guarantee {
+    /// Every message is delivered, in the order it was sent
+    ReliableOrdered,
+    /// Every message is delivered once, as soon as it arrives
+    ReliableUnordered,
+    /// Messages may be lost, those older than the newest one received are dropped
+    UnreliableSequenced,
+    /// Messages may be lost, duplicated or reordered
+    Unreliable,
+}
+
+impl Guarantee {
+    fn is_reliable(self) -> bool {
+        matches!(
+            self,
+            Guarantee::ReliableOrdered | Guarantee::ReliableUnordered
+        )
+    }
+}
+
+/// Unit carried by the multiplexed transport
+#[derive(Debug, Clone, Decode, Encode)]
+pub enum Frame {
+    Data {
+        channel: ChannelId,
+        sequence: u32,
+        payload: Vec<u8>,
+    },
+    Ack {
+        channel: ChannelId,
+        sequence: u32,
+    },
+}
+
+impl Message for Frame {
+    fn serialize(&self, data: &mut [u8]) -> Result<usize> {
+        Ok(bincode::encode_into_slice(
+            self,
+            data,
+            bincode::config::standard(),
+        )?)
+    }
+
+    fn size_hint(&self) -> usize {
+        match self {
+            Frame::Data { payload, .. } => payload.len() + 16,
+            Frame::Ack { .. } => 16,
+        }
+    }
+}
+
+pub struct FrameFactory;
+
+impl MessageFactory for FrameFactory {
+    type Message = Frame;
+
+    fn deserialize(&self, _context: &(), data: &[u8]) -> Result<(Self::Message, usize)> {
+        Ok(bincode::decode_from_slice(
+            data,
+            bincode::config::standard(),
+        )?)
+    }
+}
+
+/// Whether sequence `a` is newer than `b`, allowing for wrap around
+fn is_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Multiplexer side of a channel
+struct ChannelState {
+    guarantee: Guarantee,
+    incoming: UnboundedSender<Vec<u8>>,
+    /// Payloads written to the channel, its writers wait once this is full
+    outgoing: Receiver<Vec<u8>>,
+    next_sequence: u32,
+    /// Reliable payloads not acknowledged yet, with the time they were last sent
+    unacked: BTreeMap<u32, (Vec<u8>, Instant)>,
+    /// Every sequence below this has been delivered, on reliable channels
+    next_delivery: u32,
+    /// Frames received ahead of `next_delivery`, payloads are only kept on ordered channels
+    ahead: BTreeMap<u32, Option<Vec<u8>>>,
+    /// Newest sequence delivered on sequenced channels
+    newest: Option<u32>,
+}
+
+impl ChannelState {
+    fn new(
+        guarantee: Guarantee,
+        incoming: UnboundedSender<Vec<u8>>,
+        outgoing: Receiver<Vec<u8>>,
+    ) -> Self {
+        Self {
+            guarantee,
+            incoming,
+            outgoing,
+            next_sequence: 0,
+            unacked: BTreeMap::new(),
+            next_delivery: 0,
+            ahead: BTreeMap::new(),
+            newest: None,
+        }
+    }
+
+    /// Payloads that can be delivered after receiving `sequence`, `None` if the frame is beyond
+    /// the receive window and must not be acknowledged
+    fn receive(&mut self, sequence: u32, payload: Vec<u8>) -> Option<Vec<Vec<u8>>> {
+        match self.guarantee {
+            Guarantee::Unreliable => Some(vec![payload]),
+            Guarantee::UnreliableSequenced => {
+                if self
+                    .newest
+                    .is_some_and(|newest| !is_newer(sequence, newest))
+                {
+                    return Some(Vec::new());
+                }
+                self.newest = Some(sequence);
+                Some(vec![payload])
+            }
+            Guarantee::ReliableUnordered | Guarantee::ReliableOrdered => {
+                if is_newer(self.next_delivery, sequence) || self.ahead.contains_key(&sequence) {
+                    // Delivered already, acknowledged again in case the first ack was lost
+                    return Some(Vec::new());
+                }
+                if sequence.wrapping_sub(self.next_delivery) >= RECEIVE_WINDOW {
+                    return None;
+                }
+
+                let mut delivered = Vec::new();
+                if self.guarantee == Guarantee::ReliableOrdered {
+                    self.ahead.insert(sequence, Some(payload));
+                } else {
+                    self.ahead.insert(sequence, None);
+                    delivered.push(payload);
+                }
+                while let Some(payload) = self.ahead.remove(&self.next_delivery) {
+                    delivered.extend(payload);
+                    self.next_delivery = self.next_delivery.wrapping_add(1);
+                }
+                Some(delivered)
+            }
+        }
+    }
+
+    /// Whether no more reliable frames can be sent until some are acknowledged
+    fn is_congested(&self) -> bool {
+        self.unacked.len() >= RECEIVE_WINDOW as usize
+    }
+}
+
+/// Next payload written to a channel that may send, polling from `next_channel` on so busy
+/// channels can't starve others
+///
+/// Reliable channels waiting for acknowledgements are skipped, so only their own writers wait
+/// once their queue fills up while other channels keep sending.
+fn poll_outgoing(
+    channels: &mut HashMap<ChannelId, ChannelState>,
+    order: &[ChannelId],
+    next_channel: &mut usize,
+    cx: &mut Context<'_>,
+) -> Poll<(ChannelId, Vec<u8>)> {
+    for offset in 0..order.len() {
+        let index = (*next_channel + offset) % order.len();
+        let id = order[index];
+        let Some(state) = channels.get_mut(&id) else {
+            continue;
+        };
+        if state.is_congested() {
+            continue;
+        }
+        if let Poll::Ready(Some(payload)) = state.outgoing.poll_recv(cx) {
+            *next_channel = index + 1;
+            return Poll::Ready((id, payload));
+        }
+    }
+    Poll::Pending
+}
+
+/// Drives a transport carrying several [`Channel`]s
+pub struct Multiplexer {
+    transport: Box<dyn Unreliable<Frame>>,
+    /// The transport delivers every frame in order, so acknowledgements aren't needed
+    reliable_transport: bool,
+    resend_interval: Duration,
+    channels: HashMap<ChannelId, ChannelState>,
+    /// Channels in the order they are polled for outgoing payloads
+    order: Vec<ChannelId>,
+    /// Index into `order` of the channel polled first
+    next_channel: usize,
+}
+
+impl Multiplexer {
+    /// Multiplex over a transport that may lose frames
+    pub fn new(transport: Box<dyn Unreliable<Frame>>) -> Self {
+        Self {
+            transport,
+            reliable_transport: false,
+            resend_interval: Duration::from_millis(200),
+            channels: HashMap::new(),
+            order: Vec::new(),
+            next_channel: 0,
+        }
+    }
+
+    /// Multiplex over a reliable transport, such as TCP, skipping acknowledgements and resends
+    pub fn over_reliable(transport: Box<dyn Reliable<Frame>>) -> Self {
+        let mut multiplexer = Self::new(transport);
+        multiplexer.reliable_transport = true;
+        multiplexer
+    }
+
+    /// Time after which unacknowledged reliable frames are sent again
+    pub fn with_resend_interval(mut self, interval: Duration) -> Self {
+        self.resend_interval = interval;
+        self
+    }
+
+    /// Open a channel, must be called before [`Self::run`]
+    pub fn open<F: MessageFactory>(
+        &mut self,
+        id: ChannelId,
+        guarantee: Guarantee,
+        factory: F,
+    ) -> Result<Channel<F>> {
+        if self.channels.contains_key(&id) {
+            return Err(anyhow::anyhow!("Channel {:?} is already open", id));
+        }
+
+        let (incoming_sender, incoming) = unbounded_channel();
+        let (outgoing, outgoing_receiver) = channel(OUTGOING_QUEUE_SIZE);
+        self.channels.insert(
+            id,
+            ChannelState::new(guarantee, incoming_sender, outgoing_receiver),
+        );
+        self.order.push(id);
+
+        Ok(Channel {
+            writer: ChannelWriter {
+                outgoing,
+                _marker: PhantomData,
+            },
+            reader: ChannelReader { incoming, factory },
+        })
+    }
+
+    /// Open a reliable channel, usable wherever a [`Reliable`] transport is expected
+    pub fn open_reliable<F: MessageFactory>(
+        &mut self,
+        id: ChannelId,
+        ordered: bool,
+        factory: F,
+    ) -> Result<ReliableChannel<F>> {
+        let guarantee = if ordered {
+            Guarantee::ReliableOrdered
+        } else {
+            Guarantee::ReliableUnordered
+        };
+        Ok(ReliableChannel(self.open(id, guarantee, factory)?))
+    }
+
+    /// Send and receive frames until the transport fails
+    pub async fn run(mut self) -> Result<()> {
+        let (mut sender, mut receiver) = self.transport.split();
+        let mut resend = tokio::time::interval(self.resend_interval);
+
+        loop {
+            tokio::select! {
+                frame = receiver.receive() => {
+                    match frame? {
+                        Frame::Data { channel, sequence, payload } => {
+                            let Some(state) = self.channels.get_mut(&channel) else {
+                                warn!("Frame for unknown channel {:?}", channel);
+                                continue;
+                            };
+
+                            let Some(delivered) = state.receive(sequence, payload) else {
+                                continue;
+                            };
+                            if state.guarantee.is_reliable() && !self.reliable_transport {
+                                sender.send(&Frame::Ack { channel, sequence }).await?;
+                            }
+                            for payload in delivered {
+                                // The channel may have been dropped, its messages are discarded
+                                let _ = state.incoming.send(payload);
+                            }
+                        }
+                        Frame::Ack { channel, sequence } => {
+                            if let Some(state) = self.channels.get_mut(&channel) {
+                                state.unacked.remove(&sequence);
+                            }
+                        }
+                    }
+                }
+                (channel, payload) = poll_fn(|cx| {
+                    poll_outgoing(&mut self.channels, &self.order, &mut self.next_channel, cx)
+                }) => {
+                    let Some(state) = self.channels.get_mut(&channel) else {
+                        continue;
+                    };
+
+                    let sequence = state.next_sequence;
+                    state.next_sequence = state.next_sequence.wrapping_add(1);
+                    if state.guarantee.is_reliable() && !self.reliable_transport {
+                        state.unacked.insert(sequence, (payload.clone(), Instant::now()));
+                    }
+                    sender.send(&Frame::Data { channel, sequence, payload }).await?;
+                }
+                _ = resend.tick() => {
+                    let now = Instant::now();
+                    for (channel, state) in self.channels.iter_mut() {
+                        for (sequence, (payload, sent)) in state.unacked.iter_mut() {
+                            if now - *sent < self.resend_interval {
+                                continue;
+                            }
+                            *sent = now;
+                            sender
+                                .send(&Frame::Data {
+                                    channel: *channel,
+                                    sequence: *sequence,
+                                    payload: payload.clone(),
+                                })
+                                .await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sending half of a [`Channel`]
+pub struct ChannelWriter<M: Message> {
+    outgoing: Sender<Vec<u8>>,
+    _marker: PhantomData<fn(&M)>,
+}
+
+/// Receiving half of a [`Channel`]
+pub struct ChannelReader<F: MessageFactory> {
+    incoming: UnboundedReceiver<Vec<u8>>,
+    factory: F,
+}
+
+#[async_trait]
+impl<M: Message> SendHalf<M> for ChannelWriter<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        let mut buffer = buffer::get();
+        let len = buffer::serialize(&mut buffer, message.size_hint(), |data| {
+            message.serialize(data)
+        })?;
+        self.outgoing
+            .send(buffer[..len].to_vec())
+            .await
+            .map_err(|_| anyhow::anyhow!("Multiplexer stopped"))
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory> ReceiveHalf<F::Message> for ChannelReader<F> {
+    async fn receive(&mut self) -> Result<F::Message> {
+        let data = self
+            .incoming
+            .recv()
+            .await
+            .ok_or(anyhow::anyhow!("Multiplexer stopped"))?;
+        let (message, _) = self.factory.deserialize(&(), &data)?;
+        Ok(message)
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        match self.incoming.try_recv() {
+            Ok(data) => {
+                let (message, _) = self.factory.deserialize(&(), &data)?;
+                Ok(Some(message))
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("Multiplexer stopped")),
+        }
+    }
+}
+
+/// Logical channel of a [`Multiplexer`]
+pub struct Channel<F: MessageFactory> {
+    writer: ChannelWriter<F::Message>,
+    reader: ChannelReader<F>,
+}
+
+#[async_trait]
+impl<F: MessageFactory> Unreliable<F::Message> for Channel<F> {
+    async fn send(&mut self, message: &F::Message) -> Result<()> {
+        self.writer.send(message).await
+    }
+
+    async fn receive(&mut self) -> Result<F::Message> {
+        self.reader.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        self.reader.try_receive()
+    }
+
+    fn split<'a>(
+        self: Box<Self>,
+    ) -> (
+        Box<dyn SendHalf<F::Message> + 'a>,
+        Box<dyn ReceiveHalf<F::Message> + 'a>,
+    )
+    where
+        Self: 'a,
+    {
+        (Box::new(self.writer), Box::new(self.reader))
+    }
+}
+
+/// Channel opened with [`Multiplexer::open_reliable`]
+pub struct ReliableChannel<F: MessageFactory>(Channel<F>);
+
+#[async_trait]
+impl<F: MessageFactory> Unreliable<F::Message> for ReliableChannel<F> {
+    async fn send(&mut self, message: &F::Message) -> Result<()> {
+        self.0.send(message).await
+    }
+
+    async fn receive(&mut self) -> Result<F::Message> {
+        self.0.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        self.0.try_receive()
+    }
+
+    fn split<'a>(
+        self: Box<Self>,
+    ) -> (
+        Box<dyn SendHalf<F::Message> + 'a>,
+        Box<dyn ReceiveHalf<F::Message> + 'a>,
+    )
+    where
+        Self: 'a,
+    {
+        Box::new(self.0).split()
+    }
+}
+
+impl<F: MessageFactory> Reliable<F::Message> for ReliableChannel<F> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::{VecU8FactoryNew, memory};
+
+    fn state(guarantee: Guarantee, next_delivery: u32) -> ChannelState {
+        let (incoming, _) = unbounded_channel();
+        let (_, outgoing) = channel(1);
+        let mut state = ChannelState::new(guarantee, incoming, outgoing);
+        state.next_delivery = next_delivery;
+        state
+    }
+
+    #[test]
+    fn ordered_delivery_across_wrap_around() {
+        let mut state = state(Guarantee::ReliableOrdered, u32::MAX - 1);
+
+        assert_eq!(state.receive(0, vec![2]), Some(Vec::new()));
+        assert_eq!(state.receive(u32::MAX, vec![1]), Some(Vec::new()));
+        assert_eq!(
+            state.receive(u32::MAX - 1, vec![0]),
+            Some(vec![vec![0], vec![1], vec![2]])
+        );
+        assert_eq!(state.next_delivery, 1);
+        assert!(state.ahead.is_empty());
+
+        // Resent after wrapping around, acknowledged but not delivered again
+        assert_eq!(state.receive(u32::MAX, vec![1]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn unordered_delivery_across_wrap_around() {
+        let mut state = state(Guarantee::ReliableUnordered, u32::MAX);
+
+        assert_eq!(state.receive(0, vec![1]), Some(vec![vec![1]]));
+        assert_eq!(state.receive(0, vec![1]), Some(Vec::new()));
+        assert_eq!(state.receive(u32::MAX, vec![0]), Some(vec![vec![0]]));
+        assert_eq!(state.next_delivery, 1);
+        assert!(state.ahead.is_empty());
+    }
+
+    #[test]
+    fn frames_beyond_the_window_are_not_accepted() {
+        let mut state = state(Guarantee::ReliableOrdered, u32::MAX - 10);
+
+        let last = (u32::MAX - 10).wrapping_add(RECEIVE_WINDOW - 1);
+        assert_eq!(state.receive(last, vec![0]), Some(Vec::new()));
+        assert_eq!(state.receive(last.wrapping_add(1), vec![0]), None);
+        assert_eq!(state.ahead.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn holds_back_messages_until_acknowledged() -> Result<()> {
+        let (transport, mut remote) = memory::pair(FrameFactory, FrameFactory);
+        let mut multiplexer = Multiplexer::new(Box::new(transport));
+        let mut channel = multiplexer.open_reliable(ChannelId(0), true, VecU8FactoryNew)?;
+        tokio::spawn(multiplexer.run());
+
+        for i in 0..=RECEIVE_WINDOW {
+            channel.send(&i.to_le_bytes().to_vec()).await?;
+        }
+        for sequence in 0..RECEIVE_WINDOW {
+            let Frame::Data { sequence: sent, .. } = remote.receive().await? else {
+                panic!("Expected data");
+            };
+            assert_eq!(sent, sequence);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(remote.try_receive()?.is_none());
+
+        remote
+            .send(&Frame::Ack {
+                channel: ChannelId(0),
+                sequence: 0,
+            })
+            .await?;
+        let Frame::Data { sequence, .. } = remote.receive().await? else {
+            panic!("Expected data");
+        };
+        assert_eq!(sequence, RECEIVE_WINDOW);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn other_channels_keep_sending_while_one_is_congested() -> Result<()> {
+        let (transport, mut remote) = memory::pair(FrameFactory, FrameFactory);
+        let mut multiplexer = Multiplexer::new(Box::new(transport));
+        let mut reliable = multiplexer.open_reliable(ChannelId(0), true, VecU8FactoryNew)?;
+        let mut unreliable =
+            multiplexer.open(ChannelId(1), Guarantee::Unreliable, VecU8FactoryNew)?;
+        tokio::spawn(multiplexer.run());
+
+        for i in 0..=RECEIVE_WINDOW {
+            reliable.send(&i.to_le_bytes().to_vec()).await?;
+        }
+        for _ in 0..RECEIVE_WINDOW {
+            remote.receive().await?;
+        }
+
+        unreliable.send(&vec![1]).await?;
+        let frame = tokio::time::timeout(Duration::from_secs(1), remote.receive()).await??;
+        let Frame::Data { channel, .. } = frame else {
+            panic!("Expected data");
+        };
+        assert_eq!(channel, ChannelId(1));
+        Ok(())
+    }
+}
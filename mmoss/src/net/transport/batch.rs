@@ -0,0 +1,335 @@
+//! Coalescing of many messages into one packet
+//!
+//! [`Batched`] wraps a transport carrying [`Batch`]es, which the peer must wrap the same way.
+//! Sent messages are held until [`Unreliable::flush`] and then go out together, split into
+//! batches of at most [`Batched::with_max_size`] bytes, so a tick's worth of updates costs a few
+//! datagrams or writes instead of one per message.
+//!
+//! Batches are per connection, so a [`Udp`](udp::Udp) socket is batched per peer by wrapping each
+//! [`udp::Peer`] of its [`udp::Udp::demux`].
+//!
+//! Whoever sends through a batched transport must flush it, such as the server at the end of
+//! every tick. Wrappers sending on their own, like [`KeepAlive`](super::keepalive::KeepAlive),
+//! flush what they send.
+
+use std::{collections::VecDeque, marker::PhantomData, mem};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::net::{
+    buffer::{self, BufferTooSmall},
+    transport::{Message, MessageFactory, ReceiveHalf, Reliable, SendHalf, Unreliable, udp},
+};
+
+/// Size of the little endian length prefix in front of every message in a batch
+const LENGTH_SIZE: usize = size_of::<u32>();
+
+/// Length prefixed messages sent as one packet
+#[derive(Debug, Clone)]
+pub struct Batch(pub Vec<u8>);
+
+impl Message for Batch {
+    fn serialize(&self, data: &mut [u8]) -> Result<usize> {
+        if data.len() < self.0.len() {
+            return Err(BufferTooSmall.into());
+        }
+        data[..self.0.len()].copy_from_slice(&self.0);
+        Ok(self.0.len())
+    }
+
+    fn size_hint(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Factory for [`Batch`]es, a batch takes up the whole data
+pub struct BatchFactory;
+
+impl MessageFactory for BatchFactory {
+    type Message = Batch;
+
+    fn deserialize(&self, _context: &(), data: &[u8]) -> Result<(Self::Message, usize)> {
+        Ok((Batch(data.to_vec()), data.len()))
+    }
+}
+
+/// Sending half of a [`Batched`] transport
+pub struct BatchSender<M: Message> {
+    inner: Box<dyn SendHalf<Batch>>,
+    pending: Vec<u8>,
+    max_size: usize,
+    _marker: PhantomData<fn(&M)>,
+}
+
+/// Receiving half of a [`Batched`] transport
+pub struct BatchReceiver<F: MessageFactory> {
+    inner: Box<dyn ReceiveHalf<Batch>>,
+    factory: F,
+    incoming: VecDeque<F::Message>,
+}
+
+impl<M: Message> BatchSender<M> {
+    async fn send_pending(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let batch = Batch(mem::take(&mut self.pending));
+        self.inner.send(&batch).await
+    }
+}
+
+#[async_trait]
+impl<M: Message> SendHalf<M> for BatchSender<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        let mut buffer = buffer::get();
+        let len = buffer::serialize(&mut buffer, message.size_hint(), |data| {
+            message.serialize(data)
+        })?;
+
+        // Start a new batch rather than go over the limit, a message larger than the limit goes
+        // out on its own
+        if !self.pending.is_empty() && self.pending.len() + LENGTH_SIZE + len > self.max_size {
+            self.send_pending().await?;
+        }
+        self.pending.extend_from_slice(&(len as u32).to_le_bytes());
+        self.pending.extend_from_slice(&buffer[..len]);
+        if self.pending.len() >= self.max_size {
+            self.send_pending().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.send_pending().await?;
+        self.inner.flush().await
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+impl<F: MessageFactory> BatchReceiver<F> {
+    /// Deserialize every message in `batch`
+    fn unpack(&mut self, batch: &Batch) -> Result<()> {
+        let data = &batch.0;
+        let mut cursor = 0;
+        while cursor < data.len() {
+            if data.len() - cursor < LENGTH_SIZE {
+                return Err(anyhow::anyhow!("Truncated batch"));
+            }
+            let len = u32::from_le_bytes([
+                data[cursor],
+                data[cursor + 1],
+                data[cursor + 2],
+                data[cursor + 3],
+            ]) as usize;
+            let start = cursor + LENGTH_SIZE;
+            if data.len() - start < len {
+                return Err(anyhow::anyhow!("Truncated batch"));
+            }
+
+            let (message, _) = self.factory.deserialize(&(), &data[start..start + len])?;
+            self.incoming.push_back(message);
+            cursor = start + len;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory> ReceiveHalf<F::Message> for BatchReceiver<F> {
+    async fn receive(&mut self) -> Result<F::Message> {
+        loop {
+            if let Some(message) = self.incoming.pop_front() {
+                return Ok(message);
+            }
+
+            let batch = self.inner.receive().await?;
+            self.unpack(&batch)?;
+        }
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        loop {
+            if let Some(message) = self.incoming.pop_front() {
+                return Ok(Some(message));
+            }
+
+            match self.inner.try_receive()? {
+                Some(batch) => self.unpack(&batch)?,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.incoming.is_empty() && self.inner.is_closed()
+    }
+}
+
+/// Transport wrapper coalescing messages into [`Batch`]es over `T`
+///
+/// Reliable if `T` is. Nothing is sent until the batch is full or flushed.
+pub struct Batched<F: MessageFactory, T> {
+    sender: BatchSender<F::Message>,
+    receiver: BatchReceiver<F>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<F: MessageFactory, T: Unreliable<Batch> + 'static> Batched<F, T> {
+    /// Batches are limited to what fits in a single UDP datagram by default
+    pub fn new(transport: T, factory: F) -> Self {
+        let (sender, receiver) = Box::new(transport).split();
+        Self {
+            sender: BatchSender {
+                inner: sender,
+                pending: Vec::new(),
+                max_size: udp::MAX_PAYLOAD_SIZE,
+                _marker: PhantomData,
+            },
+            receiver: BatchReceiver {
+                inner: receiver,
+                factory,
+                incoming: VecDeque::new(),
+            },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Largest batch sent, stream transports can use much larger batches than datagrams
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.sender.max_size = max_size;
+        self
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory, T: Unreliable<Batch> + 'static> Unreliable<F::Message> for Batched<F, T> {
+    async fn send(&mut self, message: &F::Message) -> Result<()> {
+        self.sender.send(message).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.sender.flush().await
+    }
+
+    async fn receive(&mut self) -> Result<F::Message> {
+        self.receiver.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        self.receiver.try_receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed() || self.receiver.is_closed()
+    }
+
+    fn split<'a>(
+        self: Box<Self>,
+    ) -> (
+        Box<dyn SendHalf<F::Message> + 'a>,
+        Box<dyn ReceiveHalf<F::Message> + 'a>,
+    )
+    where
+        Self: 'a,
+    {
+        (Box::new(self.sender), Box::new(self.receiver))
+    }
+}
+
+impl<F: MessageFactory, T: Reliable<Batch> + 'static> Reliable<F::Message> for Batched<F, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::{StringFactoryNew, memory, udp::Udp};
+
+    fn messages() -> Vec<String> {
+        let mut messages = (0..20)
+            .map(|i| format!("message {}", i))
+            .collect::<Vec<_>>();
+        // Larger than a whole batch, goes out on its own
+        messages.insert(7, "x".repeat(500));
+        messages
+    }
+
+    #[tokio::test]
+    async fn packs_up_to_max_size() {
+        let (sender, mut receiver) = memory::pair(BatchFactory, BatchFactory);
+        let mut sender = Batched::new(sender, StringFactoryNew).with_max_size(64);
+        for message in messages() {
+            sender.send(&message).await.unwrap();
+        }
+        sender.flush().await.unwrap();
+
+        let mut batches = Vec::new();
+        while let Some(batch) = receiver.try_receive().unwrap() {
+            batches.push(batch.0);
+        }
+        assert!(batches.len() < messages().len());
+        let oversized = batches.iter().filter(|batch| batch.len() > 64).count();
+        assert_eq!(oversized, 1);
+    }
+
+    #[tokio::test]
+    async fn round_trips_in_order() {
+        let (a, b) = memory::pair(BatchFactory, BatchFactory);
+        let mut a = Batched::new(a, StringFactoryNew).with_max_size(64);
+        let mut b = Batched::new(b, StringFactoryNew);
+        for message in messages() {
+            a.send(&message).await.unwrap();
+        }
+        // Nothing goes out of a partial batch until flushed
+        a.send(&"held back".to_string()).await.unwrap();
+        let mut received = Vec::new();
+        while let Some(message) = b.try_receive().unwrap() {
+            received.push(message);
+        }
+        assert_eq!(received, messages()[..received.len()]);
+        assert!(!received.contains(&"held back".to_string()));
+
+        a.flush().await.unwrap();
+        while let Some(message) = b.try_receive().unwrap() {
+            received.push(message);
+        }
+        assert_eq!(
+            received,
+            [messages(), vec!["held back".to_string()]].concat()
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_batches() {
+        let (_, transport) = memory::pair(BatchFactory, BatchFactory);
+        let mut batched = Batched::new(transport, StringFactoryNew);
+        let mut batch = 9u32.to_le_bytes().to_vec();
+        batch.extend_from_slice(b"short");
+        assert!(batched.receiver.unpack(&Batch(batch)).is_err());
+        assert!(batched.receiver.unpack(&Batch(vec![1, 0])).is_err());
+    }
+
+    #[tokio::test]
+    async fn batches_per_udp_peer() {
+        let server = Udp::bind("127.0.0.1:0", BatchFactory).await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut server = server.demux();
+        let client = Udp::bind("127.0.0.1:0", BatchFactory)
+            .await
+            .unwrap()
+            .demux();
+
+        let mut client = Batched::new(client.connect(server_addr), StringFactoryNew);
+        for message in messages() {
+            client.send(&message).await.unwrap();
+        }
+        client.flush().await.unwrap();
+
+        let mut server = Batched::new(server.accept().await.unwrap(), StringFactoryNew);
+        for message in messages() {
+            assert_eq!(server.receive().await.unwrap(), message);
+        }
+    }
+}
@@ -0,0 +1,496 @@
+//! Per-message compression with codecs negotiated at connect
+//!
+//! [`Compressed`] wraps a transport carrying [`Packet`]s, which the peer must wrap the same way.
+//! Messages of at least [`Config::threshold`] bytes are compressed with zstd, using a shared
+//! [`Dictionary`] trained on typical payloads if both sides have the same one. Smaller messages,
+//! and those that don't get smaller, are sent as they are.
+
+use std::{marker::PhantomData, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use zstd::bulk::{Compressor, Decompressor};
+
+use crate::net::{
+    buffer::{self, BufferTooSmall, MAX_MESSAGE_SIZE},
+    transport::{Message, MessageFactory, ReceiveHalf, Reliable, SendHalf, Unreliable},
+};
+
+/// Time the peer has to send its offer when negotiating
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+const OFFER_VERSION: u8 = 1;
+
+/// Size of the uncompressed length in front of compressed data
+const LENGTH_SIZE: usize = size_of::<u32>();
+
+/// How a message is encoded, in order of preference
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Codec {
+    None = 0,
+    Zstd = 1,
+    ZstdDictionary = 2,
+}
+
+impl Codec {
+    fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::ZstdDictionary),
+            _ => Err(anyhow::anyhow!("Unknown codec {}", tag)),
+        }
+    }
+}
+
+/// zstd dictionary shared by both ends, identified by a hash of its contents
+#[derive(Clone)]
+pub struct Dictionary {
+    id: u32,
+    data: Arc<Vec<u8>>,
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>) -> Self {
+        let hash = blake3::hash(&data);
+        let id = u32::from_le_bytes(hash.as_bytes()[..4].try_into().unwrap());
+        Self {
+            id,
+            data: Arc::new(data),
+        }
+    }
+
+    /// Train a dictionary of at most `max_size` bytes on typical messages or component payloads
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Self> {
+        Ok(Self::new(zstd::dict::from_samples(samples, max_size)?))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Contents to store and load with [`Self::new`] on both ends
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    /// Whether to compress at all, a peer negotiating without compression gets none either way
+    pub enabled: bool,
+    /// zstd compression level
+    pub level: i32,
+    /// Messages smaller than this are never compressed
+    pub threshold: usize,
+    pub dictionary: Option<Dictionary>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            level: 3,
+            threshold: 128,
+            dictionary: None,
+        }
+    }
+}
+
+impl Config {
+    /// Send everything as it is, still accepting compressed messages
+    pub fn without_compression(mut self) -> Self {
+        self.enabled = false;
+        self
+    }
+
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn with_dictionary(mut self, dictionary: Dictionary) -> Self {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    /// Best codec this config supports
+    fn best_codec(&self) -> Codec {
+        match (self.enabled, &self.dictionary) {
+            (false, _) => Codec::None,
+            (true, None) => Codec::Zstd,
+            (true, Some(_)) => Codec::ZstdDictionary,
+        }
+    }
+
+    /// Codecs offered to the peer, as version, best codec and dictionary id
+    fn offer(&self) -> Vec<u8> {
+        let mut offer = vec![OFFER_VERSION, self.best_codec() as u8];
+        offer.extend_from_slice(&self.dictionary.as_ref().map_or(0, |d| d.id).to_le_bytes());
+        offer
+    }
+
+    /// Best codec both this config and the peer's `offer` support
+    fn choose(&self, offer: &[u8]) -> Result<Codec> {
+        if offer.len() < 2 + size_of::<u32>() || offer[0] != OFFER_VERSION {
+            return Err(anyhow::anyhow!("Malformed compression offer"));
+        }
+
+        let peer_codec = Codec::from_tag(offer[1])?;
+        let peer_dictionary = u32::from_le_bytes(offer[2..6].try_into().unwrap());
+        match self.best_codec().min(peer_codec) {
+            Codec::ZstdDictionary
+                if self.dictionary.as_ref().map(|d| d.id) != Some(peer_dictionary) =>
+            {
+                Ok(Codec::Zstd)
+            }
+            codec => Ok(codec),
+        }
+    }
+}
+
+/// Wire format of a [`Compressed`] transport, a codec tag followed by the encoded message
+#[derive(Debug, Clone)]
+pub struct Packet(pub Vec<u8>);
+
+impl Message for Packet {
+    fn serialize(&self, data: &mut [u8]) -> Result<usize> {
+        if data.len() < self.0.len() {
+            return Err(BufferTooSmall.into());
+        }
+        data[..self.0.len()].copy_from_slice(&self.0);
+        Ok(self.0.len())
+    }
+
+    fn size_hint(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Factory for [`Packet`]s, a packet takes up the whole data
+pub struct PacketFactory;
+
+impl MessageFactory for PacketFactory {
+    type Message = Packet;
+
+    fn deserialize(&self, _context: &(), data: &[u8]) -> Result<(Self::Message, usize)> {
+        Ok((Packet(data.to_vec()), data.len()))
+    }
+}
+
+/// Sending half of a [`Compressed`] transport
+pub struct CompressedSender<M: Message> {
+    inner: Box<dyn SendHalf<Packet>>,
+    codec: Codec,
+    threshold: usize,
+    compressor: Compressor<'static>,
+    _marker: PhantomData<fn(&M)>,
+}
+
+/// Receiving half of a [`Compressed`] transport
+pub struct CompressedReceiver<F: MessageFactory> {
+    inner: Box<dyn ReceiveHalf<Packet>>,
+    factory: F,
+    decompressor: Decompressor<'static>,
+    dictionary_decompressor: Option<Decompressor<'static>>,
+}
+
+#[async_trait]
+impl<M: Message> SendHalf<M> for CompressedSender<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        let mut buffer = buffer::get();
+        let len = buffer::serialize(&mut buffer, message.size_hint() + 1, |data| {
+            message.serialize(&mut data[1..])
+        })?;
+
+        if self.codec != Codec::None && len >= self.threshold {
+            let compressed = self.compressor.compress(&buffer[1..len + 1])?;
+            if compressed.len() + LENGTH_SIZE < len {
+                let mut packet = Vec::with_capacity(1 + LENGTH_SIZE + compressed.len());
+                packet.push(self.codec as u8);
+                packet.extend_from_slice(&(len as u32).to_le_bytes());
+                packet.extend_from_slice(&compressed);
+                return self.inner.send(&Packet(packet)).await;
+            }
+        }
+
+        buffer[0] = Codec::None as u8;
+        self.inner.send(&Packet(buffer[..len + 1].to_vec())).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+impl<F: MessageFactory> CompressedReceiver<F> {
+    fn decode(&mut self, packet: Packet) -> Result<F::Message> {
+        let Some((&tag, data)) = packet.0.split_first() else {
+            return Err(anyhow::anyhow!("Empty packet"));
+        };
+
+        let decompressor = match Codec::from_tag(tag)? {
+            Codec::None => return Ok(self.factory.deserialize(&(), data)?.0),
+            Codec::Zstd => &mut self.decompressor,
+            Codec::ZstdDictionary => {
+                self.dictionary_decompressor
+                    .as_mut()
+                    .ok_or(anyhow::anyhow!(
+                        "Received message compressed with a dictionary we lack"
+                    ))?
+            }
+        };
+
+        if data.len() < LENGTH_SIZE {
+            return Err(anyhow::anyhow!("Truncated compressed message"));
+        }
+        let len = u32::from_le_bytes(data[..LENGTH_SIZE].try_into().unwrap()) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(anyhow::anyhow!(
+                "Message of {} bytes exceeds the maximum of {}",
+                len,
+                MAX_MESSAGE_SIZE
+            ));
+        }
+
+        let decompressed = decompressor.decompress(&data[LENGTH_SIZE..], len)?;
+        Ok(self.factory.deserialize(&(), &decompressed)?.0)
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory> ReceiveHalf<F::Message> for CompressedReceiver<F> {
+    async fn receive(&mut self) -> Result<F::Message> {
+        let packet = self.inner.receive().await?;
+        self.decode(packet)
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        match self.inner.try_receive()? {
+            Some(packet) => self.decode(packet).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+/// Transport wrapper compressing messages over `T`
+///
+/// Reliable if `T` is. Messages compressed with any codec are accepted, the codec only decides
+/// how messages are sent.
+pub struct Compressed<F: MessageFactory, T> {
+    sender: CompressedSender<F::Message>,
+    receiver: CompressedReceiver<F>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<F: MessageFactory, T: Unreliable<Packet> + 'static> Compressed<F, T> {
+    /// Send with `codec`, agreed on out of band such as by negotiating on another connection
+    pub fn new(transport: T, factory: F, config: Config, codec: Codec) -> Result<Self> {
+        let dictionary = config.dictionary.as_ref();
+        if codec == Codec::ZstdDictionary && dictionary.is_none() {
+            return Err(anyhow::anyhow!("Dictionary codec requires a dictionary"));
+        }
+
+        let compressor = match (codec, dictionary) {
+            (Codec::ZstdDictionary, Some(dictionary)) => {
+                Compressor::with_dictionary(config.level, &dictionary.data)?
+            }
+            _ => Compressor::new(config.level)?,
+        };
+        let dictionary_decompressor = dictionary
+            .map(|dictionary| Decompressor::with_dictionary(&dictionary.data))
+            .transpose()?;
+
+        let (sender, receiver) = Box::new(transport).split();
+        Ok(Self {
+            sender: CompressedSender {
+                inner: sender,
+                codec,
+                threshold: config.threshold,
+                compressor,
+                _marker: PhantomData,
+            },
+            receiver: CompressedReceiver {
+                inner: receiver,
+                factory,
+                decompressor: Decompressor::new()?,
+                dictionary_decompressor,
+            },
+            _marker: PhantomData,
+        })
+    }
+
+    /// Codec messages are sent with
+    pub fn codec(&self) -> Codec {
+        self.sender.codec
+    }
+}
+
+impl<F: MessageFactory, T: Reliable<Packet> + 'static> Compressed<F, T> {
+    /// Exchange supported codecs with the peer, which must negotiate as well, and use the best
+    /// one both support
+    pub async fn negotiate(mut transport: T, factory: F, config: Config) -> Result<Self> {
+        transport.send(&Packet(config.offer())).await?;
+        transport.flush().await?;
+        let offer = tokio::time::timeout(NEGOTIATION_TIMEOUT, transport.receive())
+            .await
+            .map_err(|_| anyhow::anyhow!("Compression negotiation timed out"))??;
+
+        let codec = config.choose(&offer.0)?;
+        Self::new(transport, factory, config, codec)
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory, T: Unreliable<Packet> + 'static> Unreliable<F::Message>
+    for Compressed<F, T>
+{
+    async fn send(&mut self, message: &F::Message) -> Result<()> {
+        self.sender.send(message).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.sender.flush().await
+    }
+
+    async fn receive(&mut self) -> Result<F::Message> {
+        self.receiver.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        self.receiver.try_receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed() || self.receiver.is_closed()
+    }
+
+    fn split<'a>(
+        self: Box<Self>,
+    ) -> (
+        Box<dyn SendHalf<F::Message> + 'a>,
+        Box<dyn ReceiveHalf<F::Message> + 'a>,
+    )
+    where
+        Self: 'a,
+    {
+        (Box::new(self.sender), Box::new(self.receiver))
+    }
+}
+
+impl<F: MessageFactory, T: Reliable<Packet> + 'static> Reliable<F::Message> for Compressed<F, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::{StringFactoryNew, memory};
+
+    fn dictionary() -> Dictionary {
+        let samples = (0..1000)
+            .map(|i| format!("{{\"position\":[{},{}],\"health\":{}}}", i, i * 3, i % 100))
+            .collect::<Vec<_>>();
+        Dictionary::train(&samples, 2048).unwrap()
+    }
+
+    async fn negotiate(
+        config: Config,
+        other: Config,
+    ) -> (
+        Compressed<StringFactoryNew, memory::Memory<PacketFactory>>,
+        Compressed<StringFactoryNew, memory::Memory<PacketFactory>>,
+    ) {
+        let (a, b) = memory::pair(PacketFactory, PacketFactory);
+        let (a, b) = tokio::join!(
+            Compressed::negotiate(a, StringFactoryNew, config),
+            Compressed::negotiate(b, StringFactoryNew, other)
+        );
+        (a.unwrap(), b.unwrap())
+    }
+
+    #[tokio::test]
+    async fn negotiates_best_common_codec() {
+        let dictionary = dictionary();
+        let with_dictionary = Config::default().with_dictionary(dictionary.clone());
+        let other_dictionary = Config::default().with_dictionary(Dictionary::new(vec![7; 512]));
+
+        let cases = [
+            (
+                with_dictionary.clone(),
+                with_dictionary.clone(),
+                Codec::ZstdDictionary,
+            ),
+            (with_dictionary.clone(), other_dictionary, Codec::Zstd),
+            (with_dictionary.clone(), Config::default(), Codec::Zstd),
+            (
+                with_dictionary,
+                Config::default().without_compression(),
+                Codec::None,
+            ),
+        ];
+        for (config, other, expected) in cases {
+            let (mut a, mut b) = negotiate(config, other).await;
+            assert_eq!((a.codec(), b.codec()), (expected, expected));
+
+            let message = "{\"position\":[1,3],\"health\":1}".repeat(20);
+            a.send(&message).await.unwrap();
+            assert_eq!(b.receive().await.unwrap(), message);
+        }
+    }
+
+    /// Codec tag of the packet `message` is sent as
+    async fn sent_codec(message: &str) -> u8 {
+        let (transport, mut raw) = memory::pair(PacketFactory, PacketFactory);
+        let config = Config::default().with_threshold(64);
+        let mut compressed =
+            Compressed::new(transport, StringFactoryNew, config, Codec::Zstd).unwrap();
+        compressed.send(&message.to_string()).await.unwrap();
+        raw.receive().await.unwrap().0[0]
+    }
+
+    #[tokio::test]
+    async fn compresses_only_when_worth_it() {
+        assert_eq!(sent_codec("short").await, Codec::None as u8);
+        assert_eq!(sent_codec(&"a".repeat(1000)).await, Codec::Zstd as u8);
+
+        // Above the threshold but doesn't shrink
+        let mut state = 1u32;
+        let noise = (0..200)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                char::from(b'!' + (state >> 16) as u8 % 90)
+            })
+            .collect::<String>();
+        assert_eq!(sent_codec(&noise).await, Codec::None as u8);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_claims() {
+        let (transport, mut raw) = memory::pair(PacketFactory, PacketFactory);
+        let mut compressed =
+            Compressed::new(transport, StringFactoryNew, Config::default(), Codec::Zstd).unwrap();
+
+        let mut packet = vec![Codec::Zstd as u8];
+        packet.extend_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        packet.extend_from_slice(&zstd::bulk::compress(b"tiny", 3).unwrap());
+        raw.send(&Packet(packet)).await.unwrap();
+        assert!(compressed.receive().await.is_err());
+
+        raw.send(&Packet(vec![Codec::ZstdDictionary as u8, 0, 0, 0, 0]))
+            .await
+            .unwrap();
+        assert!(compressed.receive().await.is_err());
+    }
+}
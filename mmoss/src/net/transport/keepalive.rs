@@ -0,0 +1,470 @@
+//! Keepalive pings, idle timeouts and round trip time estimates for any transport
+//!
+//! [`KeepAlive`] wraps a transport carrying [`Packet`]s, which the peer must wrap the same way.
+//! A background task answers and sends pings, and closes the connection once nothing has been
+//! received for [`Config::timeout`]. The smoothed round trip time and jitter are exposed through
+//! [`Health`].
+
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use log::debug;
+use tokio::sync::{
+    Mutex as AsyncMutex,
+    mpsc::{UnboundedReceiver, UnboundedSender, error::TryRecvError, unbounded_channel},
+};
+
+use crate::net::{
+    buffer::{self, BufferTooSmall},
+    transport::{Message, MessageFactory, ReceiveHalf, Reliable, SendHalf, Unreliable},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub ping_interval: Duration,
+    /// Time without receiving anything after which the connection is closed
+    pub timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Config {
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Wire format of a [`KeepAlive`] transport
+#[derive(Debug, Clone)]
+pub enum Packet {
+    /// A serialized message of the wrapped message type
+    Message(Vec<u8>),
+    Ping(u32),
+    Pong(u32),
+}
+
+const MESSAGE: u8 = 0;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+
+impl Message for Packet {
+    fn serialize(&self, data: &mut [u8]) -> Result<usize> {
+        if data.len() < self.size_hint() {
+            return Err(BufferTooSmall.into());
+        }
+
+        match self {
+            Packet::Message(message) => {
+                data[0] = MESSAGE;
+                data[1..1 + message.len()].copy_from_slice(message);
+            }
+            Packet::Ping(id) | Packet::Pong(id) => {
+                data[0] = if matches!(self, Packet::Ping(_)) {
+                    PING
+                } else {
+                    PONG
+                };
+                data[1..5].copy_from_slice(&id.to_le_bytes());
+            }
+        }
+        Ok(self.size_hint())
+    }
+
+    fn size_hint(&self) -> usize {
+        match self {
+            Packet::Message(message) => 1 + message.len(),
+            Packet::Ping(_) | Packet::Pong(_) => 1 + size_of::<u32>(),
+        }
+    }
+}
+
+/// Factory for [`Packet`]s, a message packet takes up the rest of the data
+pub struct PacketFactory;
+
+impl MessageFactory for PacketFactory {
+    type Message = Packet;
+
+    fn deserialize(&self, _context: &(), data: &[u8]) -> Result<(Self::Message, usize)> {
+        match data.first() {
+            Some(&MESSAGE) => Ok((Packet::Message(data[1..].to_vec()), data.len())),
+            Some(&tag @ (PING | PONG)) if data.len() > size_of::<u32>() => {
+                let id = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+                let packet = if tag == PING {
+                    Packet::Ping(id)
+                } else {
+                    Packet::Pong(id)
+                };
+                Ok((packet, 1 + size_of::<u32>()))
+            }
+            _ => Err(anyhow::anyhow!("Malformed keepalive packet")),
+        }
+    }
+}
+
+struct HealthState {
+    /// Smoothed round trip time, `None` until the first pong arrives
+    rtt: Option<Duration>,
+    jitter: Duration,
+    last_received: Instant,
+    closed: bool,
+}
+
+/// Liveness and latency of a [`KeepAlive`] connection, cheap to clone and share
+#[derive(Clone)]
+pub struct Health(Arc<Mutex<HealthState>>);
+
+impl Health {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(HealthState {
+            rtt: None,
+            jitter: Duration::ZERO,
+            last_received: Instant::now(),
+            closed: false,
+        })))
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut HealthState) -> T) -> T {
+        f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Smoothed round trip time, `None` until the first ping has been answered
+    pub fn rtt(&self) -> Option<Duration> {
+        self.with_state(|state| state.rtt)
+    }
+
+    /// Smoothed variation of the round trip time
+    pub fn jitter(&self) -> Duration {
+        self.with_state(|state| state.jitter)
+    }
+
+    /// Time since anything was last received
+    pub fn idle(&self) -> Duration {
+        self.with_state(|state| state.last_received.elapsed())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.with_state(|state| state.closed)
+    }
+
+    /// Delay for interpolating between states sent every `update_interval`, long enough for the
+    /// next state to usually have arrived before it is needed
+    pub fn interpolation_delay(&self, update_interval: Duration) -> Duration {
+        update_interval * 2 + self.jitter() * 2
+    }
+
+    fn received(&self) {
+        self.with_state(|state| state.last_received = Instant::now());
+    }
+
+    /// Fold a round trip sample into the estimates, as TCP does for its retransmission timer
+    fn sample(&self, rtt: Duration) {
+        self.with_state(|state| match state.rtt {
+            None => {
+                state.rtt = Some(rtt);
+                state.jitter = rtt / 2;
+            }
+            Some(smoothed) => {
+                state.jitter = (state.jitter * 3 + smoothed.abs_diff(rtt)) / 4;
+                state.rtt = Some((smoothed * 7 + rtt) / 8);
+            }
+        });
+    }
+
+    fn close(&self) {
+        self.with_state(|state| state.closed = true);
+    }
+}
+
+/// Pings awaiting their pong, older ones are forgotten
+const MAX_OUTSTANDING_PINGS: usize = 8;
+
+type SharedSender = Arc<AsyncMutex<Box<dyn SendHalf<Packet>>>>;
+
+/// Send a ping or pong right away, even if the wrapped transport holds messages back
+async fn send_now(sender: &SharedSender, packet: &Packet) -> Result<()> {
+    let mut sender = sender.lock().await;
+    sender.send(packet).await?;
+    sender.flush().await
+}
+
+/// Answer and send pings and forward messages until the connection fails, times out or is
+/// dropped
+async fn drive(
+    mut receiver: Box<dyn ReceiveHalf<Packet>>,
+    sender: SharedSender,
+    incoming: UnboundedSender<Vec<u8>>,
+    health: Health,
+    config: Config,
+) {
+    let mut ping = tokio::time::interval(config.ping_interval);
+    let mut next_ping_id = 0u32;
+    let mut outstanding = VecDeque::new();
+
+    loop {
+        tokio::select! {
+            packet = receiver.receive() => {
+                let packet = match packet {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        debug!("Keepalive connection failed: {}", e);
+                        break;
+                    }
+                };
+                health.received();
+
+                match packet {
+                    Packet::Message(data) => {
+                        // The receiving half may have been dropped, its messages are discarded
+                        let _ = incoming.send(data);
+                    }
+                    Packet::Ping(id) => {
+                        if let Err(e) = send_now(&sender, &Packet::Pong(id)).await {
+                            debug!("Failed to answer ping: {}", e);
+                            break;
+                        }
+                    }
+                    Packet::Pong(id) => {
+                        if let Some(index) = outstanding.iter().position(|(ping, _)| *ping == id) {
+                            let (_, sent): (u32, Instant) = outstanding[index];
+                            outstanding.drain(..=index);
+                            health.sample(sent.elapsed());
+                        }
+                    }
+                }
+            }
+            _ = ping.tick() => {
+                // Both halves were dropped, close the connection
+                if incoming.is_closed() && Arc::strong_count(&sender) == 1 {
+                    break;
+                }
+                if health.idle() > config.timeout {
+                    debug!("Keepalive connection timed out");
+                    break;
+                }
+
+                let id = next_ping_id;
+                next_ping_id = next_ping_id.wrapping_add(1);
+                if outstanding.len() == MAX_OUTSTANDING_PINGS {
+                    outstanding.pop_front();
+                }
+                outstanding.push_back((id, Instant::now()));
+                if let Err(e) = send_now(&sender, &Packet::Ping(id)).await {
+                    debug!("Failed to send ping: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    health.close();
+}
+
+/// Sending half of a [`KeepAlive`] transport
+pub struct KeepAliveSender<M: Message> {
+    sender: SharedSender,
+    health: Health,
+    _marker: PhantomData<fn(&M)>,
+}
+
+/// Receiving half of a [`KeepAlive`] transport
+pub struct KeepAliveReceiver<F: MessageFactory> {
+    incoming: UnboundedReceiver<Vec<u8>>,
+    factory: F,
+    health: Health,
+}
+
+#[async_trait]
+impl<M: Message> SendHalf<M> for KeepAliveSender<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        if self.health.is_closed() {
+            return Err(anyhow::anyhow!("Connection closed"));
+        }
+
+        let mut buffer = buffer::get();
+        let len = buffer::serialize(&mut buffer, message.size_hint(), |data| {
+            message.serialize(data)
+        })?;
+        let packet = Packet::Message(buffer[..len].to_vec());
+        self.sender.lock().await.send(&packet).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.sender.lock().await.flush().await
+    }
+
+    fn is_closed(&self) -> bool {
+        self.health.is_closed()
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory> ReceiveHalf<F::Message> for KeepAliveReceiver<F> {
+    async fn receive(&mut self) -> Result<F::Message> {
+        let data = self
+            .incoming
+            .recv()
+            .await
+            .ok_or(anyhow::anyhow!("Connection closed"))?;
+        let (message, _) = self.factory.deserialize(&(), &data)?;
+        Ok(message)
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        match self.incoming.try_recv() {
+            Ok(data) => {
+                let (message, _) = self.factory.deserialize(&(), &data)?;
+                Ok(Some(message))
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("Connection closed")),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.health.is_closed()
+    }
+}
+
+/// Transport wrapper sending keepalive pings over `T`
+///
+/// Reliable if `T` is. Must be created within a tokio runtime, which runs the background task.
+pub struct KeepAlive<F: MessageFactory, T> {
+    sender: KeepAliveSender<F::Message>,
+    receiver: KeepAliveReceiver<F>,
+    health: Health,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<F: MessageFactory, T: Unreliable<Packet> + 'static> KeepAlive<F, T> {
+    pub fn new(transport: T, factory: F, config: Config) -> Self {
+        let health = Health::new();
+        let (sender, receiver) = Box::new(transport).split();
+        let sender = Arc::new(AsyncMutex::new(sender));
+        let (incoming_sender, incoming) = unbounded_channel();
+
+        tokio::spawn(drive(
+            receiver,
+            sender.clone(),
+            incoming_sender,
+            health.clone(),
+            config,
+        ));
+
+        Self {
+            sender: KeepAliveSender {
+                sender,
+                health: health.clone(),
+                _marker: PhantomData,
+            },
+            receiver: KeepAliveReceiver {
+                incoming,
+                factory,
+                health: health.clone(),
+            },
+            health,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        self.health.clone()
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory, T: Unreliable<Packet> + 'static> Unreliable<F::Message>
+    for KeepAlive<F, T>
+{
+    async fn send(&mut self, message: &F::Message) -> Result<()> {
+        self.sender.send(message).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.sender.flush().await
+    }
+
+    async fn receive(&mut self) -> Result<F::Message> {
+        self.receiver.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        self.receiver.try_receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.health.is_closed()
+    }
+
+    fn split<'a>(
+        self: Box<Self>,
+    ) -> (
+        Box<dyn SendHalf<F::Message> + 'a>,
+        Box<dyn ReceiveHalf<F::Message> + 'a>,
+    )
+    where
+        Self: 'a,
+    {
+        (Box::new(self.sender), Box::new(self.receiver))
+    }
+}
+
+impl<F: MessageFactory, T: Reliable<Packet> + 'static> Reliable<F::Message> for KeepAlive<F, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::{StringFactoryNew, udp::Udp};
+
+    #[tokio::test]
+    async fn closes_when_udp_peer_goes_silent() {
+        let config = Config::default()
+            .with_ping_interval(Duration::from_millis(20))
+            .with_timeout(Duration::from_millis(200));
+        let server = Udp::bind("127.0.0.1:0", PacketFactory).await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut server = server.demux();
+        let client = Udp::bind("127.0.0.1:0", PacketFactory)
+            .await
+            .unwrap()
+            .demux();
+
+        let mut client = KeepAlive::new(client.connect(server_addr), StringFactoryNew, config);
+        client.send(&"hello".to_string()).await.unwrap();
+        let peer = server.accept().await.unwrap();
+        let mut server = KeepAlive::new(peer, StringFactoryNew, config);
+        assert_eq!(server.receive().await.unwrap(), "hello");
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server.health().rtt().is_some());
+        assert!(!server.is_closed());
+
+        // Nothing tells the server the client is gone, only the silence
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !server.is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
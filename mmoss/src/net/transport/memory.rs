@@ -0,0 +1,257 @@
+//! In-process transports connecting two endpoints through channels
+//!
+//! [`pair`] passes messages through their real encoding, so tests exercise the same code as a
+//! network connection. [`direct_pair`] hands over clones of the messages, for a listen server
+//! hosted in the same process as its client.
+
+use std::marker::PhantomData;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::mpsc::{
+    UnboundedReceiver, UnboundedSender, error::TryRecvError, unbounded_channel,
+};
+
+use crate::net::{
+    buffer,
+    transport::{Message, MessageFactory, ReceiveHalf, Reliable, SendHalf, Unreliable},
+};
+
+/// Connected endpoints serializing every message and deserializing it with the other end's
+/// factory
+pub fn pair<F, G>(factory: F, other_factory: G) -> (Memory<F>, Memory<G>)
+where
+    F: MessageFactory,
+    G: MessageFactory<Message = F::Message>,
+{
+    let (sender, other_receiver) = unbounded_channel();
+    let (other_sender, receiver) = unbounded_channel();
+    (
+        Memory {
+            writer: WriteHalf {
+                sender,
+                _marker: PhantomData,
+            },
+            reader: ReadHalf { receiver, factory },
+        },
+        Memory {
+            writer: WriteHalf {
+                sender: other_sender,
+                _marker: PhantomData,
+            },
+            reader: ReadHalf {
+                receiver: other_receiver,
+                factory: other_factory,
+            },
+        },
+    )
+}
+
+/// Connected endpoints passing clones of the messages without encoding them
+pub fn direct_pair<M: Message + Clone>() -> (Direct<M>, Direct<M>) {
+    let (sender, other_receiver) = unbounded_channel();
+    let (other_sender, receiver) = unbounded_channel();
+    (
+        Direct {
+            writer: DirectWriteHalf { sender },
+            reader: DirectReadHalf { receiver },
+        },
+        Direct {
+            writer: DirectWriteHalf {
+                sender: other_sender,
+            },
+            reader: DirectReadHalf {
+                receiver: other_receiver,
+            },
+        },
+    )
+}
+
+/// Endpoint created by [`pair`]
+pub struct Memory<F: MessageFactory> {
+    writer: WriteHalf<F::Message>,
+    reader: ReadHalf<F>,
+}
+
+impl<F: MessageFactory> Memory<F> {
+    /// Split into halves that can be used from different tasks
+    pub fn into_split(self) -> (WriteHalf<F::Message>, ReadHalf<F>) {
+        (self.writer, self.reader)
+    }
+}
+
+/// Sending half of a [`Memory`] endpoint
+pub struct WriteHalf<M: Message> {
+    sender: UnboundedSender<Vec<u8>>,
+    _marker: PhantomData<fn(&M)>,
+}
+
+/// Receiving half of a [`Memory`] endpoint
+pub struct ReadHalf<F: MessageFactory> {
+    receiver: UnboundedReceiver<Vec<u8>>,
+    factory: F,
+}
+
+#[async_trait]
+impl<M: Message> SendHalf<M> for WriteHalf<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        let mut buffer = buffer::get();
+        let len = buffer::serialize(&mut buffer, message.size_hint(), |data| {
+            message.serialize(data)
+        })?;
+        self.sender
+            .send(buffer[..len].to_vec())
+            .map_err(|_| anyhow::anyhow!("Connection closed"))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory> ReceiveHalf<F::Message> for ReadHalf<F> {
+    async fn receive(&mut self) -> Result<F::Message> {
+        let data = self
+            .receiver
+            .recv()
+            .await
+            .ok_or(anyhow::anyhow!("Connection closed"))?;
+        let (message, _) = self.factory.deserialize(&(), &data)?;
+        Ok(message)
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        match self.receiver.try_recv() {
+            Ok(data) => {
+                let (message, _) = self.factory.deserialize(&(), &data)?;
+                Ok(Some(message))
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("Connection closed")),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.receiver.is_closed() && self.receiver.is_empty()
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory> Unreliable<F::Message> for Memory<F> {
+    async fn send(&mut self, message: &F::Message) -> Result<()> {
+        self.writer.send(message).await
+    }
+
+    async fn receive(&mut self) -> Result<F::Message> {
+        self.reader.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        self.reader.try_receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.writer.is_closed() || self.reader.is_closed()
+    }
+
+    fn split<'a>(
+        self: Box<Self>,
+    ) -> (
+        Box<dyn SendHalf<F::Message> + 'a>,
+        Box<dyn ReceiveHalf<F::Message> + 'a>,
+    )
+    where
+        Self: 'a,
+    {
+        (Box::new(self.writer), Box::new(self.reader))
+    }
+}
+
+impl<F: MessageFactory> Reliable<F::Message> for Memory<F> {}
+
+/// Endpoint created by [`direct_pair`]
+pub struct Direct<M: Message + Clone> {
+    writer: DirectWriteHalf<M>,
+    reader: DirectReadHalf<M>,
+}
+
+impl<M: Message + Clone> Direct<M> {
+    /// Split into halves that can be used from different tasks
+    pub fn into_split(self) -> (DirectWriteHalf<M>, DirectReadHalf<M>) {
+        (self.writer, self.reader)
+    }
+}
+
+/// Sending half of a [`Direct`] endpoint
+pub struct DirectWriteHalf<M: Message + Clone> {
+    sender: UnboundedSender<M>,
+}
+
+/// Receiving half of a [`Direct`] endpoint
+pub struct DirectReadHalf<M: Message + Clone> {
+    receiver: UnboundedReceiver<M>,
+}
+
+#[async_trait]
+impl<M: Message + Clone> SendHalf<M> for DirectWriteHalf<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        self.sender
+            .send(message.clone())
+            .map_err(|_| anyhow::anyhow!("Connection closed"))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+#[async_trait]
+impl<M: Message + Clone> ReceiveHalf<M> for DirectReadHalf<M> {
+    async fn receive(&mut self) -> Result<M> {
+        self.receiver
+            .recv()
+            .await
+            .ok_or(anyhow::anyhow!("Connection closed"))
+    }
+
+    fn try_receive(&mut self) -> Result<Option<M>> {
+        match self.receiver.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("Connection closed")),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.receiver.is_closed() && self.receiver.is_empty()
+    }
+}
+
+#[async_trait]
+impl<M: Message + Clone> Unreliable<M> for Direct<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        self.writer.send(message).await
+    }
+
+    async fn receive(&mut self) -> Result<M> {
+        self.reader.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<M>> {
+        self.reader.try_receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.writer.is_closed() || self.reader.is_closed()
+    }
+
+    fn split<'a>(self: Box<Self>) -> (Box<dyn SendHalf<M> + 'a>, Box<dyn ReceiveHalf<M> + 'a>)
+    where
+        Self: 'a,
+    {
+        (Box::new(self.writer), Box::new(self.reader))
+    }
+}
+
+impl<M: Message + Clone> Reliable<M> for Direct<M> {}
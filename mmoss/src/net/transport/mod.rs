@@ -2,12 +2,25 @@ use anyhow::Result;
 use async_trait::async_trait;
 use std::marker::PhantomData;
 
+use crate::net::buffer;
+
+pub mod batch;
+pub mod compress;
+pub mod keepalive;
+pub mod memory;
+pub mod secure;
+pub mod simulator;
 pub mod tcp;
 pub mod udp;
 
 /// Message trait for serialization
 pub trait Message: Send + Sync {
     fn serialize(&self, data: &mut [u8]) -> Result<usize>;
+
+    /// Expected serialized size, buffers grow if the message turns out larger
+    fn size_hint(&self) -> usize {
+        buffer::DEFAULT_SIZE_HINT
+    }
 }
 
 /// Message factory trait for deserialization
@@ -21,8 +34,53 @@ pub trait MessageFactory<Context = ()>: Send {
 #[async_trait]
 pub trait Unreliable<M: Message>: Send {
     async fn send(&mut self, message: &M) -> Result<()>;
+
+    /// Send anything held back by [`Self::send`], a no-op for transports sending immediately
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     async fn receive(&mut self) -> Result<M>;
     fn try_receive(&mut self) -> Result<Option<M>>;
+
+    /// Whether the connection is known to be closed, `false` if the transport can't tell
+    fn is_closed(&self) -> bool {
+        false
+    }
+
+    /// Split into independent halves that can be used from different tasks
+    ///
+    /// There is no default, transports implemented before this existed need to add it. Sharing
+    /// the whole transport between both halves would make a pending receive block sends, so
+    /// each half has to own its side, wrappers split the transport they wrap.
+    fn split<'a>(self: Box<Self>) -> (Box<dyn SendHalf<M> + 'a>, Box<dyn ReceiveHalf<M> + 'a>)
+    where
+        Self: 'a;
+}
+
+/// Sending half of a split transport
+#[async_trait]
+pub trait SendHalf<M: Message>: Send {
+    async fn send(&mut self, message: &M) -> Result<()>;
+
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+/// Receiving half of a split transport
+#[async_trait]
+pub trait ReceiveHalf<M: Message>: Send {
+    async fn receive(&mut self) -> Result<M>;
+    fn try_receive(&mut self) -> Result<Option<M>>;
+
+    fn is_closed(&self) -> bool {
+        false
+    }
 }
 
 /// Reliable transport for sending and receiving messages of type `M`
@@ -43,6 +101,10 @@ impl<A: Send + Sync, M: Message> Message for Addressed<A, M> {
     fn serialize(&self, data: &mut [u8]) -> Result<usize> {
         self.message.serialize(data)
     }
+
+    fn size_hint(&self) -> usize {
+        self.message.size_hint()
+    }
 }
 
 pub struct AddressedFactory<A, F: MessageFactory> {
@@ -82,6 +144,10 @@ impl Message for String {
             bincode::config::standard(),
         )?)
     }
+
+    fn size_hint(&self) -> usize {
+        self.len() + size_of::<u64>()
+    }
 }
 
 /// String factory that creates a new string every time
@@ -105,6 +171,10 @@ impl Message for Vec<u8> {
             bincode::config::standard(),
         )?)
     }
+
+    fn size_hint(&self) -> usize {
+        self.len() + size_of::<u64>()
+    }
 }
 
 pub struct VecU8FactoryNew;
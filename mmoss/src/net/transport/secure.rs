@@ -0,0 +1,570 @@
+//! Encryption and authentication of connections
+//!
+//! A TCP connection starts with a handshake shaped after Noise NK: the client knows the server's
+//! static public key and both sides contribute an ephemeral key, so only the real server can
+//! complete it and past traffic stays secret if the static key leaks later. Frames are then
+//! sealed with ChaCha20-Poly1305 using counter nonces.
+//!
+//! The handshake also yields [`SessionKeys`] for datagrams, which are registered with a UDP
+//! socket through [`PeerKeys`]. Sealed datagrams carry their counter, and datagrams that fail
+//! authentication or were already received are dropped.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use x25519_dalek::StaticSecret;
+
+pub use x25519_dalek::PublicKey;
+
+/// Size of the authentication tag appended to every sealed message
+pub const TAG_SIZE: usize = 16;
+
+/// Size of the counter in front of every sealed datagram
+const COUNTER_SIZE: usize = size_of::<u64>();
+
+/// Bytes sealing adds to a datagram
+pub(crate) const DATAGRAM_OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+
+const KEY_SIZE: usize = 32;
+
+/// Datagrams this far behind the newest one are dropped as replays
+const REPLAY_WINDOW: u64 = 64;
+
+/// Static key pair identifying a server
+#[derive(Clone)]
+pub struct Keypair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Keypair {
+    pub fn generate() -> Self {
+        Self::from_secret(rand::random())
+    }
+
+    pub fn from_secret(secret: [u8; KEY_SIZE]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// Secret to store for recreating the key pair, keep it private
+    pub fn secret(&self) -> [u8; KEY_SIZE] {
+        self.secret.to_bytes()
+    }
+
+    /// Public key clients connect with
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+}
+
+/// Keys for one direction each, derived from a handshake
+#[derive(Clone)]
+pub struct SessionKeys {
+    send: [u8; KEY_SIZE],
+    receive: [u8; KEY_SIZE],
+}
+
+/// Result of a completed handshake
+pub(crate) struct Handshake {
+    pub stream: SessionKeys,
+    pub datagram: SessionKeys,
+}
+
+fn derive(label: &str, material: &[u8]) -> [u8; KEY_SIZE] {
+    blake3::derive_key(&format!("mmoss handshake v1 {}", label), material)
+}
+
+fn cipher(key: &[u8; KEY_SIZE]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Key material from both Diffie-Hellman results and the public keys exchanged
+fn key_material(
+    ephemeral_static: &[u8; KEY_SIZE],
+    ephemeral_ephemeral: &[u8; KEY_SIZE],
+    transcript: &[u8],
+) -> Vec<u8> {
+    let mut material = Vec::with_capacity(2 * KEY_SIZE + transcript.len());
+    material.extend_from_slice(ephemeral_static);
+    material.extend_from_slice(ephemeral_ephemeral);
+    material.extend_from_slice(transcript);
+    material
+}
+
+fn shared(secret: &StaticSecret, public: &PublicKey) -> Result<[u8; KEY_SIZE]> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(anyhow::anyhow!("Handshake used a low order key"));
+    }
+    Ok(*shared.as_bytes())
+}
+
+async fn read_key(stream: &mut TcpStream) -> Result<PublicKey> {
+    let mut key = [0u8; KEY_SIZE];
+    stream.read_exact(&mut key).await?;
+    Ok(PublicKey::from(key))
+}
+
+/// Run the client side of the handshake with a server known by `server`
+pub(crate) async fn client_handshake(
+    stream: &mut TcpStream,
+    server: &PublicKey,
+) -> Result<Handshake> {
+    let ephemeral = StaticSecret::from(rand::random::<[u8; KEY_SIZE]>());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    stream.write_all(ephemeral_public.as_bytes()).await?;
+
+    let server_ephemeral = read_key(stream).await?;
+    let mut confirmation = [0u8; TAG_SIZE];
+    stream.read_exact(&mut confirmation).await?;
+
+    let transcript = [
+        ephemeral_public.as_bytes().as_slice(),
+        server.as_bytes(),
+        server_ephemeral.as_bytes(),
+    ]
+    .concat();
+    let material = key_material(
+        &shared(&ephemeral, server)?,
+        &shared(&ephemeral, &server_ephemeral)?,
+        &transcript,
+    );
+
+    // Only the holder of the server's secret can produce the confirmation
+    cipher(&derive("confirm", &material))
+        .decrypt(
+            Nonce::from_slice(&nonce(0)),
+            Payload {
+                msg: &confirmation,
+                aad: &transcript,
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Server failed to authenticate"))?;
+
+    Ok(Handshake {
+        stream: SessionKeys {
+            send: derive("client stream", &material),
+            receive: derive("server stream", &material),
+        },
+        datagram: SessionKeys {
+            send: derive("client datagram", &material),
+            receive: derive("server datagram", &material),
+        },
+    })
+}
+
+/// Run the server side of the handshake with `keypair`
+pub(crate) async fn server_handshake(
+    stream: &mut TcpStream,
+    keypair: &Keypair,
+) -> Result<Handshake> {
+    let client_ephemeral = read_key(stream).await?;
+
+    let ephemeral = StaticSecret::from(rand::random::<[u8; KEY_SIZE]>());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+
+    let transcript = [
+        client_ephemeral.as_bytes().as_slice(),
+        keypair.public.as_bytes(),
+        ephemeral_public.as_bytes(),
+    ]
+    .concat();
+    let material = key_material(
+        &shared(&keypair.secret, &client_ephemeral)?,
+        &shared(&ephemeral, &client_ephemeral)?,
+        &transcript,
+    );
+
+    let confirmation = cipher(&derive("confirm", &material))
+        .encrypt(
+            Nonce::from_slice(&nonce(0)),
+            Payload {
+                msg: &[],
+                aad: &transcript,
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to confirm handshake"))?;
+    stream.write_all(ephemeral_public.as_bytes()).await?;
+    stream.write_all(&confirmation).await?;
+
+    Ok(Handshake {
+        stream: SessionKeys {
+            send: derive("server stream", &material),
+            receive: derive("client stream", &material),
+        },
+        datagram: SessionKeys {
+            send: derive("server datagram", &material),
+            receive: derive("client datagram", &material),
+        },
+    })
+}
+
+/// Seals or opens the frames of one direction of a stream, in order
+pub(crate) struct StreamCipher {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl StreamCipher {
+    pub fn sending(keys: &SessionKeys) -> Self {
+        Self {
+            cipher: cipher(&keys.send),
+            counter: 0,
+        }
+    }
+
+    pub fn receiving(keys: &SessionKeys) -> Self {
+        Self {
+            cipher: cipher(&keys.receive),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<[u8; 12]> {
+        let counter = self.counter;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(anyhow::anyhow!("Stream key exhausted"))?;
+        Ok(nonce(counter))
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| anyhow::anyhow!("Failed to seal frame"))
+    }
+
+    pub fn open(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| anyhow::anyhow!("Frame failed authentication"))
+    }
+}
+
+/// Datagram ciphers of one peer
+struct Peer {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    next_counter: u64,
+    /// Highest counter received
+    newest: Option<u64>,
+    /// Bit `n` is set if counter `newest - n` was received
+    received: u64,
+}
+
+impl Peer {
+    /// Record `counter` as received, `false` if it was already received or is too old
+    fn accept(&mut self, counter: u64) -> bool {
+        let Some(newest) = self.newest else {
+            self.newest = Some(counter);
+            self.received = 1;
+            return true;
+        };
+
+        if counter > newest {
+            let shift = counter - newest;
+            self.received = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.received << shift
+            };
+            self.received |= 1;
+            self.newest = Some(counter);
+            return true;
+        }
+
+        let age = newest - counter;
+        if age >= REPLAY_WINDOW || self.received & (1 << age) != 0 {
+            return false;
+        }
+        self.received |= 1 << age;
+        true
+    }
+}
+
+/// Datagram keys of the peers of a UDP socket, shared between its halves
+///
+/// Once a socket uses these, datagrams are only exchanged with peers that have keys.
+#[derive(Clone, Default)]
+pub struct PeerKeys(Arc<Mutex<HashMap<SocketAddr, Peer>>>);
+
+impl PeerKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, addr: SocketAddr, keys: &SessionKeys) {
+        self.lock().insert(
+            addr,
+            Peer {
+                send: cipher(&keys.send),
+                receive: cipher(&keys.receive),
+                next_counter: 0,
+                newest: None,
+                received: 0,
+            },
+        );
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) {
+        self.lock().remove(addr);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<SocketAddr, Peer>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Seal a datagram for `addr`, prefixed by its counter
+    pub(crate) fn seal(&self, addr: SocketAddr, data: &[u8]) -> Result<Vec<u8>> {
+        let mut peers = self.lock();
+        let peer = peers
+            .get_mut(&addr)
+            .ok_or(anyhow::anyhow!("No keys for {}", addr))?;
+
+        let counter = peer.next_counter;
+        peer.next_counter = peer
+            .next_counter
+            .checked_add(1)
+            .ok_or(anyhow::anyhow!("Datagram key for {} exhausted", addr))?;
+
+        let sealed = peer
+            .send
+            .encrypt(Nonce::from_slice(&nonce(counter)), data)
+            .map_err(|_| anyhow::anyhow!("Failed to seal datagram"))?;
+        Ok([counter.to_le_bytes().as_slice(), &sealed].concat())
+    }
+
+    /// Authenticate and decrypt a datagram from `addr`, `None` if it should be dropped
+    pub(crate) fn open(&self, addr: SocketAddr, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < COUNTER_SIZE + TAG_SIZE {
+            return None;
+        }
+
+        let mut peers = self.lock();
+        let peer = peers.get_mut(&addr)?;
+        let counter = u64::from_le_bytes(data[..COUNTER_SIZE].try_into().ok()?);
+        let opened = peer
+            .receive
+            .decrypt(Nonce::from_slice(&nonce(counter)), &data[COUNTER_SIZE..])
+            .ok()?;
+        // Only authentic datagrams move the replay window
+        peer.accept(counter).then_some(opened)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::{
+        Addressed, StringFactoryNew, Unreliable,
+        tcp::{Connection, Listener},
+        udp::Udp,
+    };
+    use tokio::net::TcpListener;
+
+    /// Run both sides of the handshake, the client expecting `server`
+    async fn handshake(
+        keypair: &Keypair,
+        server: &PublicKey,
+    ) -> (Result<Handshake>, Result<Handshake>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            client_handshake(&mut stream, server).await
+        };
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            server_handshake(&mut stream, keypair).await
+        };
+        tokio::join!(client, server)
+    }
+
+    #[tokio::test]
+    async fn handshake_agrees_on_keys() {
+        let keypair = Keypair::generate();
+        let (client, server) = handshake(&keypair, &keypair.public()).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+
+        assert_eq!(client.stream.send, server.stream.receive);
+        assert_eq!(client.stream.receive, server.stream.send);
+        assert_eq!(client.datagram.send, server.datagram.receive);
+        assert_ne!(client.stream.send, client.stream.receive);
+        assert_ne!(client.stream.send, client.datagram.send);
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_wrong_server_key() {
+        let keypair = Keypair::generate();
+        let (client, _) = handshake(&keypair, &Keypair::generate().public()).await;
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn secure_connection_round_trips() {
+        let keypair = Keypair::generate();
+        let listener = Listener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_keypair(keypair.clone());
+        let addr = listener.local_addr().unwrap();
+        let public = keypair.public();
+
+        let (client, server) = tokio::join!(
+            Connection::connect_secure(addr, StringFactoryNew, &public),
+            async {
+                let (accepted, _) = listener.accept().await.unwrap();
+                accepted.establish(StringFactoryNew).await
+            }
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        client.send(&"ping".to_string()).await.unwrap();
+        assert_eq!(server.receive().await.unwrap(), "ping");
+        server.send(&"pong".to_string()).await.unwrap();
+        assert_eq!(client.receive().await.unwrap(), "pong");
+        assert!(client.datagram_keys().is_some());
+    }
+
+    #[tokio::test]
+    async fn tampered_frame_fails() {
+        let keypair = Keypair::generate();
+        let (client, server) = handshake(&keypair, &keypair.public()).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+        let mut sender = StreamCipher::sending(&client.stream);
+        let first = sender.seal(b"move north").unwrap();
+        let second = sender.seal(b"move south").unwrap();
+
+        let mut tampered = first.clone();
+        tampered[3] ^= 1;
+        assert!(
+            StreamCipher::receiving(&server.stream)
+                .open(&tampered)
+                .is_err()
+        );
+
+        // Frames can't be dropped or reordered either
+        assert!(
+            StreamCipher::receiving(&server.stream)
+                .open(&second)
+                .is_err()
+        );
+
+        let mut receiver = StreamCipher::receiving(&server.stream);
+        assert_eq!(receiver.open(&first).unwrap(), b"move north");
+        assert_eq!(receiver.open(&second).unwrap(), b"move south");
+    }
+
+    fn peer_keys() -> (PeerKeys, PeerKeys, SocketAddr, SocketAddr) {
+        let (a, b) = (PeerKeys::new(), PeerKeys::new());
+        let a_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let b_addr = SocketAddr::from(([127, 0, 0, 1], 2));
+        let keys = SessionKeys {
+            send: [1; KEY_SIZE],
+            receive: [2; KEY_SIZE],
+        };
+        a.insert(b_addr, &keys);
+        b.insert(
+            a_addr,
+            &SessionKeys {
+                send: keys.receive,
+                receive: keys.send,
+            },
+        );
+        (a, b, a_addr, b_addr)
+    }
+
+    #[test]
+    fn replayed_datagrams_are_dropped() {
+        let (a, b, a_addr, b_addr) = peer_keys();
+        let first = a.seal(b_addr, b"first").unwrap();
+        let second = a.seal(b_addr, b"second").unwrap();
+
+        // Out of order is fine, a second copy isn't
+        assert_eq!(b.open(a_addr, &second).unwrap(), b"second");
+        assert_eq!(b.open(a_addr, &first).unwrap(), b"first");
+        assert!(b.open(a_addr, &first).is_none());
+        assert!(b.open(a_addr, &second).is_none());
+    }
+
+    #[test]
+    fn datagrams_older_than_the_window_are_dropped() {
+        let (a, b, a_addr, b_addr) = peer_keys();
+        let old = a.seal(b_addr, b"old").unwrap();
+        let recent = a.seal(b_addr, b"recent").unwrap();
+        for _ in 0..REPLAY_WINDOW - 2 {
+            a.seal(b_addr, b"skipped").unwrap();
+        }
+        let newest = a.seal(b_addr, b"newest").unwrap();
+
+        assert!(b.open(a_addr, &newest).is_some());
+        assert!(b.open(a_addr, &old).is_none());
+        assert!(b.open(a_addr, &recent).is_some());
+    }
+
+    #[test]
+    fn datagrams_from_others_fail() {
+        let (a, b, a_addr, b_addr) = peer_keys();
+        let mut datagram = a.seal(b_addr, b"hello").unwrap();
+        // Only the peer the keys belong to is accepted
+        assert!(b.open(b_addr, &datagram).is_none());
+
+        let last = datagram.len() - 1;
+        datagram[last] ^= 1;
+        assert!(b.open(a_addr, &datagram).is_none());
+    }
+
+    #[tokio::test]
+    async fn secured_udp_round_trips_fragmented_messages() {
+        let (a_keys, b_keys, _, _) = peer_keys();
+        let a = Udp::bind("127.0.0.1:0", StringFactoryNew).await.unwrap();
+        let b = Udp::bind("127.0.0.1:0", StringFactoryNew).await.unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        // Rekey for the real addresses
+        a_keys.insert(
+            b_addr,
+            &SessionKeys {
+                send: [1; KEY_SIZE],
+                receive: [2; KEY_SIZE],
+            },
+        );
+        b_keys.insert(
+            a_addr,
+            &SessionKeys {
+                send: [2; KEY_SIZE],
+                receive: [1; KEY_SIZE],
+            },
+        );
+        let mut a = a.with_peer_keys(a_keys);
+        let mut b = b.with_peer_keys(b_keys);
+
+        for message in ["short".to_string(), "y".repeat(5000)] {
+            a.send(&Addressed::new(b_addr, message.clone()))
+                .await
+                .unwrap();
+            assert_eq!(b.receive().await.unwrap().message, message);
+        }
+    }
+}
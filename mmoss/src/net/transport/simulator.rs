@@ -0,0 +1,504 @@
+//! Simulated network conditions for any transport
+//!
+//! [`Simulator`] wraps a transport and applies latency, jitter, loss, duplication, reordering
+//! and a bandwidth cap to what it receives, and duplication to what it sends. Wrapping both ends
+//! of a connection simulates both directions. All randomness comes from [`Config::seed`], so a
+//! run can be reproduced. [`ReliableSimulator`] does the same for reliable transports without
+//! breaking their guarantees.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::net::{
+    buffer,
+    transport::{Message, ReceiveHalf, Reliable, SendHalf, Unreliable},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// One way delay added to every message
+    pub latency: Duration,
+    /// Largest random delay added on top of the latency
+    pub jitter: Duration,
+    /// Probability of a message being lost, or resent after a round trip on reliable transports
+    pub loss: f64,
+    /// Probability of a message being sent twice, ignored on reliable transports
+    pub duplication: f64,
+    /// Probability of a message being held back by up to another latency, overtaken by later
+    /// ones, ignored on reliable transports
+    pub reorder: f64,
+    /// Bytes per second received, unlimited if `None`
+    pub bandwidth: Option<u64>,
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            duplication: 0.0,
+            reorder: 0.0,
+            bandwidth: None,
+            seed: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Typical conditions of a mobile network
+    pub fn mobile() -> Self {
+        Self::default()
+            .with_latency(Duration::from_millis(60))
+            .with_jitter(Duration::from_millis(30))
+            .with_loss(0.02)
+            .with_duplication(0.005)
+            .with_reorder(0.01)
+            .with_bandwidth(256 * 1024)
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    pub fn with_duplication(mut self, duplication: f64) -> Self {
+        self.duplication = duplication;
+        self
+    }
+
+    pub fn with_reorder(mut self, reorder: f64) -> Self {
+        self.reorder = reorder;
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bytes_per_second: u64) -> Self {
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+fn chance(rng: &mut StdRng, probability: f64) -> bool {
+    probability > 0.0 && rng.random::<f64>() < probability
+}
+
+/// Uniform in `[0, max)`
+fn random_duration(rng: &mut StdRng, max: Duration) -> Duration {
+    max.mul_f64(rng.random())
+}
+
+/// A received message waiting to be delivered
+struct Delayed<M> {
+    deliver_at: Instant,
+    /// Arrival order, breaks ties between messages due at the same time
+    sequence: u64,
+    message: M,
+}
+
+impl<M> PartialEq for Delayed<M> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deliver_at, self.sequence) == (other.deliver_at, other.sequence)
+    }
+}
+
+impl<M> Eq for Delayed<M> {}
+
+impl<M> PartialOrd for Delayed<M> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<M> Ord for Delayed<M> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.deliver_at, self.sequence).cmp(&(other.deliver_at, other.sequence))
+    }
+}
+
+/// Sending half of a [`Simulator`]
+pub struct SimulatorSender<M: Message> {
+    inner: Box<dyn SendHalf<M>>,
+    config: Config,
+    reliable: bool,
+    rng: StdRng,
+}
+
+/// Receiving half of a [`Simulator`]
+pub struct SimulatorReceiver<M: Message> {
+    inner: Box<dyn ReceiveHalf<M>>,
+    config: Config,
+    reliable: bool,
+    rng: StdRng,
+    queue: BinaryHeap<Reverse<Delayed<M>>>,
+    next_sequence: u64,
+    /// When the simulated link finishes receiving everything queued so far
+    link_free_at: Instant,
+    /// Delivery time of the last message, reliable transports never deliver before it
+    last_deliver_at: Instant,
+    /// Error from the wrapped transport, returned once everything queued was delivered
+    error: Option<anyhow::Error>,
+}
+
+#[async_trait]
+impl<M: Message> SendHalf<M> for SimulatorSender<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        self.inner.send(message).await?;
+        if !self.reliable && chance(&mut self.rng, self.config.duplication) {
+            self.inner.send(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+impl<M: Message> SimulatorReceiver<M> {
+    /// Decide the fate of a message that just arrived from the wrapped transport
+    fn arrive(&mut self, message: M) {
+        let now = Instant::now();
+        let lost = chance(&mut self.rng, self.config.loss);
+        if lost && !self.reliable {
+            return;
+        }
+
+        let mut deliver_at = match self.config.bandwidth {
+            Some(bandwidth) => {
+                // Messages that fail to encode still take their expected size on the link
+                let mut buffer = buffer::get();
+                let size = buffer::serialize(&mut buffer, message.size_hint(), |data| {
+                    message.serialize(data)
+                })
+                .unwrap_or_else(|_| message.size_hint());
+                let transmit = Duration::from_secs_f64(size as f64 / bandwidth as f64);
+                self.link_free_at = self.link_free_at.max(now) + transmit;
+                self.link_free_at
+            }
+            None => now,
+        };
+        deliver_at += self.config.latency + random_duration(&mut self.rng, self.config.jitter);
+
+        if self.reliable {
+            if lost {
+                // Resent once the sender notices, after a round trip
+                deliver_at += self.config.latency * 2 + self.config.jitter;
+            }
+            deliver_at = deliver_at.max(self.last_deliver_at);
+        } else if chance(&mut self.rng, self.config.reorder) {
+            deliver_at +=
+                random_duration(&mut self.rng, self.config.latency.max(self.config.jitter));
+        }
+        self.last_deliver_at = deliver_at;
+
+        self.queue.push(Reverse(Delayed {
+            deliver_at,
+            sequence: self.next_sequence,
+            message,
+        }));
+        self.next_sequence += 1;
+    }
+
+    /// Pop the next message that is due
+    fn pop_due(&mut self) -> Option<M> {
+        let Reverse(next) = self.queue.peek()?;
+        if next.deliver_at > Instant::now() {
+            return None;
+        }
+        self.queue.pop().map(|Reverse(delayed)| delayed.message)
+    }
+}
+
+#[async_trait]
+impl<M: Message> ReceiveHalf<M> for SimulatorReceiver<M> {
+    async fn receive(&mut self) -> Result<M> {
+        loop {
+            if let Some(message) = self.pop_due() {
+                return Ok(message);
+            }
+
+            let next = self.queue.peek().map(|Reverse(delayed)| delayed.deliver_at);
+            if let Some(e) = self.error.take() {
+                match next {
+                    Some(deliver_at) => {
+                        self.error = Some(e);
+                        tokio::time::sleep_until(deliver_at.into()).await;
+                        continue;
+                    }
+                    None => return Err(e),
+                }
+            }
+
+            match next {
+                Some(deliver_at) => {
+                    tokio::select! {
+                        message = self.inner.receive() => match message {
+                            Ok(message) => self.arrive(message),
+                            Err(e) => self.error = Some(e),
+                        },
+                        _ = tokio::time::sleep_until(deliver_at.into()) => {}
+                    }
+                }
+                None => match self.inner.receive().await {
+                    Ok(message) => self.arrive(message),
+                    Err(e) => self.error = Some(e),
+                },
+            }
+        }
+    }
+
+    fn try_receive(&mut self) -> Result<Option<M>> {
+        while self.error.is_none() {
+            match self.inner.try_receive() {
+                Ok(Some(message)) => self.arrive(message),
+                Ok(None) => break,
+                Err(e) => self.error = Some(e),
+            }
+        }
+
+        match self.pop_due() {
+            Some(message) => Ok(Some(message)),
+            None if self.queue.is_empty() => match self.error.take() {
+                Some(e) => Err(e),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.queue.is_empty() && (self.error.is_some() || self.inner.is_closed())
+    }
+}
+
+/// Transport wrapper simulating the network conditions in a [`Config`]
+///
+/// Never reliable, as messages may be lost, duplicated or reordered. Use [`ReliableSimulator`]
+/// for reliable transports. The wrapped transport's receive must be cancel safe.
+pub struct Simulator<M: Message, T> {
+    sender: SimulatorSender<M>,
+    receiver: SimulatorReceiver<M>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<M: Message + 'static, T: Unreliable<M> + 'static> Simulator<M, T> {
+    pub fn new(transport: T, config: Config) -> Self {
+        Self::with_reliable(transport, config, false)
+    }
+
+    /// Reliable ones keep messages in order and turn losses into resend delays
+    fn with_reliable(transport: T, config: Config, reliable: bool) -> Self {
+        let (sender, receiver) = Box::new(transport).split();
+        let now = Instant::now();
+        Self {
+            sender: SimulatorSender {
+                inner: sender,
+                config,
+                reliable,
+                rng: StdRng::seed_from_u64(config.seed),
+            },
+            receiver: SimulatorReceiver {
+                inner: receiver,
+                config,
+                reliable,
+                rng: StdRng::seed_from_u64(config.seed ^ 0x5eed_5eed_5eed_5eed),
+                queue: BinaryHeap::new(),
+                next_sequence: 0,
+                link_free_at: now,
+                last_deliver_at: now,
+                error: None,
+            },
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<M: Message + 'static, T: Unreliable<M> + 'static> Unreliable<M> for Simulator<M, T> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        self.sender.send(message).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.sender.flush().await
+    }
+
+    async fn receive(&mut self) -> Result<M> {
+        self.receiver.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<M>> {
+        self.receiver.try_receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed() || self.receiver.is_closed()
+    }
+
+    fn split<'a>(self: Box<Self>) -> (Box<dyn SendHalf<M> + 'a>, Box<dyn ReceiveHalf<M> + 'a>)
+    where
+        Self: 'a,
+    {
+        (Box::new(self.sender), Box::new(self.receiver))
+    }
+}
+
+/// Transport wrapper simulating the network conditions in a [`Config`] over a reliable transport
+///
+/// Messages stay in order and losses become resend delays of a round trip, duplication and
+/// reordering are ignored. The wrapped transport's receive must be cancel safe.
+pub struct ReliableSimulator<M: Message, T>(Simulator<M, T>);
+
+impl<M: Message + 'static, T: Reliable<M> + 'static> ReliableSimulator<M, T> {
+    pub fn new(transport: T, config: Config) -> Self {
+        Self(Simulator::with_reliable(transport, config, true))
+    }
+}
+
+#[async_trait]
+impl<M: Message + 'static, T: Reliable<M> + 'static> Unreliable<M> for ReliableSimulator<M, T> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        self.0.send(message).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.0.flush().await
+    }
+
+    async fn receive(&mut self) -> Result<M> {
+        self.0.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<M>> {
+        self.0.try_receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+
+    fn split<'a>(self: Box<Self>) -> (Box<dyn SendHalf<M> + 'a>, Box<dyn ReceiveHalf<M> + 'a>)
+    where
+        Self: 'a,
+    {
+        Box::new(self.0).split()
+    }
+}
+
+impl<M: Message + 'static, T: Reliable<M> + 'static> Reliable<M> for ReliableSimulator<M, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::memory;
+
+    /// Messages delivered out of 200 sent through a simulator with `config`
+    async fn delivered(config: Config) -> Vec<String> {
+        let (sender, receiver) = memory::direct_pair::<String>();
+        let mut sender = Simulator::new(sender, config);
+        let mut receiver = Simulator::new(receiver, config);
+        for i in 0..200 {
+            sender.send(&i.to_string()).await.unwrap();
+        }
+
+        let mut delivered = Vec::new();
+        while let Some(message) = receiver.try_receive().unwrap() {
+            delivered.push(message);
+        }
+        delivered
+    }
+
+    #[tokio::test]
+    async fn same_seed_same_fates() {
+        let config = Config::default()
+            .with_loss(0.2)
+            .with_duplication(0.2)
+            .with_seed(7);
+        let first = delivered(config).await;
+        assert_eq!(first, delivered(config).await);
+        assert_ne!(first, delivered(config.with_seed(8)).await);
+
+        // Some were lost and some duplicated
+        let unique = first.iter().collect::<std::collections::HashSet<_>>();
+        assert!(unique.len() < 200);
+        assert!(first.len() > unique.len());
+    }
+
+    #[tokio::test]
+    async fn reliable_keeps_order_and_delivers_everything() {
+        let config = Config::default()
+            .with_latency(Duration::from_millis(1))
+            .with_loss(0.5)
+            .with_duplication(0.5)
+            .with_reorder(0.5);
+        let (sender, receiver) = memory::direct_pair::<String>();
+        let mut sender = ReliableSimulator::new(sender, config);
+        let mut receiver = ReliableSimulator::new(receiver, config);
+        for i in 0..50 {
+            sender.send(&i.to_string()).await.unwrap();
+        }
+        for i in 0..50 {
+            assert_eq!(receiver.receive().await.unwrap(), i.to_string());
+        }
+        assert!(receiver.try_receive().unwrap().is_none());
+    }
+
+    /// Encodes to far more bytes than its size hint suggests
+    #[derive(Debug, Clone, PartialEq)]
+    struct Padded;
+
+    impl Message for Padded {
+        fn serialize(&self, data: &mut [u8]) -> Result<usize> {
+            let data = data.get_mut(..200).ok_or(buffer::BufferTooSmall)?;
+            data.fill(0);
+            Ok(data.len())
+        }
+
+        fn size_hint(&self) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn bandwidth_counts_serialized_bytes() {
+        let config = Config::default().with_bandwidth(1000);
+        let (mut sender, receiver) = memory::direct_pair::<Padded>();
+        let mut receiver = Simulator::new(receiver, config);
+        let start = Instant::now();
+        sender.send(&Padded).await.unwrap();
+
+        // 200 bytes take 200ms at 1000 bytes per second
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(receiver.try_receive().unwrap().is_none());
+        assert_eq!(receiver.receive().await.unwrap(), Padded);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
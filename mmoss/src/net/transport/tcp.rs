@@ -1,16 +1,31 @@
-use std::{collections::VecDeque, marker::PhantomData, mem::size_of, net::SocketAddr};
+use std::{
+    collections::VecDeque, marker::PhantomData, mem::size_of, net::SocketAddr, time::Duration,
+};
 
 use anyhow::Result;
 use async_trait::async_trait;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    net::{
+        TcpListener, TcpStream, ToSocketAddrs,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+};
+
+use crate::net::{
+    buffer::{self, MAX_MESSAGE_SIZE},
+    transport::{
+        Message, MessageFactory, ReceiveHalf, Reliable, SendHalf, Unreliable,
+        secure::{self, Handshake, Keypair, PublicKey, SessionKeys, StreamCipher},
+    },
 };
 
-use crate::net::transport::{Message, MessageFactory, Reliable, Unreliable};
+/// Clients that don't complete the handshake within this time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct Listener<M: Message> {
     listener: TcpListener,
+    keypair: Option<Keypair>,
     _marker: PhantomData<M>,
 }
 
@@ -18,113 +33,506 @@ impl<M: Message> Listener<M> {
     pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
         Ok(Self {
             listener: TcpListener::bind(addr).await?,
+            keypair: None,
             _marker: PhantomData,
         })
     }
-    pub async fn accept<F: MessageFactory<Message = M>>(
-        &self,
-        factory: F,
-    ) -> Result<(Connection<F>, SocketAddr)> {
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Encrypt accepted connections, clients connect with [`Connection::connect_secure`] and the
+    /// public key of `keypair`
+    pub fn with_keypair(mut self, keypair: Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    /// Wait for a client, whose connection is ready once [`Accepted::establish`] completes
+    ///
+    /// Establishing involves the handshake on encrypted listeners, run it on another task to keep
+    /// accepting other clients meanwhile.
+    pub async fn accept(&self) -> Result<(Accepted<M>, SocketAddr)> {
         let (stream, addr) = self.listener.accept().await?;
         Ok((
-            Connection {
+            Accepted {
                 stream,
-                factory,
-                incoming: VecDeque::new(),
-                receive_buffer: Vec::with_capacity(1024),
+                addr,
+                keypair: self.keypair.clone(),
+                _marker: PhantomData,
             },
             addr,
         ))
     }
 }
 
-pub struct Connection<F: MessageFactory> {
+/// Client accepted by a [`Listener`], before the handshake
+pub struct Accepted<M: Message> {
     stream: TcpStream,
+    addr: SocketAddr,
+    keypair: Option<Keypair>,
+    _marker: PhantomData<M>,
+}
+
+impl<M: Message> Accepted<M> {
+    /// Complete the handshake if the listener is encrypted, failing if the client doesn't within
+    /// a few seconds
+    pub async fn establish<F: MessageFactory<Message = M>>(
+        mut self,
+        factory: F,
+    ) -> Result<Connection<F>> {
+        let Some(keypair) = self.keypair.as_ref() else {
+            return Ok(Connection::new(self.stream, factory));
+        };
+
+        let handshake = tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            secure::server_handshake(&mut self.stream, keypair),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Handshake with {} timed out", self.addr))??;
+        Ok(Connection::new(self.stream, factory).secured(handshake))
+    }
+}
+
+pub struct Connection<F: MessageFactory> {
+    writer: WriteHalf<F::Message>,
+    reader: ReadHalf<F>,
+    datagram_keys: Option<SessionKeys>,
+}
+
+impl<F: MessageFactory> Connection<F> {
+    fn new(stream: TcpStream, factory: F) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self {
+            writer: WriteHalf {
+                stream: writer,
+                cipher: None,
+                closed: false,
+                _marker: PhantomData,
+            },
+            reader: ReadHalf {
+                stream: reader,
+                cipher: None,
+                factory,
+                incoming: VecDeque::new(),
+                receive_buffer: Vec::with_capacity(1024),
+                poisoned: None,
+                closed: false,
+            },
+            datagram_keys: None,
+        }
+    }
+
+    fn secured(mut self, handshake: Handshake) -> Self {
+        self.writer.cipher = Some(StreamCipher::sending(&handshake.stream));
+        self.reader.cipher = Some(StreamCipher::receiving(&handshake.stream));
+        self.datagram_keys = Some(handshake.datagram);
+        self
+    }
+
+    pub async fn connect(addr: impl ToSocketAddrs, factory: F) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self::new(stream, factory))
+    }
+
+    /// Connect to a listener using [`Listener::with_keypair`], failing unless it proves to hold
+    /// the secret key of `server`
+    pub async fn connect_secure(
+        addr: impl ToSocketAddrs,
+        factory: F,
+        server: &PublicKey,
+    ) -> Result<Self> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let handshake = tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            secure::client_handshake(&mut stream, server),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Handshake timed out"))??;
+        Ok(Self::new(stream, factory).secured(handshake))
+    }
+
+    /// Keys for securing datagrams with the peer through [`secure::PeerKeys`], `None` if the
+    /// connection isn't encrypted
+    pub fn datagram_keys(&self) -> Option<&SessionKeys> {
+        self.datagram_keys.as_ref()
+    }
+
+    /// Split into halves that can be used from different tasks
+    pub fn into_split(self) -> (WriteHalf<F::Message>, ReadHalf<F>) {
+        (self.writer, self.reader)
+    }
+}
+
+/// Sending half of a [`Connection`]
+pub struct WriteHalf<M: Message> {
+    stream: OwnedWriteHalf,
+    cipher: Option<StreamCipher>,
+    /// Set once a write failed
+    closed: bool,
+    _marker: PhantomData<fn(&M)>,
+}
+
+/// Receiving half of a [`Connection`]
+pub struct ReadHalf<F: MessageFactory> {
+    stream: OwnedReadHalf,
+    cipher: Option<StreamCipher>,
     incoming: VecDeque<F::Message>,
     /// Buffer to contain a partial message read from the stream
     receive_buffer: Vec<u8>,
     factory: F,
+    /// Why a frame failed to decode, the stream can't be resynchronized after that
+    poisoned: Option<String>,
+    /// Set once the stream reached its end or failed
+    closed: bool,
 }
 
-impl<F: MessageFactory> Connection<F> {
-    pub async fn connect(addr: impl ToSocketAddrs, factory: F) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
-        Ok(Self {
-            stream,
-            factory,
-            incoming: VecDeque::new(),
-            receive_buffer: Vec::with_capacity(1024),
-        })
+impl<F: MessageFactory> ReadHalf<F> {
+    /// Deserialize every complete message in the receive buffer, keeping a trailing partial one
+    ///
+    /// Messages decoded before a bad frame are still delivered, after which the stream is
+    /// poisoned.
+    fn decode_buffer(&mut self) {
+        let mut cursor = 0;
+        if let Err(e) = self.decode_frames(&mut cursor) {
+            self.poisoned = Some(format!("{:#}", e));
+        }
+        self.receive_buffer.drain(..cursor);
     }
 
-    async fn receive_to_buffer(&mut self, needed: usize) -> Result<usize> {
-        let initial = self.receive_buffer.len();
-        while self.receive_buffer.len() < initial + needed {
-            let mut buffer = [0u8; BUFFER_SIZE];
-            let len = self.stream.read(&mut buffer).await?;
-            self.receive_buffer.extend_from_slice(&buffer[..len]);
+    fn decode_frames(&mut self, cursor: &mut usize) -> Result<()> {
+        while self.receive_buffer.len() - *cursor >= LENGTH_SIZE {
+            let data_len = length_prefix(&self.receive_buffer[*cursor..])?;
+            let start = *cursor + LENGTH_SIZE;
+            if self.receive_buffer.len() - start < data_len {
+                break;
+            }
+
+            // The frame counts as consumed even if it fails, it must never be decoded again
+            *cursor = start + data_len;
+            let data = &self.receive_buffer[start..start + data_len];
+            let opened;
+            let data = match self.cipher.as_mut() {
+                Some(cipher) => {
+                    opened = cipher.open(data)?;
+                    &opened[..]
+                }
+                None => data,
+            };
+            let (message, _) = self.factory.deserialize(&(), data)?;
+            self.incoming.push_back(message);
         }
-        Ok(self.receive_buffer.len())
+        Ok(())
+    }
+
+    fn check_poisoned(&self) -> Result<()> {
+        if let Some(reason) = &self.poisoned {
+            return Err(anyhow::anyhow!("Stream is corrupt: {}", reason));
+        }
+        Ok(())
     }
 }
 
-const BUFFER_SIZE: usize = 512;
+/// Bytes read from the stream at a time
+const READ_SIZE: usize = 4096;
+
+/// Size of the little endian length prefix in front of every message
+const LENGTH_SIZE: usize = size_of::<u32>();
+
+fn length_prefix(data: &[u8]) -> Result<usize> {
+    let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(anyhow::anyhow!(
+            "Message of {} bytes exceeds the maximum of {}",
+            len,
+            MAX_MESSAGE_SIZE
+        ));
+    }
+    Ok(len)
+}
 
 #[async_trait]
-impl<F: MessageFactory> Unreliable<F::Message> for Connection<F> {
-    async fn send(&mut self, message: &F::Message) -> Result<()> {
-        let mut buffer = [0u8; BUFFER_SIZE];
-        let len = message.serialize(&mut buffer[size_of::<u16>()..])?;
-        let size_bytes = (len as u16).to_le_bytes();
-        buffer[0] = size_bytes[0];
-        buffer[1] = size_bytes[1];
-        self.stream.write(&buffer[..len + size_of::<u16>()]).await?;
+impl<M: Message> SendHalf<M> for WriteHalf<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        let mut buffer = buffer::get();
+        let mut len = buffer::serialize(&mut buffer, message.size_hint() + LENGTH_SIZE, |data| {
+            message.serialize(&mut data[LENGTH_SIZE..])
+        })?;
+        if let Some(cipher) = self.cipher.as_mut() {
+            let sealed = cipher.seal(&buffer[LENGTH_SIZE..LENGTH_SIZE + len])?;
+            buffer.truncate(LENGTH_SIZE);
+            buffer.extend_from_slice(&sealed);
+            len = sealed.len();
+        }
+        if len > MAX_MESSAGE_SIZE {
+            return Err(anyhow::anyhow!(
+                "Message of {} bytes exceeds the maximum of {}",
+                len,
+                MAX_MESSAGE_SIZE
+            ));
+        }
+
+        buffer[..LENGTH_SIZE].copy_from_slice(&(len as u32).to_le_bytes());
+        if let Err(e) = self.stream.write_all(&buffer[..LENGTH_SIZE + len]).await {
+            self.closed = true;
+            return Err(e.into());
+        }
         Ok(())
     }
 
-    async fn receive(&mut self) -> Result<F::Message> {
-        if let Some(message) = self.incoming.pop_front() {
-            Ok(message)
-        } else {
-            // Read at least one message
-            let _ = self.receive_to_buffer(size_of::<u16>()).await?;
-            let data_len =
-                u16::from_le_bytes([self.receive_buffer[0], self.receive_buffer[1]]) as usize;
-            let _ = self.receive_to_buffer(data_len).await?;
-
-            // Deserialize messages
-            while self.receive_buffer.len() >= size_of::<u16>() {
-                let data_len =
-                    u16::from_le_bytes([self.receive_buffer[0], self.receive_buffer[1]]) as usize;
-                if self.receive_buffer[size_of::<u16>()..].len() < data_len {
-                    break;
-                }
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
 
-                let data = self
-                    .receive_buffer
-                    .drain(..size_of::<u16>() + data_len)
-                    .collect::<Vec<u8>>();
-                let (message, _) = self.factory.deserialize(&(), &data[size_of::<u16>()..])?;
-                self.incoming.push_back(message);
+#[async_trait]
+impl<F: MessageFactory> ReceiveHalf<F::Message> for ReadHalf<F> {
+    async fn receive(&mut self) -> Result<F::Message> {
+        loop {
+            if let Some(message) = self.incoming.pop_front() {
+                return Ok(message);
+            }
+            self.check_poisoned()?;
+            if self.closed {
+                return Err(anyhow::anyhow!("Connection closed"));
             }
 
-            // Safe because the above ensures there is at least one message
-            Ok(self.incoming.pop_front().unwrap())
+            let mut buffer = [0u8; READ_SIZE];
+            let len = match self.stream.read(&mut buffer).await {
+                Ok(len) => len,
+                Err(e) => {
+                    self.closed = true;
+                    return Err(e.into());
+                }
+            };
+            if len == 0 {
+                self.closed = true;
+                return Err(anyhow::anyhow!("Connection closed"));
+            }
+            self.receive_buffer.extend_from_slice(&buffer[..len]);
+            self.decode_buffer();
         }
     }
 
     fn try_receive(&mut self) -> Result<Option<F::Message>> {
-        let mut buffer = [0u8; BUFFER_SIZE];
-        match self.stream.try_read(&mut buffer) {
-            Ok(len) if len > 0 => {
-                let (message, _) = self.factory.deserialize(&(), &buffer[..len])?;
-                Ok(Some(message))
+        if let Some(message) = self.incoming.pop_front() {
+            return Ok(Some(message));
+        }
+        self.check_poisoned()?;
+
+        // Drain whatever the socket has ready without waiting
+        while !self.closed {
+            let mut buffer = [0u8; READ_SIZE];
+            match self.stream.try_read(&mut buffer) {
+                Ok(0) => self.closed = true,
+                Ok(len) => self.receive_buffer.extend_from_slice(&buffer[..len]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    self.closed = true;
+                    return Err(e.into());
+                }
             }
-            Ok(_) => Ok(None),
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
-            Err(e) => Err(e.into()),
         }
+        self.decode_buffer();
+
+        if let Some(message) = self.incoming.pop_front() {
+            return Ok(Some(message));
+        }
+        self.check_poisoned()?;
+        if self.closed {
+            return Err(anyhow::anyhow!("Connection closed"));
+        }
+        Ok(None)
+    }
+
+    /// Closed once the messages received before the end of the stream have been taken
+    fn is_closed(&self) -> bool {
+        self.incoming.is_empty() && (self.closed || self.poisoned.is_some())
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory> Unreliable<F::Message> for Connection<F> {
+    async fn send(&mut self, message: &F::Message) -> Result<()> {
+        self.writer.send(message).await
+    }
+
+    async fn receive(&mut self) -> Result<F::Message> {
+        self.reader.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<F::Message>> {
+        self.reader.try_receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.writer.is_closed() || self.reader.is_closed()
+    }
+
+    fn split<'a>(
+        self: Box<Self>,
+    ) -> (
+        Box<dyn SendHalf<F::Message> + 'a>,
+        Box<dyn ReceiveHalf<F::Message> + 'a>,
+    )
+    where
+        Self: 'a,
+    {
+        (Box::new(self.writer), Box::new(self.reader))
     }
 }
 
 impl<F: MessageFactory> Reliable<F::Message> for Connection<F> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::StringFactoryNew;
+
+    /// Raw stream to write frames into, and the connection reading them
+    async fn connected() -> (TcpStream, Connection<StringFactoryNew>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (raw, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (
+            raw.unwrap(),
+            Connection::new(accepted.unwrap().0, StringFactoryNew),
+        )
+    }
+
+    fn frame(message: &str) -> Vec<u8> {
+        let mut data = vec![0u8; message.to_string().size_hint()];
+        let len = message.to_string().serialize(&mut data).unwrap();
+        [&(len as u32).to_le_bytes()[..], &data[..len]].concat()
+    }
+
+    fn messages() -> Vec<String> {
+        vec![
+            "a".to_string(),
+            String::new(),
+            "x".repeat(300),
+            "last".to_string(),
+        ]
+    }
+
+    fn frames() -> Vec<u8> {
+        messages()
+            .iter()
+            .map(|message| frame(message))
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
+    /// Feed `chunks` to the reader one at a time, as if each came from one read
+    fn decode_chunks(reader: &mut ReadHalf<StringFactoryNew>, chunks: &[&[u8]]) -> Vec<String> {
+        for chunk in chunks {
+            reader.receive_buffer.extend_from_slice(chunk);
+            reader.decode_buffer();
+            reader.check_poisoned().unwrap();
+        }
+        assert!(reader.receive_buffer.is_empty());
+        reader.incoming.drain(..).collect()
+    }
+
+    #[tokio::test]
+    async fn decodes_frames_split_at_any_byte() {
+        let (_raw, connection) = connected().await;
+        let (_, mut reader) = connection.into_split();
+        let data = frames();
+
+        for first in 0..=data.len() {
+            for second in first..=data.len() {
+                let chunks = [&data[..first], &data[first..second], &data[second..]];
+                assert_eq!(decode_chunks(&mut reader, &chunks), messages());
+            }
+        }
+
+        let bytes = data.chunks(1).collect::<Vec<_>>();
+        assert_eq!(decode_chunks(&mut reader, &bytes), messages());
+    }
+
+    #[tokio::test]
+    async fn receives_frames_written_in_pieces() {
+        let (mut raw, mut connection) = connected().await;
+        let data = [frames(), frames()].concat();
+
+        // Odd sized writes merge the end of one frame with the start of the next
+        for chunk in data.chunks(7) {
+            raw.write_all(chunk).await.unwrap();
+            raw.flush().await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        for expected in [messages(), messages()].concat() {
+            assert_eq!(connection.receive().await.unwrap(), expected);
+        }
+    }
+
+    /// Wait for the socket to have data, then take a message if one is complete
+    async fn try_receive_ready(connection: &mut Connection<StringFactoryNew>) -> Option<String> {
+        connection.reader.stream.readable().await.unwrap();
+        connection.try_receive().unwrap()
+    }
+
+    #[tokio::test]
+    async fn try_receive_waits_for_whole_frames() {
+        let (mut raw, mut connection) = connected().await;
+
+        // One frame in two writes
+        let data = frame("split");
+        let (first, second) = data.split_at(data.len() / 2);
+        raw.write_all(first).await.unwrap();
+        while connection.reader.receive_buffer.len() < first.len() {
+            assert_eq!(try_receive_ready(&mut connection).await, None);
+        }
+        assert_eq!(connection.try_receive().unwrap(), None);
+        raw.write_all(second).await.unwrap();
+        let message = loop {
+            if let Some(message) = try_receive_ready(&mut connection).await {
+                break message;
+            }
+        };
+        assert_eq!(message, "split");
+        assert_eq!(connection.try_receive().unwrap(), None);
+
+        // Two frames in one write
+        raw.write_all(&[frame("one"), frame("two")].concat())
+            .await
+            .unwrap();
+        let message = loop {
+            if let Some(message) = try_receive_ready(&mut connection).await {
+                break message;
+            }
+        };
+        assert_eq!(message, "one");
+        assert_eq!(connection.try_receive().unwrap().as_deref(), Some("two"));
+        assert_eq!(connection.try_receive().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn bad_frame_poisons_the_stream() {
+        let (mut raw, mut connection) = connected().await;
+        let garbage = [&3u32.to_le_bytes()[..], &[0xff, 0xff, 0xff]].concat();
+        let data = [frame("before"), garbage, frame("after")].concat();
+        raw.write_all(&data).await.unwrap();
+
+        assert_eq!(connection.receive().await.unwrap(), "before");
+        assert!(connection.receive().await.is_err());
+        // The bad frame isn't decoded again, nor anything after it
+        assert!(connection.receive().await.is_err());
+        assert!(connection.try_receive().is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_once_peer_hangs_up() {
+        let (mut raw, mut connection) = connected().await;
+        raw.write_all(&frame("bye")).await.unwrap();
+        drop(raw);
+
+        assert!(!connection.is_closed());
+        assert_eq!(connection.receive().await.unwrap(), "bye");
+        assert!(connection.receive().await.is_err());
+        assert!(connection.is_closed());
+    }
+}
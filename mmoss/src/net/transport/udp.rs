@@ -1,51 +1,706 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use crate::net::transport::{Addressed, AddressedFactory, Message, MessageFactory, Unreliable};
+use crate::net::{
+    buffer::{self, MAX_MESSAGE_SIZE},
+    transport::{
+        Addressed, AddressedFactory, Message, MessageFactory, ReceiveHalf, SendHalf, Unreliable,
+        secure::{self, PeerKeys},
+    },
+};
 use anyhow::Result;
 use async_trait::async_trait;
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use log::debug;
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{
+        Mutex as AsyncMutex,
+        mpsc::{self, error::TryRecvError},
+    },
+};
+
+/// Largest datagram sent, small enough to avoid IP fragmentation on common links
+const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// Largest datagram before sealing, leaving room for what sealing adds on secured sockets
+const MAX_PLAIN_DATAGRAM_SIZE: usize = MAX_DATAGRAM_SIZE - secure::DATAGRAM_OVERHEAD;
+
+/// Largest message sent in a single datagram, larger ones are fragmented
+pub const MAX_PAYLOAD_SIZE: usize = MAX_PLAIN_DATAGRAM_SIZE - 1;
+
+/// Largest datagram that can be received
+const RECEIVE_SIZE: usize = u16::MAX as usize;
+
+/// Incomplete messages are dropped when their fragments don't all arrive within this time
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages queued for a [`Peer`] before further ones are dropped
+const PEER_QUEUE_SIZE: usize = 1024;
+
+/// Unknown peers waiting for [`Demux::accept`] before datagrams from further ones are dropped
+const MAX_PENDING_PEERS: usize = 64;
+
+/// Datagram carrying a whole message
+const WHOLE: u8 = 0;
+
+/// Datagram carrying one fragment of a message
+const FRAGMENT: u8 = 1;
+
+/// Kind, message id, fragment index and fragment count
+const FRAGMENT_HEADER_SIZE: usize = 1 + size_of::<u32>() + 2 * size_of::<u16>();
+
+const FRAGMENT_PAYLOAD_SIZE: usize = MAX_PLAIN_DATAGRAM_SIZE - FRAGMENT_HEADER_SIZE;
+
+/// Messages being reassembled from one peer before fragments of further ones are dropped
+const MAX_REASSEMBLIES_PER_PEER: usize = 8;
+
+/// Messages being reassembled from all peers before fragments of further ones are dropped
+const MAX_REASSEMBLIES: usize = 1024;
+
+/// Bytes of fragments held for reassembly before further ones are dropped
+const MAX_REASSEMBLY_SIZE: usize = 4 * MAX_MESSAGE_SIZE;
+
+/// Fragments of a message received so far
+struct Fragments {
+    parts: BTreeMap<u16, Vec<u8>>,
+    count: u16,
+    size: usize,
+    started: Instant,
+}
+
+/// Messages being reassembled, bounded in number and size so peers can't exhaust memory
+#[derive(Default)]
+struct Reassembly {
+    messages: HashMap<(SocketAddr, u32), Fragments>,
+    per_peer: HashMap<SocketAddr, usize>,
+    /// Messages in the order they were started, to expire them without scanning all
+    started: VecDeque<(Instant, (SocketAddr, u32))>,
+    size: usize,
+}
+
+impl Reassembly {
+    /// Drop messages whose fragments didn't all arrive in time
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(started, key)) = self.started.front() {
+            if now - started < FRAGMENT_TIMEOUT {
+                break;
+            }
+            self.started.pop_front();
+            // The message may have completed, or its id been reused since
+            if self
+                .messages
+                .get(&key)
+                .is_some_and(|fragments| fragments.started == started)
+            {
+                self.remove(&key);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &(SocketAddr, u32)) -> Option<Fragments> {
+        let fragments = self.messages.remove(key)?;
+        self.size -= fragments.size;
+        if let Some(count) = self.per_peer.get_mut(&key.0) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_peer.remove(&key.0);
+            }
+        }
+        Some(fragments)
+    }
+
+    /// Add a fragment, returning the message once all its fragments arrived
+    fn insert(
+        &mut self,
+        key: (SocketAddr, u32),
+        index: u16,
+        count: u16,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>> {
+        self.expire(now);
+        if self.size + data.len() > MAX_REASSEMBLY_SIZE {
+            return Err(anyhow::anyhow!("Too many fragments awaiting reassembly"));
+        }
+
+        let fragments = match self.messages.get_mut(&key) {
+            Some(fragments) => fragments,
+            None => {
+                let peer_count = self.per_peer.get(&key.0).copied().unwrap_or(0);
+                if peer_count >= MAX_REASSEMBLIES_PER_PEER
+                    || self.messages.len() >= MAX_REASSEMBLIES
+                {
+                    return Err(anyhow::anyhow!(
+                        "Too many messages awaiting reassembly from {}",
+                        key.0
+                    ));
+                }
+                self.per_peer.insert(key.0, peer_count + 1);
+                self.started.push_back((now, key));
+                self.messages.entry(key).or_insert(Fragments {
+                    parts: BTreeMap::new(),
+                    count,
+                    size: 0,
+                    started: now,
+                })
+            }
+        };
+        if fragments.count != count {
+            return Err(anyhow::anyhow!(
+                "Fragment count mismatch for message {} from {}",
+                key.1,
+                key.0
+            ));
+        }
+        if fragments.parts.contains_key(&index) {
+            return Ok(None);
+        }
+
+        fragments.parts.insert(index, data.to_vec());
+        fragments.size += data.len();
+        self.size += data.len();
+        if fragments.parts.len() < count as usize {
+            return Ok(None);
+        }
+
+        let fragments = self.remove(&key).unwrap();
+        Ok(Some(fragments.parts.into_values().flatten().collect()))
+    }
+}
 
 pub struct Udp<F: MessageFactory> {
-    socket: UdpSocket,
-    factory: AddressedFactory<SocketAddr, F>,
+    writer: WriteHalf<F::Message>,
+    reader: ReadHalf<F>,
 }
 
 impl<F: MessageFactory> Udp<F> {
     pub async fn bind<A: ToSocketAddrs>(addr: A, factory: F) -> Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
         Ok(Self {
-            socket: UdpSocket::bind(addr).await?,
-            factory: AddressedFactory::new(factory),
+            writer: WriteHalf {
+                socket: socket.clone(),
+                next_message_id: 0,
+                peers: None,
+                _marker: PhantomData,
+            },
+            reader: ReadHalf {
+                socket,
+                factory: AddressedFactory::new(factory),
+                reassembly: Reassembly::default(),
+                peers: None,
+            },
         })
     }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.reader.socket.local_addr()?)
+    }
+
+    /// Seal every datagram for its peer and drop datagrams that aren't from a peer in `peers` or
+    /// fail authentication
+    pub fn with_peer_keys(mut self, peers: PeerKeys) -> Self {
+        self.writer.peers = Some(peers.clone());
+        self.reader.peers = Some(peers);
+        self
+    }
+
+    /// Split into halves sharing the socket, which can be used from different tasks
+    pub fn into_split(self) -> (WriteHalf<F::Message>, ReadHalf<F>) {
+        (self.writer, self.reader)
+    }
+}
+
+impl<F: MessageFactory + 'static> Udp<F> {
+    /// Serve every peer of the socket as its own transport, so wrappers made for connections
+    /// such as [`KeepAlive`](super::keepalive::KeepAlive) can be used per peer
+    ///
+    /// Must be called within a tokio runtime, which runs the background task receiving datagrams.
+    pub fn demux(self) -> Demux<F::Message> {
+        let writer = Arc::new(AsyncMutex::new(self.writer));
+        let routes = Routes::default();
+        let (new_peers_sender, new_peers) = mpsc::channel(MAX_PENDING_PEERS);
+
+        tokio::spawn(route(
+            self.reader,
+            writer.clone(),
+            routes.clone(),
+            new_peers_sender,
+        ));
+
+        Demux {
+            writer,
+            routes,
+            new_peers,
+        }
+    }
+}
+
+/// Sending half of a [`Udp`] socket
+pub struct WriteHalf<M: Message> {
+    socket: Arc<UdpSocket>,
+    next_message_id: u32,
+    peers: Option<PeerKeys>,
+    _marker: PhantomData<fn(&M)>,
+}
+
+/// Receiving half of a [`Udp`] socket
+pub struct ReadHalf<F: MessageFactory> {
+    socket: Arc<UdpSocket>,
+    factory: AddressedFactory<SocketAddr, F>,
+    reassembly: Reassembly,
+    peers: Option<PeerKeys>,
+}
+
+impl<F: MessageFactory> ReadHalf<F> {
+    fn deserialize(
+        &self,
+        addr: SocketAddr,
+        data: &[u8],
+    ) -> Result<Option<Addressed<SocketAddr, F::Message>>> {
+        let (message, _) = self.factory.deserialize(&addr, data)?;
+        Ok(Some(message))
+    }
+
+    /// Handle a received datagram, returning a message once it is complete
+    ///
+    /// Datagrams of secured sockets are authenticated and decrypted first, so forged ones never
+    /// reach reassembly.
+    fn receive_datagram(
+        &mut self,
+        addr: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Option<Addressed<SocketAddr, F::Message>>> {
+        let opened;
+        let datagram = match self.peers.as_ref() {
+            Some(peers) => match peers.open(addr, datagram) {
+                Some(datagram) => {
+                    opened = datagram;
+                    &opened[..]
+                }
+                None => {
+                    debug!("Dropped unauthenticated datagram from {}", addr);
+                    return Ok(None);
+                }
+            },
+            None => datagram,
+        };
+
+        match datagram.first() {
+            Some(&WHOLE) => self.deserialize(addr, &datagram[1..]),
+            Some(&FRAGMENT) if datagram.len() > FRAGMENT_HEADER_SIZE => {
+                let message_id =
+                    u32::from_le_bytes([datagram[1], datagram[2], datagram[3], datagram[4]]);
+                let index = u16::from_le_bytes([datagram[5], datagram[6]]);
+                let count = u16::from_le_bytes([datagram[7], datagram[8]]);
+                if index >= count || count as usize * FRAGMENT_PAYLOAD_SIZE > MAX_MESSAGE_SIZE {
+                    return Err(anyhow::anyhow!(
+                        "Invalid fragment {} of {} from {}",
+                        index,
+                        count,
+                        addr
+                    ));
+                }
+
+                let data = self.reassembly.insert(
+                    (addr, message_id),
+                    index,
+                    count,
+                    &datagram[FRAGMENT_HEADER_SIZE..],
+                    Instant::now(),
+                )?;
+                match data {
+                    Some(data) => self.deserialize(addr, &data),
+                    None => Ok(None),
+                }
+            }
+            _ => Err(anyhow::anyhow!("Malformed datagram from {}", addr)),
+        }
+    }
 }
 
 #[async_trait]
-impl<F: MessageFactory> Unreliable<Addressed<SocketAddr, F::Message>> for Udp<F> {
-    async fn send(&mut self, message: &Addressed<SocketAddr, F::Message>) -> Result<()> {
-        let mut buffer = [0u8; 512];
-        let len = message.serialize(&mut buffer)?;
-        self.socket.send_to(&buffer[..len], message.address).await?;
+impl<M: Message> SendHalf<Addressed<SocketAddr, M>> for WriteHalf<M> {
+    async fn send(&mut self, message: &Addressed<SocketAddr, M>) -> Result<()> {
+        self.send_to(message.address, &message.message).await
+    }
+}
+
+impl<M: Message> WriteHalf<M> {
+    async fn send_to(&mut self, address: SocketAddr, message: &M) -> Result<()> {
+        let mut buffer = buffer::get();
+        let len = buffer::serialize(&mut buffer, message.size_hint() + 1, |data| {
+            message.serialize(&mut data[1..])
+        })?;
+
+        if len <= MAX_PAYLOAD_SIZE {
+            buffer[0] = WHOLE;
+            return self.send_datagram(address, &buffer[..len + 1]).await;
+        }
+
+        let count = len.div_ceil(FRAGMENT_PAYLOAD_SIZE);
+        if count > u16::MAX as usize || len > MAX_MESSAGE_SIZE {
+            return Err(anyhow::anyhow!(
+                "Message of {} bytes exceeds the maximum of {}",
+                len,
+                MAX_MESSAGE_SIZE
+            ));
+        }
+
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+
+        let mut datagram = [0u8; MAX_PLAIN_DATAGRAM_SIZE];
+        for (index, payload) in buffer[1..len + 1].chunks(FRAGMENT_PAYLOAD_SIZE).enumerate() {
+            datagram[0] = FRAGMENT;
+            datagram[1..5].copy_from_slice(&message_id.to_le_bytes());
+            datagram[5..7].copy_from_slice(&(index as u16).to_le_bytes());
+            datagram[7..9].copy_from_slice(&(count as u16).to_le_bytes());
+            datagram[FRAGMENT_HEADER_SIZE..FRAGMENT_HEADER_SIZE + payload.len()]
+                .copy_from_slice(payload);
+            self.send_datagram(address, &datagram[..FRAGMENT_HEADER_SIZE + payload.len()])
+                .await?;
+        }
         Ok(())
     }
 
+    /// Send a datagram, sealed for `address` if the socket is secured
+    async fn send_datagram(&self, address: SocketAddr, datagram: &[u8]) -> Result<()> {
+        match self.peers.as_ref() {
+            Some(peers) => {
+                let sealed = peers.seal(address, datagram)?;
+                self.socket.send_to(&sealed, address).await?;
+            }
+            None => {
+                self.socket.send_to(datagram, address).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory> ReceiveHalf<Addressed<SocketAddr, F::Message>> for ReadHalf<F> {
     async fn receive(&mut self) -> Result<Addressed<SocketAddr, F::Message>> {
-        let mut buffer = [0u8; 512];
+        let mut buffer = buffer::get();
+        buffer.resize(RECEIVE_SIZE, 0);
+
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buffer).await?;
+            if let Some(message) = self.receive_datagram(addr, &buffer[..len])? {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn try_receive(&mut self) -> Result<Option<Addressed<SocketAddr, F::Message>>> {
+        let mut buffer = buffer::get();
+        buffer.resize(RECEIVE_SIZE, 0);
+
+        loop {
+            match self.socket.try_recv_from(&mut buffer) {
+                Ok((len, addr)) => {
+                    if let Some(message) = self.receive_datagram(addr, &buffer[..len])? {
+                        return Ok(Some(message));
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<F: MessageFactory> Unreliable<Addressed<SocketAddr, F::Message>> for Udp<F> {
+    async fn send(&mut self, message: &Addressed<SocketAddr, F::Message>) -> Result<()> {
+        self.writer.send(message).await
+    }
 
-        let (len, addr) = self.socket.recv_from(&mut buffer).await?;
-        let (message, _) = self.factory.deserialize(&addr, &buffer[..len])?;
-        Ok(message)
+    async fn receive(&mut self) -> Result<Addressed<SocketAddr, F::Message>> {
+        self.reader.receive().await
     }
 
     fn try_receive(&mut self) -> Result<Option<Addressed<SocketAddr, F::Message>>> {
-        let mut buffer = [0u8; 512];
+        self.reader.try_receive()
+    }
+
+    fn split<'a>(
+        self: Box<Self>,
+    ) -> (
+        Box<dyn SendHalf<Addressed<SocketAddr, F::Message>> + 'a>,
+        Box<dyn ReceiveHalf<Addressed<SocketAddr, F::Message>> + 'a>,
+    )
+    where
+        Self: 'a,
+    {
+        (Box::new(self.writer), Box::new(self.reader))
+    }
+}
+
+type Routes<M> = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<M>>>>;
+
+fn lock<M>(routes: &Routes<M>) -> std::sync::MutexGuard<'_, HashMap<SocketAddr, mpsc::Sender<M>>> {
+    routes.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Receive datagrams and hand every message to the [`Peer`] it came from, until the socket fails
+/// or the [`Demux`] and all its peers are dropped
+async fn route<F: MessageFactory>(
+    mut reader: ReadHalf<F>,
+    writer: Arc<AsyncMutex<WriteHalf<F::Message>>>,
+    routes: Routes<F::Message>,
+    new_peers: mpsc::Sender<Peer<F::Message>>,
+) {
+    let mut buffer = buffer::get();
+    buffer.resize(RECEIVE_SIZE, 0);
 
-        match self.socket.try_recv_from(&mut buffer) {
-            Ok((len, addr)) => {
-                let (message, _) = self.factory.deserialize(&addr, &buffer[..len])?;
-                Ok(Some(message))
+    loop {
+        let (len, addr) = match reader.socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            // Reported on some platforms when an earlier datagram couldn't be delivered
+            Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionReset => continue,
+            Err(e) => {
+                debug!("Demultiplexed socket failed: {}", e);
+                break;
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
-            Err(e) => Err(e.into()),
+        };
+        let message = match reader.receive_datagram(addr, &buffer[..len]) {
+            Ok(Some(message)) => message.message,
+            Ok(None) => continue,
+            Err(e) => {
+                debug!("Dropped datagram: {}", e);
+                continue;
+            }
+        };
+
+        let mut routes_guard = lock(&routes);
+        if let Some(route) = routes_guard.get(&addr) {
+            match route.try_send(message) {
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    routes_guard.remove(&addr);
+                }
+                // A peer that doesn't keep up loses messages, as it would on a congested link
+                Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => {}
+            }
+            continue;
+        }
+
+        if new_peers.is_closed() {
+            if routes_guard.is_empty() {
+                break;
+            }
+            continue;
+        }
+        let Ok(permit) = new_peers.try_reserve() else {
+            debug!(
+                "Dropped datagram from {}, too many peers awaiting accept",
+                addr
+            );
+            continue;
+        };
+        let (sender, incoming) = mpsc::channel(PEER_QUEUE_SIZE);
+        // Can't fail as the channel is new
+        let _ = sender.try_send(message);
+        routes_guard.insert(addr, sender);
+        permit.send(Peer::new(addr, writer.clone(), incoming));
+    }
+}
+
+/// Per peer transports sharing one UDP socket, created by [`Udp::demux`]
+///
+/// Messages from unknown addresses open a new peer, returned by [`Self::accept`].
+pub struct Demux<M: Message> {
+    writer: Arc<AsyncMutex<WriteHalf<M>>>,
+    routes: Routes<M>,
+    new_peers: mpsc::Receiver<Peer<M>>,
+}
+
+impl<M: Message + 'static> Demux<M> {
+    /// Transport to `addr`, receiving whatever it sends from now on instead of earlier peers for
+    /// the same address
+    pub fn connect(&self, addr: SocketAddr) -> Peer<M> {
+        let (sender, incoming) = mpsc::channel(PEER_QUEUE_SIZE);
+        lock(&self.routes).insert(addr, sender);
+        Peer::new(addr, self.writer.clone(), incoming)
+    }
+
+    /// Wait for a message from an address without a peer
+    pub async fn accept(&mut self) -> Result<Peer<M>> {
+        self.new_peers
+            .recv()
+            .await
+            .ok_or(anyhow::anyhow!("Socket closed"))
+    }
+}
+
+/// Sending half of a [`Peer`]
+pub struct PeerSender<M: Message> {
+    addr: SocketAddr,
+    writer: Arc<AsyncMutex<WriteHalf<M>>>,
+}
+
+/// Receiving half of a [`Peer`]
+pub struct PeerReceiver<M: Message> {
+    incoming: mpsc::Receiver<M>,
+}
+
+#[async_trait]
+impl<M: Message> SendHalf<M> for PeerSender<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        self.writer.lock().await.send_to(self.addr, message).await
+    }
+}
+
+#[async_trait]
+impl<M: Message> ReceiveHalf<M> for PeerReceiver<M> {
+    async fn receive(&mut self) -> Result<M> {
+        self.incoming
+            .recv()
+            .await
+            .ok_or(anyhow::anyhow!("Socket closed"))
+    }
+
+    fn try_receive(&mut self) -> Result<Option<M>> {
+        match self.incoming.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("Socket closed")),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.incoming.is_closed() && self.incoming.is_empty()
+    }
+}
+
+/// Unreliable transport to one address of a [`Demux`]ed socket
+///
+/// Only closed when the socket fails, wrap it in a
+/// [`KeepAlive`](super::keepalive::KeepAlive) to notice the peer going silent.
+pub struct Peer<M: Message> {
+    sender: PeerSender<M>,
+    receiver: PeerReceiver<M>,
+}
+
+impl<M: Message> Peer<M> {
+    fn new(
+        addr: SocketAddr,
+        writer: Arc<AsyncMutex<WriteHalf<M>>>,
+        incoming: mpsc::Receiver<M>,
+    ) -> Self {
+        Self {
+            sender: PeerSender { addr, writer },
+            receiver: PeerReceiver { incoming },
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.sender.addr
+    }
+}
+
+#[async_trait]
+impl<M: Message> Unreliable<M> for Peer<M> {
+    async fn send(&mut self, message: &M) -> Result<()> {
+        self.sender.send(message).await
+    }
+
+    async fn receive(&mut self) -> Result<M> {
+        self.receiver.receive().await
+    }
+
+    fn try_receive(&mut self) -> Result<Option<M>> {
+        self.receiver.try_receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.receiver.is_closed()
+    }
+
+    fn split<'a>(self: Box<Self>) -> (Box<dyn SendHalf<M> + 'a>, Box<dyn ReceiveHalf<M> + 'a>)
+    where
+        Self: 'a,
+    {
+        (Box::new(self.sender), Box::new(self.receiver))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::StringFactoryNew;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn round_trips_messages_larger_than_a_datagram() {
+        let mut a = Udp::bind("127.0.0.1:0", StringFactoryNew).await.unwrap();
+        let mut b = Udp::bind("127.0.0.1:0", StringFactoryNew).await.unwrap();
+        let b_addr = b.local_addr().unwrap();
+
+        let messages = [
+            "x".repeat(MAX_DATAGRAM_SIZE),
+            (0..20_000)
+                .map(|i| char::from(b'a' + (i % 26) as u8))
+                .collect(),
+            "small".to_string(),
+        ];
+        for message in &messages {
+            a.send(&Addressed::new(b_addr, message.clone()))
+                .await
+                .unwrap();
         }
+        for message in &messages {
+            let received = b.receive().await.unwrap();
+            assert_eq!(received.address, a.local_addr().unwrap());
+            assert_eq!(&received.message, message);
+        }
+    }
+
+    #[test]
+    fn limits_reassemblies_per_peer() {
+        let mut reassembly = Reassembly::default();
+        let now = Instant::now();
+        for id in 0..MAX_REASSEMBLIES_PER_PEER as u32 {
+            assert!(
+                reassembly
+                    .insert((addr(1), id), 0, 2, b"a", now)
+                    .unwrap()
+                    .is_none()
+            );
+        }
+        let id = MAX_REASSEMBLIES_PER_PEER as u32;
+        assert!(reassembly.insert((addr(1), id), 0, 2, b"a", now).is_err());
+        // Other peers and messages already started are unaffected
+        assert!(reassembly.insert((addr(2), id), 0, 2, b"a", now).is_ok());
+        let completed = reassembly.insert((addr(1), 0), 1, 2, b"b", now).unwrap();
+        assert_eq!(completed.unwrap(), b"ab");
+        assert!(reassembly.insert((addr(1), id), 0, 2, b"a", now).is_ok());
+    }
+
+    #[test]
+    fn expires_incomplete_messages() {
+        let mut reassembly = Reassembly::default();
+        let now = Instant::now();
+        reassembly.insert((addr(1), 0), 0, 2, b"a", now).unwrap();
+        reassembly.insert((addr(1), 1), 1, 2, b"b", now).unwrap();
+        assert_eq!(reassembly.size, 2);
+
+        reassembly.expire(now + FRAGMENT_TIMEOUT);
+        assert!(reassembly.messages.is_empty());
+        assert!(reassembly.per_peer.is_empty());
+        assert_eq!(reassembly.size, 0);
+
+        // The late fragment starts over rather than completing the expired message
+        let later = now + FRAGMENT_TIMEOUT;
+        let late = reassembly.insert((addr(1), 0), 1, 2, b"b", later).unwrap();
+        assert!(late.is_none());
     }
 }
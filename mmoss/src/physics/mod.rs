@@ -1,9 +1,9 @@
 use anyhow::Result;
 use bevy::{
-    ecs::{component::Component, entity::Entity},
+    ecs::{component::Component, entity::Entity, hierarchy::ChildOf, query::Has},
     math::{Quat, Vec3},
 };
-use bevy_trait_query::queryable;
+use bevy_trait_query::{One, queryable};
 use bincode::{Decode, Encode};
 
 pub mod proxy;
@@ -40,6 +40,49 @@ impl<Context> Decode<Context> for Transform {
     }
 }
 
+/// Fixed-point translation with a smallest-three encoded rotation
+impl convert::Quantize for Transform {
+    fn encode_quantized<E: bincode::enc::Encoder>(
+        &self,
+        fixed: &convert::FixedPoint,
+        encoder: &mut E,
+    ) -> core::result::Result<(), bincode::error::EncodeError> {
+        self.translation.encode_quantized(fixed, encoder)?;
+        convert::SmallestThree(self.rotation).encode(encoder)
+    }
+
+    fn decode_quantized<D: bincode::de::Decoder>(
+        fixed: &convert::FixedPoint,
+        decoder: &mut D,
+    ) -> core::result::Result<Self, bincode::error::DecodeError> {
+        let translation = Vec3::decode_quantized(fixed, decoder)?;
+        let rotation = convert::SmallestThree::decode(decoder)?.0;
+        Ok(Transform {
+            translation,
+            rotation,
+        })
+    }
+}
+
+impl Transform {
+    /// Composes `child`, expressed relative to this transform, into this transform's space
+    pub fn mul_transform(&self, child: &Transform) -> Transform {
+        Transform {
+            translation: self.translation + self.rotation * child.translation,
+            rotation: self.rotation * child.rotation,
+        }
+    }
+
+    /// Expresses this transform relative to `parent`
+    pub fn relative_to(&self, parent: &Transform) -> Transform {
+        let inverse = parent.rotation.inverse();
+        Transform {
+            translation: inverse * (self.translation - parent.translation),
+            rotation: inverse * self.rotation,
+        }
+    }
+}
+
 impl Default for Transform {
     fn default() -> Self {
         Self {
@@ -84,6 +127,34 @@ pub trait TransformComponent {
     fn transform(&self) -> &Transform;
 }
 
+/// Marker component for entities whose [`TransformComponent`] is relative to their [`ChildOf`] parent
+#[derive(Debug, Clone, Copy, Default, Component)]
+pub struct RelativeTransform;
+
+/// Computes the world-space transform of `entity`
+///
+/// Walks up the hierarchy for as long as entities are marked with [`RelativeTransform`],
+/// composing each parent's transform. Returns `None` if any entity along the way has no
+/// [`TransformComponent`].
+pub fn global_transform(world: &mut bevy::ecs::world::World, entity: Entity) -> Option<Transform> {
+    let mut query = world.query::<(
+        One<&dyn TransformComponent>,
+        Option<&ChildOf>,
+        Has<RelativeTransform>,
+    )>();
+
+    let (transform, child_of, relative) = query.get(world, entity).ok()?;
+    let mut result = transform.transform().clone();
+    let mut parent = child_of.filter(|_| relative).map(ChildOf::parent);
+    while let Some(current) = parent {
+        let (transform, child_of, relative) = query.get(world, current).ok()?;
+        result = transform.transform().mul_transform(&result);
+        parent = child_of.filter(|_| relative).map(ChildOf::parent);
+    }
+
+    Some(result)
+}
+
 /// Core actor trait for components
 #[queryable]
 pub trait StaticActorComponent: TransformComponent {}
@@ -5,25 +5,19 @@
 //! proxy types that only replicated a remote component.
 
 use crate::{
-    core::{
-        WorldContainer,
-        component_type::physics::{
-            DYNAMIC_ACTOR_PROXY_COMPONENT_TYPE, STATIC_ACTOR_PROXY_COMPONENT_TYPE,
-        },
+    core::component_type::physics::{
+        DYNAMIC_ACTOR_PROXY_COMPONENT_TYPE, STATIC_ACTOR_PROXY_COMPONENT_TYPE,
     },
     physics::{DynamicActorComponent, StaticActorComponent, TransformComponent},
     replication::Replicated,
 };
-use anyhow::Result;
-use async_trait::async_trait;
-use bevy::ecs::{component::Component, world::EntityWorldMut};
+use bevy::ecs::{component::Component, world::World};
+use bevy_trait_query::RegisterExt as _;
 use mmoss_proc_macros::Replicated;
 
-use crate::{physics::Transform, replication, replication::Id};
+use crate::{physics::Transform, replication::Id};
 
-use replication::client::factory::component::Entry as ComponentFactory;
-
-#[derive(Debug, Clone, Component, Replicated)]
+#[derive(Debug, Clone, Default, Component, Replicated)]
 #[component_type(DYNAMIC_ACTOR_PROXY_COMPONENT_TYPE)]
 pub struct DynamicActorComponentProxy {
     #[replication_id]
@@ -53,25 +47,7 @@ impl DynamicActorComponent for DynamicActorComponentProxy {
     }
 }
 
-pub struct DynamicActorComponentProxyFactory;
-
-#[async_trait(?Send)]
-impl<W: WorldContainer> ComponentFactory<W> for DynamicActorComponentProxyFactory {
-    async fn add_component(
-        &self,
-        mut entity: EntityWorldMut<'_>,
-        replication_id: Id,
-        data: &Vec<u8>,
-    ) -> Result<()> {
-        let mut component = DynamicActorComponentProxy::new(replication_id);
-
-        component.replicate(data)?;
-        entity.insert(component);
-        Ok(())
-    }
-}
-
-#[derive(Debug, Clone, Component, Replicated)]
+#[derive(Debug, Clone, Default, Component, Replicated)]
 #[component_type(STATIC_ACTOR_PROXY_COMPONENT_TYPE)]
 pub struct StaticActorComponentProxy {
     #[replication_id]
@@ -96,3 +72,14 @@ impl TransformComponent for StaticActorComponentProxy {
 }
 
 impl StaticActorComponent for StaticActorComponentProxy {}
+
+/// Register the proxy components with the trait queries they implement
+pub fn register_proxy_components(world: &mut World) {
+    world.register_component_as::<dyn TransformComponent, DynamicActorComponentProxy>();
+    world.register_component_as::<dyn DynamicActorComponent, DynamicActorComponentProxy>();
+    world.register_component_as::<dyn Replicated, DynamicActorComponentProxy>();
+
+    world.register_component_as::<dyn TransformComponent, StaticActorComponentProxy>();
+    world.register_component_as::<dyn StaticActorComponent, StaticActorComponentProxy>();
+    world.register_component_as::<dyn Replicated, StaticActorComponentProxy>();
+}
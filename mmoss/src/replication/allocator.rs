@@ -0,0 +1,162 @@
+//! Allocation of replication [`Id`](super::Id)s and [`SpawnId`](super::SpawnId)s
+//!
+//! Allocated values are split into an index in the low 24 bits and a generation in the high
+//! 8 bits. Freed indices are reused with the next generation, so a stale value still held by a
+//! client never aliases a live object. An index that has used up all of its generations is
+//! retired instead of wrapping around.
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+
+const INDEX_BITS: u32 = 24;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+const MAX_GENERATION: u8 = u8::MAX;
+
+/// Furthest a reserved index may lie past the ones handed out so far, so a stray value can't
+/// grow the tables by millions of entries
+const MAX_RESERVE_AHEAD: usize = 1 << 16;
+
+fn split(raw: u32) -> (usize, u8) {
+    ((raw & INDEX_MASK) as usize, (raw >> INDEX_BITS) as u8)
+}
+
+fn join(index: usize, generation: u8) -> u32 {
+    ((generation as u32) << INDEX_BITS) | index as u32
+}
+
+pub struct Allocator {
+    /// Current generation of each index
+    generations: Vec<u8>,
+    /// Whether each index is currently allocated
+    live: Vec<bool>,
+    /// Indices available for reuse, may contain entries that were since reserved
+    free: VecDeque<usize>,
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Allocator {
+    pub fn new() -> Self {
+        // Index 0 is never handed out so that a raw value of 0 can mean "unassigned"
+        Self {
+            generations: vec![0],
+            live: vec![true],
+            free: VecDeque::new(),
+        }
+    }
+
+    pub fn allocate(&mut self) -> Result<u32> {
+        while let Some(index) = self.free.pop_front() {
+            if !self.live[index] {
+                self.live[index] = true;
+                return Ok(join(index, self.generations[index]));
+            }
+        }
+
+        let index = self.generations.len();
+        if index > INDEX_MASK as usize {
+            return Err(anyhow::anyhow!("All {} indices are in use", INDEX_MASK));
+        }
+
+        self.generations.push(0);
+        self.live.push(true);
+        Ok(join(index, 0))
+    }
+
+    /// Claim a specific value chosen outside of the allocator
+    ///
+    /// Returns `false` if the index is already live, lies too far past the indices handed out so
+    /// far or the value belongs to an older generation.
+    pub fn reserve(&mut self, raw: u32) -> bool {
+        let (index, generation) = split(raw);
+        if index == 0 || index >= self.generations.len() + MAX_RESERVE_AHEAD {
+            return false;
+        }
+
+        while self.generations.len() <= index {
+            self.free.push_back(self.generations.len());
+            self.generations.push(0);
+            self.live.push(false);
+        }
+
+        if self.live[index] || generation < self.generations[index] {
+            return false;
+        }
+
+        self.generations[index] = generation;
+        self.live[index] = true;
+        true
+    }
+
+    /// Release a value so its index can be reused with the next generation
+    ///
+    /// Returns `false` if the value isn't currently live.
+    pub fn free(&mut self, raw: u32) -> bool {
+        if !self.is_live(raw) {
+            return false;
+        }
+
+        let (index, generation) = split(raw);
+        self.live[index] = false;
+        if generation < MAX_GENERATION {
+            self.generations[index] = generation + 1;
+            self.free.push_back(index);
+        }
+        true
+    }
+
+    pub fn is_live(&self, raw: u32) -> bool {
+        let (index, generation) = split(raw);
+        index != 0
+            && index < self.generations.len()
+            && self.live[index]
+            && self.generations[index] == generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_freed_indices_with_the_next_generation() -> Result<()> {
+        let mut allocator = Allocator::new();
+        let first = allocator.allocate()?;
+        assert!(allocator.free(first));
+        assert!(!allocator.free(first));
+
+        let second = allocator.allocate()?;
+        assert_eq!(split(second), (split(first).0, 1));
+        assert!(!allocator.is_live(first));
+        assert!(allocator.is_live(second));
+        Ok(())
+    }
+
+    #[test]
+    fn reserves_values_chosen_elsewhere() -> Result<()> {
+        let mut allocator = Allocator::new();
+        let reserved = join(10, 3);
+        assert!(allocator.reserve(reserved));
+        assert!(!allocator.reserve(reserved));
+        assert!(!allocator.reserve(join(10, 2)));
+        assert!(allocator.is_live(reserved));
+
+        // Indices skipped over are handed out next
+        assert_eq!(split(allocator.allocate()?), (1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_reservations_far_past_allocated_indices() {
+        let mut allocator = Allocator::new();
+        assert!(!allocator.reserve(join(MAX_RESERVE_AHEAD + 1, 0)));
+        assert!(!allocator.reserve(join(INDEX_MASK as usize, 0)));
+        assert!(allocator.reserve(join(MAX_RESERVE_AHEAD, 0)));
+        assert!(allocator.generations.len() <= MAX_RESERVE_AHEAD + 1);
+    }
+}
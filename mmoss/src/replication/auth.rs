@@ -0,0 +1,178 @@
+//! Authentication of clients when they connect
+//!
+//! The client's first message is a [`LoginData`] carrying a token issued by the game's backend.
+//! The server hands the token to a [`Validator`] and answers with [`LoginResultData`]. Only
+//! accepted clients are added to the [`server::Manager`](crate::replication::server::Manager),
+//! together with the [`Identity`] the validator returned.
+
+use std::{future::Future, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    net::transport::Unreliable,
+    replication::{LoginData, LoginResultData, Message},
+};
+
+/// Time a client has to log in, and the server to answer
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Who an authenticated client is, as reported by the [`Validator`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity(pub String);
+
+/// Checks login tokens, such as by asking the backend that issued them
+///
+/// The error of a rejected token is sent to the client as the reason.
+#[async_trait]
+pub trait Validator: Send + Sync {
+    async fn validate(&self, token: &[u8]) -> Result<Identity>;
+}
+
+#[async_trait]
+impl<F, Fut> Validator for F
+where
+    F: Fn(Vec<u8>) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Identity>> + Send,
+{
+    async fn validate(&self, token: &[u8]) -> Result<Identity> {
+        self(token.to_vec()).await
+    }
+}
+
+async fn answer<T: Unreliable<Message> + ?Sized>(
+    transport: &mut T,
+    reason: Option<String>,
+) -> Result<()> {
+    let result = LoginResultData {
+        accepted: reason.is_none(),
+        reason,
+    };
+    transport.send(&Message::LoginResult(result)).await?;
+    transport.flush().await
+}
+
+/// Wait for the client's token and validate it, telling the client the outcome either way
+pub async fn authenticate<T: Unreliable<Message> + ?Sized>(
+    transport: &mut T,
+    validator: &dyn Validator,
+    timeout: Duration,
+) -> Result<Identity> {
+    let message = tokio::time::timeout(timeout, transport.receive())
+        .await
+        .map_err(|_| anyhow::anyhow!("Client did not log in in time"))??;
+    let Message::Login(login) = message else {
+        let _ = answer(transport, Some("Expected login".to_string())).await;
+        return Err(anyhow::anyhow!("Expected login, got {:?}", message));
+    };
+
+    match validator.validate(&login.token).await {
+        Ok(identity) => {
+            answer(transport, None).await?;
+            Ok(identity)
+        }
+        Err(e) => {
+            let _ = answer(transport, Some(e.to_string())).await;
+            Err(e.context("Login rejected"))
+        }
+    }
+}
+
+/// Present `token` to a server requiring authentication, before replicating from `transport`
+pub async fn login<T: Unreliable<Message> + ?Sized>(
+    transport: &mut T,
+    token: Vec<u8>,
+    timeout: Duration,
+) -> Result<()> {
+    transport.send(&Message::Login(LoginData { token })).await?;
+    transport.flush().await?;
+
+    let message = tokio::time::timeout(timeout, transport.receive())
+        .await
+        .map_err(|_| anyhow::anyhow!("Server did not answer login in time"))??;
+    match message {
+        Message::LoginResult(LoginResultData { accepted: true, .. }) => Ok(()),
+        Message::LoginResult(LoginResultData { reason, .. }) => Err(anyhow::anyhow!(
+            "Login rejected: {}",
+            reason.unwrap_or_default()
+        )),
+        message => Err(anyhow::anyhow!("Expected login result, got {:?}", message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        net::transport::memory,
+        replication::{DespawnData, MessageFactoryNew, SpawnId},
+    };
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    async fn validate(token: Vec<u8>) -> Result<Identity> {
+        match token.as_slice() {
+            b"alice" => Ok(Identity("alice".to_string())),
+            _ => Err(anyhow::anyhow!("Unknown token")),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_valid_token() {
+        let (mut server, mut client) = memory::pair(MessageFactoryNew, MessageFactoryNew);
+        let (identity, result) = tokio::join!(
+            authenticate(&mut server, &validate, TIMEOUT),
+            login(&mut client, b"alice".to_vec(), TIMEOUT)
+        );
+        assert_eq!(identity.unwrap(), Identity("alice".to_string()));
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_token_with_reason() {
+        let (mut server, mut client) = memory::pair(MessageFactoryNew, MessageFactoryNew);
+        let (identity, result) = tokio::join!(
+            authenticate(&mut server, &validate, TIMEOUT),
+            login(&mut client, b"mallory".to_vec(), TIMEOUT)
+        );
+        assert!(identity.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Login rejected: Unknown token"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_other_messages_than_login() {
+        let (mut server, mut client) = memory::pair(MessageFactoryNew, MessageFactoryNew);
+        client
+            .send(&Message::Despawn(DespawnData {
+                spawn_id: SpawnId(1),
+            }))
+            .await
+            .unwrap();
+        assert!(authenticate(&mut server, &validate, TIMEOUT).await.is_err());
+
+        let Message::LoginResult(result) = client.receive().await.unwrap() else {
+            panic!("Expected login result");
+        };
+        assert!(!result.accepted);
+        assert_eq!(result.reason.as_deref(), Some("Expected login"));
+    }
+
+    #[tokio::test]
+    async fn times_out_silent_peers() {
+        let (mut server, mut client) = memory::pair(MessageFactoryNew, MessageFactoryNew);
+        let error = authenticate(&mut server, &validate, TIMEOUT)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Client did not log in in time");
+
+        let error = login(&mut client, b"alice".to_vec(), TIMEOUT)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Server did not answer login in time");
+    }
+}
@@ -0,0 +1,206 @@
+//! Bit-level packing of small replicated values
+//!
+//! Fields marked with `#[replicated(bits = N)]` are written with exactly `N` bits into a block
+//! preceding the byte-aligned fields, so booleans, small integers and enums implementing
+//! [`BitPacked`] don't each cost a full byte.
+
+use anyhow::Result;
+
+use crate::net::buffer::BufferTooSmall;
+
+/// Values that can be written with a fixed number of bits
+///
+/// Fieldless enums get it with `#[derive(BitPacked)]` from `mmoss-proc-macros`, which packs the
+/// position of the variant.
+pub trait BitPacked: Sized {
+    /// Raw bits of the value, failing if it doesn't fit in `width` bits
+    fn to_bits(&self, width: u32) -> Result<u64>;
+    fn from_bits(bits: u64, width: u32) -> Result<Self>;
+}
+
+fn check_width(width: u32) -> Result<()> {
+    if width == 0 || width > u64::BITS {
+        return Err(anyhow::anyhow!("Invalid bit width {}", width));
+    }
+    Ok(())
+}
+
+fn mask(width: u32) -> u64 {
+    if width >= u64::BITS {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+impl BitPacked for bool {
+    fn to_bits(&self, _width: u32) -> Result<u64> {
+        Ok(*self as u64)
+    }
+
+    fn from_bits(bits: u64, _width: u32) -> Result<Self> {
+        Ok(bits != 0)
+    }
+}
+
+macro_rules! impl_unsigned {
+    ($($type:ty),+) => {
+        $(impl BitPacked for $type {
+            fn to_bits(&self, width: u32) -> Result<u64> {
+                let bits = *self as u64;
+                if bits & !mask(width) != 0 {
+                    return Err(anyhow::anyhow!("{} doesn't fit in {} bits", self, width));
+                }
+                Ok(bits)
+            }
+
+            fn from_bits(bits: u64, _width: u32) -> Result<Self> {
+                Ok(<$type>::try_from(bits)?)
+            }
+        })+
+    };
+}
+
+macro_rules! impl_signed {
+    ($($type:ty),+) => {
+        $(impl BitPacked for $type {
+            fn to_bits(&self, width: u32) -> Result<u64> {
+                let value = *self as i64;
+                let min = if width >= i64::BITS { i64::MIN } else { -(1i64 << (width - 1)) };
+                let max = if width >= i64::BITS { i64::MAX } else { (1i64 << (width - 1)) - 1 };
+                if value < min || value > max {
+                    return Err(anyhow::anyhow!("{} doesn't fit in {} bits", self, width));
+                }
+                Ok(value as u64 & mask(width))
+            }
+
+            fn from_bits(bits: u64, width: u32) -> Result<Self> {
+                // Sign extend from the top bit of the field
+                let shift = i64::BITS - width;
+                let value = ((bits << shift) as i64) >> shift;
+                Ok(<$type>::try_from(value)?)
+            }
+        })+
+    };
+}
+
+impl_unsigned!(u8, u16, u32, u64);
+impl_signed!(i8, i16, i32, i64);
+
+/// Writes values with arbitrary bit widths, least significant bit first
+pub struct BitWriter<'a> {
+    data: &'a mut [u8],
+    bit: usize,
+}
+
+impl<'a> BitWriter<'a> {
+    pub fn new(data: &'a mut [u8]) -> Self {
+        Self { data, bit: 0 }
+    }
+
+    pub fn write_bits(&mut self, value: u64, width: u32) -> Result<()> {
+        check_width(width)?;
+        if self.bit + width as usize > self.data.len() * 8 {
+            return Err(BufferTooSmall.into());
+        }
+
+        for i in 0..width as usize {
+            let (byte, offset) = ((self.bit + i) / 8, (self.bit + i) % 8);
+            if offset == 0 {
+                self.data[byte] = 0;
+            }
+            self.data[byte] |= (((value >> i) & 1) as u8) << offset;
+        }
+        self.bit += width as usize;
+        Ok(())
+    }
+
+    pub fn write<T: BitPacked>(&mut self, value: &T, width: u32) -> Result<()> {
+        check_width(width)?;
+        self.write_bits(value.to_bits(width)?, width)
+    }
+
+    /// Number of bytes written, including the partially filled last byte
+    pub fn finish(self) -> usize {
+        self.bit.div_ceil(8)
+    }
+}
+
+/// Reads values written by a [`BitWriter`]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, bit: 0 }
+    }
+
+    pub fn read_bits(&mut self, width: u32) -> Result<u64> {
+        check_width(width)?;
+        if self.bit + width as usize > self.data.len() * 8 {
+            return Err(anyhow::anyhow!("Not enough data to read {} bits", width));
+        }
+
+        let mut value = 0;
+        for i in 0..width as usize {
+            let (byte, offset) = ((self.bit + i) / 8, (self.bit + i) % 8);
+            value |= (((self.data[byte] >> offset) & 1) as u64) << i;
+        }
+        self.bit += width as usize;
+        Ok(value)
+    }
+
+    pub fn read<T: BitPacked>(&mut self, width: u32) -> Result<T> {
+        T::from_bits(self.read_bits(width)?, width)
+    }
+
+    /// Number of bytes read, including the partially read last byte
+    pub fn finish(self) -> usize {
+        self.bit.div_ceil(8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mmoss_proc_macros::BitPacked;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, BitPacked)]
+    enum Stance {
+        Standing,
+        Crouching,
+        Prone,
+    }
+
+    #[test]
+    fn packs_values_across_bytes() {
+        let mut data = [0xff; 4];
+        let mut writer = BitWriter::new(&mut data);
+        writer.write(&true, 1).unwrap();
+        writer.write(&5u8, 3).unwrap();
+        writer.write(&-3i16, 7).unwrap();
+        writer.write(&Stance::Prone, 2).unwrap();
+        assert_eq!(writer.finish(), 2);
+
+        let mut reader = BitReader::new(&data);
+        assert!(reader.read::<bool>(1).unwrap());
+        assert_eq!(reader.read::<u8>(3).unwrap(), 5);
+        assert_eq!(reader.read::<i16>(7).unwrap(), -3);
+        assert_eq!(reader.read::<Stance>(2).unwrap(), Stance::Prone);
+        assert_eq!(reader.finish(), 2);
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        let mut data = [0; 1];
+        let mut writer = BitWriter::new(&mut data);
+        assert!(writer.write(&8u8, 3).is_err());
+        assert!(writer.write(&-5i8, 3).is_err());
+        assert!(writer.write(&Stance::Prone, 1).is_err());
+
+        assert!(BitReader::new(&[3]).read::<Stance>(2).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+//! Observer events triggered by [`super::Manager::update_world`]
+//!
+//! Each event targets the affected entity, so it can be observed globally with
+//! [`World::add_observer`](bevy::ecs::world::World::add_observer) or on a single entity with
+//! [`EntityWorldMut::observe`](bevy::ecs::world::EntityWorldMut::observe).
+
+use bevy::ecs::{
+    entity::Entity,
+    event::{EntityEvent, Event},
+};
+
+use crate::replication::{ComponentType, Id, MobType, ResourceType, SpawnId};
+
+/// A replicated mob was spawned
+#[derive(Debug, Clone, EntityEvent)]
+pub struct Spawned {
+    pub entity: Entity,
+    pub spawn_id: SpawnId,
+    pub mob_type: MobType,
+}
+
+/// A replicated component was added to a spawned mob
+#[derive(Debug, Clone, EntityEvent)]
+pub struct ComponentAdded {
+    pub entity: Entity,
+    pub spawn_id: SpawnId,
+    pub component_type: ComponentType,
+    pub replicated_id: Id,
+}
+
+/// A replicated component received an update
+#[derive(Debug, Clone, EntityEvent)]
+pub struct ComponentUpdated {
+    pub entity: Entity,
+    pub spawn_id: SpawnId,
+    pub replicated_id: Id,
+}
+
+/// A replicated mob was attached to or detached from a parent
+#[derive(Debug, Clone, EntityEvent)]
+pub struct ParentChanged {
+    pub entity: Entity,
+    pub spawn_id: SpawnId,
+    pub parent: Option<Entity>,
+}
+
+/// More of the full-state snapshot has arrived
+///
+/// Not targeted at an entity. Triggered before the snapshot's contents are applied.
+#[derive(Debug, Clone, Event)]
+pub struct SnapshotProgress {
+    pub received: u32,
+    pub total: u32,
+}
+
+/// A replicated resource was inserted or updated
+///
+/// Not targeted at an entity.
+#[derive(Debug, Clone, Event)]
+pub struct ResourceUpdated {
+    pub resource_type: ResourceType,
+}
+
+/// The connection to the server was closed, for example after a keepalive timeout
+///
+/// Not targeted at an entity. Triggered once, after the last received messages are applied.
+#[derive(Debug, Clone, Event)]
+pub struct Disconnected;
+
+/// A replicated mob is about to be despawned
+///
+/// Triggered before the entity is removed from the world so observers can still inspect it.
+#[derive(Debug, Clone, EntityEvent)]
+pub struct Despawned {
+    pub entity: Entity,
+    pub spawn_id: SpawnId,
+}
@@ -1,13 +1,18 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, marker::PhantomData};
 
 use anyhow::Result;
 use async_trait::async_trait;
-use bevy::ecs::{entity::Entity, world::EntityWorldMut};
+use bevy::ecs::{
+    component::Component,
+    entity::Entity,
+    world::{EntityWorldMut, World},
+};
+use bevy_trait_query::RegisterExt as _;
 
 use crate::{
-    core::{WorldContainer, component_type::physics::DYNAMIC_ACTOR_PROXY_COMPONENT_TYPE},
-    physics::proxy::DynamicActorComponentProxyFactory,
-    replication::{ComponentType, Id},
+    core::WorldContainer,
+    physics::proxy::DynamicActorComponentProxy,
+    replication::{ComponentType, Id, Replicated},
 };
 
 #[async_trait(?Send)]
@@ -20,6 +25,37 @@ pub trait Entry<W: WorldContainer> {
     ) -> Result<()>;
 }
 
+/// Entry constructing `T` with [`Default`] before replicating its initial state
+pub struct ReplicatedEntry<T>(PhantomData<fn() -> T>);
+
+impl<T> Default for ReplicatedEntry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> ReplicatedEntry<T> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: WorldContainer, T: Component + Replicated + Default> Entry<W> for ReplicatedEntry<T> {
+    async fn add_component(
+        &self,
+        mut entity: EntityWorldMut<'_>,
+        replication_id: Id,
+        data: &Vec<u8>,
+    ) -> Result<()> {
+        let mut component = T::default();
+        component.set_id(replication_id);
+        component.replicate(data)?;
+        entity.insert(component);
+        Ok(())
+    }
+}
+
 pub struct Factory<W: WorldContainer> {
     prototypes: HashMap<ComponentType, Box<dyn Entry<W>>>,
 }
@@ -40,6 +76,11 @@ impl<W: WorldContainer> Factory<W> {
             .insert(component_type, Box::new(constructor));
     }
 
+    /// Register `T` under its own component type using a [`ReplicatedEntry`]
+    pub fn register_replicated<T: Component + Replicated + Default>(&mut self) {
+        self.register_component(T::default().component_type(), ReplicatedEntry::<T>::new());
+    }
+
     pub async fn add_component(
         &self,
         world: &mut W,
@@ -55,16 +96,25 @@ impl<W: WorldContainer> Factory<W> {
             )
         })?;
 
-        let entity = world.world_mut().entity_mut(entity);
+        let entity = world
+            .world_mut()
+            .get_entity_mut(entity)
+            .map_err(|_| anyhow::anyhow!("Entity {:?} doesn't exist", entity))?;
         constructor
             .add_component(entity, replication_id, data)
             .await
     }
 }
 
+/// Register `T` with `factory` and as a [`Replicated`] implementation in `world`
+pub fn register_replicated<T: Component + Replicated + Default, W: WorldContainer>(
+    world: &mut World,
+    factory: &mut Factory<W>,
+) {
+    world.register_component_as::<dyn Replicated, T>();
+    factory.register_replicated::<T>();
+}
+
 pub fn register_default_factory_components<W: WorldContainer>(factory: &mut Factory<W>) {
-    factory.register_component(
-        DYNAMIC_ACTOR_PROXY_COMPONENT_TYPE,
-        DynamicActorComponentProxyFactory,
-    );
+    factory.register_replicated::<DynamicActorComponentProxy>();
 }
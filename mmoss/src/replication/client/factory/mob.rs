@@ -8,7 +8,8 @@ use crate::{core::WorldContainer, replication::MobType};
 
 #[async_trait(?Send)]
 pub trait Entry<W: WorldContainer> {
-    async fn construct(&self, world: &mut W) -> Result<Entity>;
+    /// Construct a mob, `payload` is the instance data the server attached to the spawn
+    async fn construct(&self, world: &mut W, payload: Option<&[u8]>) -> Result<Entity>;
 }
 
 pub struct Factory<W: WorldContainer> {
@@ -26,10 +27,15 @@ impl<W: WorldContainer> Factory<W> {
         self.prototypes.insert(mob_type, Box::new(constructor));
     }
 
-    pub async fn construct(&self, world: &mut W, mob_type: MobType) -> Result<Entity> {
+    pub async fn construct(
+        &self,
+        world: &mut W,
+        mob_type: MobType,
+        payload: Option<&[u8]>,
+    ) -> Result<Entity> {
         let constructor = self.prototypes.get(&mob_type).ok_or_else(|| {
             anyhow::anyhow!("No prototype registered for mob type {:?}", mob_type)
         })?;
-        constructor.construct(world).await
+        constructor.construct(world, payload).await
     }
 }
@@ -1,26 +1,37 @@
 use std::{
     collections::{HashMap, VecDeque},
+    mem,
     sync::Arc,
 };
 
 use anyhow::Result;
-use bevy::ecs::{
-    entity::{Entity, EntityHashMap},
-    world::EntityRef,
+use async_trait::async_trait;
+use bevy::{
+    ecs::{
+        component::{Component, Mutable},
+        entity::{Entity, EntityHashMap},
+        hierarchy::ChildOf,
+        resource::Resource,
+        world::EntityRef,
+    },
+    reflect::{GetTypeRegistration, Reflect, Typed},
 };
 use bevy_trait_query::All;
+use bincode::{Decode, Encode};
 use log::{debug, error, trace};
 use tokio::{sync::Mutex, task::yield_now};
 
 use crate::{
     core::WorldContainer,
-    net::transport::Unreliable,
+    net::transport::{ReceiveHalf, SendHalf, Unreliable},
+    physics::RelativeTransform,
     replication::{
-        AddedComponentData, ComponentType, Id, Message, MobType, Replicated, SpawnData, SpawnId,
-        UpdateData,
+        AddedComponentData, ComponentType, DespawnData, Id, Message, MobType, ParentData,
+        Replicated, ResourceType, SpawnData, SpawnId, UpdateData, reflect, resource, snapshot,
     },
 };
 
+pub mod event;
 pub mod factory;
 
 use factory::component::Factory as ComponentFactory;
@@ -30,6 +41,18 @@ struct Pending {
     updates: HashMap<Id, UpdateData>,
     spawns: VecDeque<SpawnData>,
     added_component: HashMap<SpawnId, VecDeque<AddedComponentData>>,
+    /// Latest parent of each entity, kept until both the entity and its parent exist or either
+    /// fails to spawn or is despawned
+    parents: HashMap<SpawnId, ParentData>,
+    despawns: VecDeque<DespawnData>,
+    /// Latest state of each replicated resource
+    resources: HashMap<ResourceType, Vec<u8>>,
+    /// Snapshot currently being received
+    snapshot: Option<snapshot::Assembler>,
+    /// Snapshot progress not yet reported by [`Manager::update_world`]
+    snapshot_progress: Option<snapshot::Progress>,
+    /// The server connection closed and [`Manager::update_world`] hasn't reported it yet
+    disconnected: bool,
 }
 
 impl Pending {
@@ -38,43 +61,120 @@ impl Pending {
             updates: HashMap::new(),
             spawns: VecDeque::new(),
             added_component: HashMap::new(),
+            parents: HashMap::new(),
+            despawns: VecDeque::new(),
+            resources: HashMap::new(),
+            snapshot: None,
+            snapshot_progress: None,
+            disconnected: false,
         }
     }
-}
 
-pub struct Incoming {
-    transport: Box<dyn Unreliable<Message>>,
-    pending: Arc<Mutex<Pending>>,
-}
+    /// Drop the hierarchy change of `spawn_id` and those of children waiting for it to spawn
+    fn forget_parents(&mut self, spawn_id: SpawnId) {
+        self.parents.retain(|child, parent_data| {
+            *child != spawn_id && parent_data.parent != Some(spawn_id)
+        });
+    }
 
-impl Incoming {
-    pub async fn process_incoming(&mut self) -> Result<()> {
-        let message = self.transport.receive().await?;
-        let mut pending = self.pending.lock().await;
+    fn push(&mut self, message: Message) -> Result<()> {
         match message {
             Message::Update(update) => {
                 trace!("Received update: {:?}", update);
-                pending.updates.entry(update.id).or_insert(update);
+                self.updates.entry(update.id).or_insert(update);
             }
             Message::Spawn(spawn) => {
                 debug!("Received spawn: {:?}", spawn);
-                pending.spawns.push_back(spawn);
+                self.spawns.push_back(spawn);
             }
             Message::AddComponent(add_component) => {
                 debug!("Received add component: {:?}", add_component);
-                pending
-                    .added_component
+                self.added_component
                     .entry(add_component.spawn_id)
                     .or_insert_with(VecDeque::new)
                     .push_back(add_component);
             }
+            Message::SetParent(parent) => {
+                debug!("Received set parent: {:?}", parent);
+                self.parents.insert(parent.spawn_id, parent);
+            }
+            Message::Despawn(despawn) => {
+                debug!("Received despawn: {:?}", despawn);
+                self.despawns.push_back(despawn);
+            }
+            Message::Resource(resource) => {
+                trace!("Received resource {:?}", resource.resource_type);
+                self.resources.insert(resource.resource_type, resource.data);
+            }
+            Message::SnapshotChunk(chunk) => {
+                trace!(
+                    "Received snapshot chunk {}/{} of snapshot {}",
+                    chunk.index + 1,
+                    chunk.count,
+                    chunk.snapshot_id
+                );
+
+                // A newer snapshot replaces one that was never completed
+                let mut assembler = match self.snapshot.take() {
+                    Some(assembler) if assembler.snapshot_id() == chunk.snapshot_id => assembler,
+                    _ => snapshot::Assembler::new(chunk.snapshot_id, chunk.count)?,
+                };
+                let mut progress = assembler.progress();
+
+                match assembler.add(chunk)? {
+                    Some(messages) => {
+                        progress.received = progress.total;
+                        debug!("Received snapshot of {} messages", messages.len());
+                        // Queue the whole snapshot under the same lock so it is applied at once
+                        for message in messages {
+                            self.push(message)?;
+                        }
+                    }
+                    None => {
+                        progress = assembler.progress();
+                        self.snapshot = Some(assembler);
+                    }
+                }
+                self.snapshot_progress = Some(progress);
+            }
+            Message::Login(_) | Message::LoginResult(_) => {
+                return Err(anyhow::anyhow!("Unexpected login message"));
+            }
         }
+
+        Ok(())
+    }
+}
+
+pub struct Incoming {
+    transport: Box<dyn ReceiveHalf<Message>>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Incoming {
+    pub async fn process_incoming(&mut self) -> Result<()> {
+        let message = match self.transport.receive().await {
+            Ok(message) => message,
+            Err(e) => {
+                if self.transport.is_closed() {
+                    self.pending.lock().await.disconnected = true;
+                }
+                return Err(e);
+            }
+        };
+        self.pending.lock().await.push(message)?;
         yield_now().await;
 
         Ok(())
     }
+
+    /// Whether the transport is known to be closed, no more messages will be received
+    pub fn is_closed(&self) -> bool {
+        self.transport.is_closed()
+    }
 }
 
+/// Callbacks invoked by [`Manager::update_world`] alongside the [`event`] observer events
 pub trait UpdateCallbacks {
     fn on_component_updated(&mut self, entity: Entity, spawn_id: SpawnId, replicated_id: Id);
     fn on_spawn(&mut self, entity: Entity, spawn_id: SpawnId, mob_type: MobType);
@@ -85,6 +185,8 @@ pub trait UpdateCallbacks {
         component_type: ComponentType,
         replicated_id: Id,
     );
+    fn on_parent_changed(&mut self, entity: Entity, spawn_id: SpawnId, parent: Option<Entity>);
+    fn on_despawn(&mut self, entity: Entity, spawn_id: SpawnId);
 }
 
 pub struct NoopUpdateCallbacks;
@@ -100,6 +202,8 @@ impl UpdateCallbacks for NoopUpdateCallbacks {
         _replicated_id: Id,
     ) {
     }
+    fn on_parent_changed(&mut self, _entity: Entity, _spawn_id: SpawnId, _parent: Option<Entity>) {}
+    fn on_despawn(&mut self, _entity: Entity, _spawn_id: SpawnId) {}
 }
 
 pub struct Manager<W: WorldContainer> {
@@ -108,6 +212,37 @@ pub struct Manager<W: WorldContainer> {
     component_factory: Arc<ComponentFactory<W>>,
     spawn_id_lookup: EntityHashMap<SpawnId>,
     entity_lookup: HashMap<SpawnId, Entity>,
+    /// Map from replication ID to the entity holding the component and its type
+    component_lookup: HashMap<Id, (Entity, ComponentType)>,
+    /// Replication IDs of the components added to each entity
+    entity_components: EntityHashMap<Vec<Id>>,
+    /// Components replicated through reflection
+    reflected: reflect::Registry,
+    /// Resources registered for replication
+    resources: resource::Registry,
+    snapshot_progress: Option<snapshot::Progress>,
+    /// Sending half of the transport passed to [`Self::new`], until taken
+    sender: Option<Box<dyn SendHalf<Message>>>,
+    connected: bool,
+}
+
+/// Sending half flushing after every message
+struct FlushingSender(Box<dyn SendHalf<Message>>);
+
+#[async_trait]
+impl SendHalf<Message> for FlushingSender {
+    async fn send(&mut self, message: &Message) -> Result<()> {
+        self.0.send(message).await?;
+        self.0.flush().await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.0.flush().await
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
 }
 
 impl<W: WorldContainer> Manager<W> {
@@ -117,6 +252,7 @@ impl<W: WorldContainer> Manager<W> {
         component_factory: Arc<ComponentFactory<W>>,
     ) -> (Self, Incoming) {
         let pending = Arc::new(Mutex::new(Pending::new()));
+        let (sender, transport) = transport.split();
         (
             Self {
                 pending: pending.clone(),
@@ -124,28 +260,224 @@ impl<W: WorldContainer> Manager<W> {
                 component_factory,
                 spawn_id_lookup: EntityHashMap::new(),
                 entity_lookup: HashMap::new(),
+                component_lookup: HashMap::new(),
+                entity_components: EntityHashMap::new(),
+                reflected: reflect::Registry::new(),
+                resources: resource::Registry::new(),
+                snapshot_progress: None,
+                sender: Some(sender),
+                connected: true,
             },
             Incoming { pending, transport },
         )
     }
 
+    /// Insert the resource `R` into the world whenever the server replicates it
+    pub fn register_resource<R: Resource + Encode + Decode<()>>(
+        &mut self,
+        resource_type: ResourceType,
+    ) {
+        self.resources.insert(resource_type, resource::entry::<R>());
+    }
+
+    /// Construct components of type `T` through reflection instead of the component factory
+    ///
+    /// Must match the server's [`server::Manager::register_reflect_component`] call.
+    ///
+    /// [`server::Manager::register_reflect_component`]: crate::replication::server::Manager::register_reflect_component
+    pub fn register_reflect_component<T>(
+        &mut self,
+        component_type: ComponentType,
+        fields: &[&str],
+    ) -> Result<()>
+    where
+        T: Component<Mutability = Mutable> + Reflect + Typed + GetTypeRegistration + Default,
+    {
+        self.reflected.register::<T>(component_type, fields)
+    }
+
+    /// Receive from an additional transport, such as the receiving half of the one carrying
+    /// unreliable updates
+    ///
+    /// Messages from all transports are applied by the same [`Self::update_world`].
+    pub fn add_transport(&self, transport: Box<dyn ReceiveHalf<Message>>) -> Incoming {
+        Incoming {
+            pending: self.pending.clone(),
+            transport,
+        }
+    }
+
+    /// Take the sending half of the server connection, to send on it from another task
+    ///
+    /// Every message is flushed as it is sent, so nothing waits in a
+    /// [`Batched`](crate::net::transport::batch::Batched) transport.
+    pub fn take_sender(&mut self) -> Option<Box<dyn SendHalf<Message>>> {
+        self.sender
+            .take()
+            .map(|sender| Box::new(FlushingSender(sender)) as Box<dyn SendHalf<Message>>)
+    }
+
+    pub fn entity_by_spawn_id(&self, spawn_id: SpawnId) -> Option<Entity> {
+        self.entity_lookup.get(&spawn_id).copied()
+    }
+
+    pub fn spawn_id_by_entity(&self, entity: Entity) -> Option<SpawnId> {
+        self.spawn_id_lookup.get(&entity).copied()
+    }
+
+    /// Entity holding the replicated component with the given ID
+    pub fn entity_by_replicated_id(&self, replicated_id: Id) -> Option<Entity> {
+        self.component_lookup
+            .get(&replicated_id)
+            .map(|(entity, _)| *entity)
+    }
+
+    /// Entity and type of the replicated component with the given ID
+    pub fn component_by_replicated_id(&self, replicated_id: Id) -> Option<(Entity, ComponentType)> {
+        self.component_lookup.get(&replicated_id).copied()
+    }
+
+    /// `false` once a closed server connection has been reported by [`Self::update_world`]
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Progress of the most recent full-state snapshot, `None` until its first chunk arrives
+    pub fn snapshot_progress(&self) -> Option<snapshot::Progress> {
+        self.snapshot_progress
+    }
+
+    async fn add_component(
+        &mut self,
+        world: &mut W,
+        entity: Entity,
+        component_type: ComponentType,
+        replicated_id: Id,
+        data: &Vec<u8>,
+    ) -> Result<()> {
+        if self.reflected.contains(component_type) {
+            let mut entity_mut = world
+                .world_mut()
+                .get_entity_mut(entity)
+                .map_err(|_| anyhow::anyhow!("Entity {:?} doesn't exist", entity))?;
+            self.reflected
+                .insert(component_type, &mut entity_mut, data)?;
+        } else {
+            self.component_factory
+                .add_component(world, entity, component_type, replicated_id, data)
+                .await?;
+        }
+
+        self.component_lookup
+            .insert(replicated_id, (entity, component_type));
+        self.entity_components
+            .entry(entity)
+            .or_default()
+            .push(replicated_id);
+        Ok(())
+    }
+
+    /// Forget the replication IDs of every component added to `entity`
+    fn remove_components(&mut self, entity: Entity) {
+        for id in self.entity_components.remove(&entity).unwrap_or_default() {
+            self.component_lookup.remove(&id);
+        }
+    }
+
     pub async fn update_world(&mut self, world: &mut W, callbacks: &mut impl UpdateCallbacks) {
-        let mut pending = self.pending.lock().await;
+        let pending = self.pending.clone();
+        let mut pending = pending.lock().await;
+        if let Some(progress) = pending.snapshot_progress.take() {
+            self.snapshot_progress = Some(progress);
+            world.world_mut().trigger(event::SnapshotProgress {
+                received: progress.received,
+                total: progress.total,
+            });
+        }
+
         // Process spawns
         if pending.spawns.len() > 0 {
             trace!("Processing {} spawns", pending.spawns.len());
         }
+        let mut failed_spawns = Vec::new();
         for spawn in pending.spawns.drain(..) {
-            match self.mob_factory.construct(world, spawn.mob_type).await {
-                Ok(entity) => {
-                    self.spawn_id_lookup.insert(entity, spawn.spawn_id);
-                    self.entity_lookup.insert(spawn.spawn_id, entity);
-                    callbacks.on_spawn(entity, spawn.spawn_id, spawn.mob_type);
+            let entity = match self
+                .mob_factory
+                .construct(world, spawn.mob_type, spawn.payload.as_deref())
+                .await
+            {
+                Ok(entity) => entity,
+                Err(e) => {
+                    error!("Failed to spawn mob of type {:?}: {}", spawn.mob_type, e);
+                    failed_spawns.push(spawn.spawn_id);
+                    continue;
                 }
-                Err(e) => error!("Failed to spawn mob of type {:?}: {}", spawn.mob_type, e),
+            };
+
+            // Build the whole mob before notifying anyone about it, a mob missing components is
+            // despawned rather than left half built
+            let mut added = Vec::new();
+            let mut failed = false;
+            for component in spawn.components {
+                if let Err(e) = self
+                    .add_component(
+                        world,
+                        entity,
+                        component.component_type,
+                        component.replicated_id,
+                        &component.data,
+                    )
+                    .await
+                {
+                    error!(
+                        "Failed to add component {:?} to entity {:?}: {}",
+                        component.replicated_id,
+                        entity.index(),
+                        e
+                    );
+                    failed = true;
+                    break;
+                }
+                added.push(component);
+            }
+            if failed {
+                self.remove_components(entity);
+                world.world_mut().despawn(entity);
+                failed_spawns.push(spawn.spawn_id);
+                continue;
+            }
+
+            self.spawn_id_lookup.insert(entity, spawn.spawn_id);
+            self.entity_lookup.insert(spawn.spawn_id, entity);
+
+            callbacks.on_spawn(entity, spawn.spawn_id, spawn.mob_type);
+            world.world_mut().trigger(event::Spawned {
+                entity,
+                spawn_id: spawn.spawn_id,
+                mob_type: spawn.mob_type,
+            });
+
+            for component in added {
+                callbacks.on_component_added(
+                    entity,
+                    spawn.spawn_id,
+                    component.component_type,
+                    component.replicated_id,
+                );
+                world.world_mut().trigger(event::ComponentAdded {
+                    entity,
+                    spawn_id: spawn.spawn_id,
+                    component_type: component.component_type,
+                    replicated_id: component.replicated_id,
+                });
             }
         }
 
+        // Hierarchy changes waiting for these would never be applied
+        for spawn_id in failed_spawns {
+            pending.forget_parents(spawn_id);
+        }
+
         // Process add component
         for (spawn_id, added_components) in pending.added_component.iter_mut() {
             let entity = self.entity_lookup.get(spawn_id);
@@ -158,7 +490,6 @@ impl<W: WorldContainer> Manager<W> {
             let entity = *entity.unwrap();
             for added_component in added_components.drain(..) {
                 if let Err(e) = self
-                    .component_factory
                     .add_component(
                         world,
                         entity,
@@ -181,11 +512,79 @@ impl<W: WorldContainer> Manager<W> {
                         added_component.component_type,
                         added_component.replicated_id,
                     );
+                    world.world_mut().trigger(event::ComponentAdded {
+                        entity,
+                        spawn_id: *spawn_id,
+                        component_type: added_component.component_type,
+                        replicated_id: added_component.replicated_id,
+                    });
                 }
             }
         }
 
-        // Process updates
+        // Process hierarchy changes, keeping any that reference entities not spawned yet
+        pending.parents.retain(|spawn_id, parent_data| {
+            let Some(entity) = self.entity_lookup.get(spawn_id).copied() else {
+                return true;
+            };
+
+            let parent = match parent_data.parent {
+                Some(parent_spawn_id) => match self.entity_lookup.get(&parent_spawn_id) {
+                    Some(parent) => Some(*parent),
+                    None => return true,
+                },
+                None => None,
+            };
+
+            let Ok(mut entity_mut) = world.world_mut().get_entity_mut(entity) else {
+                error!(
+                    "Entity {:?} for spawn ID {:?} no longer exists",
+                    entity, spawn_id
+                );
+                return false;
+            };
+
+            match parent {
+                Some(parent) => {
+                    entity_mut.insert(ChildOf(parent));
+                }
+                None => {
+                    entity_mut.remove::<ChildOf>();
+                }
+            }
+
+            if parent_data.relative_transform {
+                entity_mut.insert(RelativeTransform);
+            } else {
+                entity_mut.remove::<RelativeTransform>();
+            }
+
+            callbacks.on_parent_changed(entity, *spawn_id, parent);
+            world.world_mut().trigger(event::ParentChanged {
+                entity,
+                spawn_id: *spawn_id,
+                parent,
+            });
+            false
+        });
+
+        for (resource_type, data) in pending.resources.drain() {
+            let Some(entry) = self.resources.get(&resource_type) else {
+                error!("No resource registered for type {:?}", resource_type);
+                continue;
+            };
+
+            if let Err(e) = entry.insert(world.world_mut(), &data) {
+                error!("Failed to replicate resource {:?}: {}", resource_type, e);
+                continue;
+            }
+            world
+                .world_mut()
+                .trigger(event::ResourceUpdated { resource_type });
+        }
+
+        // Process updates, triggering events once the query is done with the world
+        let mut updated = Vec::new();
         for (entity, components) in world
             .world_mut()
             .query::<(EntityRef, All<&mut dyn Replicated>)>()
@@ -201,11 +600,95 @@ impl<W: WorldContainer> Manager<W> {
 
                     if let Some(spawn_id) = self.spawn_id_lookup.get(&entity.id()) {
                         callbacks.on_component_updated(entity.id(), *spawn_id, id);
+                        updated.push(event::ComponentUpdated {
+                            entity: entity.id(),
+                            spawn_id: *spawn_id,
+                            replicated_id: id,
+                        });
                     } else {
                         error!("No spawn ID found for entity {:?}", entity.id());
                     }
                 }
             }
         }
+
+        let reflected = pending
+            .updates
+            .keys()
+            .filter(|id| {
+                self.component_lookup
+                    .get(*id)
+                    .is_some_and(|(_, component_type)| self.reflected.contains(*component_type))
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        for id in reflected {
+            let Some(update) = pending.updates.remove(&id) else {
+                continue;
+            };
+            let Some((entity, component_type)) = self.component_lookup.get(&id).copied() else {
+                continue;
+            };
+            let Ok(mut entity_mut) = world.world_mut().get_entity_mut(entity) else {
+                error!(
+                    "Entity {:?} for replication ID {:?} no longer exists",
+                    entity, id
+                );
+                continue;
+            };
+
+            if let Err(e) = self
+                .reflected
+                .apply(component_type, &mut entity_mut, &update.data)
+            {
+                error!("Failed to replicate update for {:?}: {}", id, e);
+                continue;
+            }
+
+            if let Some(spawn_id) = self.spawn_id_lookup.get(&entity) {
+                callbacks.on_component_updated(entity, *spawn_id, id);
+                updated.push(event::ComponentUpdated {
+                    entity,
+                    spawn_id: *spawn_id,
+                    replicated_id: id,
+                });
+            } else {
+                error!("No spawn ID found for entity {:?}", entity);
+            }
+        }
+
+        for event in updated {
+            world.world_mut().trigger(event);
+        }
+
+        // Process despawns last so anything received before them is applied first
+        let despawns = pending.despawns.drain(..).collect::<Vec<_>>();
+        for despawn in despawns {
+            let Some(entity) = self.entity_lookup.remove(&despawn.spawn_id) else {
+                error!("No entity found for spawn ID {:?}", despawn.spawn_id);
+                continue;
+            };
+            self.spawn_id_lookup.remove(&entity);
+            self.remove_components(entity);
+            pending.added_component.remove(&despawn.spawn_id);
+            pending.forget_parents(despawn.spawn_id);
+
+            // Children may already have been despawned along with their parent
+            if world.world().get_entity(entity).is_ok() {
+                world.world_mut().trigger(event::Despawned {
+                    entity,
+                    spawn_id: despawn.spawn_id,
+                });
+                world.world_mut().despawn(entity);
+            } else {
+                debug!("Entity {:?} was already despawned", entity);
+            }
+            callbacks.on_despawn(entity, despawn.spawn_id);
+        }
+
+        if mem::take(&mut pending.disconnected) && self.connected {
+            self.connected = false;
+            world.world_mut().trigger(event::Disconnected);
+        }
     }
 }
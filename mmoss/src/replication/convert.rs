@@ -1,7 +1,7 @@
 use bincode::{
     Decode, Encode,
-    de::Decoder,
-    enc::Encoder,
+    de::{Decoder, read::Reader},
+    enc::{Encoder, write::Writer},
     error::{DecodeError, EncodeError},
 };
 
@@ -142,3 +142,258 @@ impl From<Quat> for bevy::math::Quat {
         q.0
     }
 }
+
+fn write_bytes<E: Encoder>(encoder: &mut E, bytes: &[u8]) -> Result<(), EncodeError> {
+    encoder.writer().write(bytes)
+}
+
+fn read_bytes<const N: usize, D: Decoder>(decoder: &mut D) -> Result<[u8; N], DecodeError> {
+    let mut bytes = [0u8; N];
+    decoder.reader().read(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Values that can be sent as half precision floats with [`Half`]
+pub trait HalfFloat: Sized {
+    fn encode_half<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError>;
+    fn decode_half<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError>;
+}
+
+impl HalfFloat for f32 {
+    fn encode_half<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        write_bytes(encoder, &half::f16::from_f32(*self).to_bits().to_le_bytes())
+    }
+
+    fn decode_half<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(half::f16::from_bits(u16::from_le_bytes(read_bytes(decoder)?)).to_f32())
+    }
+}
+
+macro_rules! impl_half_float {
+    ($type:ty, $($component:ident),+) => {
+        impl HalfFloat for $type {
+            fn encode_half<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+                $(self.$component.encode_half(encoder)?;)+
+                Ok(())
+            }
+
+            fn decode_half<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+                $(let $component = f32::decode_half(decoder)?;)+
+                Ok(Self::new($($component),+))
+            }
+        }
+    };
+}
+
+impl_half_float!(bevy::math::Vec2, x, y);
+impl_half_float!(bevy::math::Vec3, x, y, z);
+impl_half_float!(bevy::math::Vec4, x, y, z, w);
+
+/// Wrapper struct for serializing/deserializing floats and vectors as half precision floats
+///
+/// Two bytes per component, with about three significant decimal digits.
+pub struct Half<T>(pub T);
+
+impl<T: HalfFloat> Encode for Half<T> {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.0.encode_half(encoder)
+    }
+}
+
+impl<T: HalfFloat, Context> Decode<Context> for Half<T> {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(Half(T::decode_half(decoder)?))
+    }
+}
+
+const SMALLEST_THREE_BITS: u32 = 10;
+const SMALLEST_THREE_MAX: u32 = (1 << SMALLEST_THREE_BITS) - 1;
+
+/// Wrapper struct for serializing/deserializing [`bevy::math::Quat`] with smallest-three
+/// encoding
+///
+/// The largest component is dropped and rebuilt from the other three, which are stored with
+/// 10 bits each, for 4 bytes in total.
+pub struct SmallestThree(pub bevy::math::Quat);
+
+impl Encode for SmallestThree {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        let components = self.0.normalize().to_array();
+        let largest = (0..4)
+            .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+            .unwrap_or(3);
+        // q and -q are the same rotation, so the dropped component can always be positive
+        let sign = if components[largest] < 0.0 { -1.0 } else { 1.0 };
+
+        let mut packed = largest as u32;
+        for (index, component) in components.iter().enumerate() {
+            if index == largest {
+                continue;
+            }
+
+            let normalized = (component * sign / std::f32::consts::FRAC_1_SQRT_2).clamp(-1.0, 1.0);
+            let quantized = ((normalized * 0.5 + 0.5) * SMALLEST_THREE_MAX as f32).round() as u32;
+            packed = (packed << SMALLEST_THREE_BITS) | quantized;
+        }
+
+        write_bytes(encoder, &packed.to_le_bytes())
+    }
+}
+
+impl<Context> Decode<Context> for SmallestThree {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let mut packed = u32::from_le_bytes(read_bytes(decoder)?);
+
+        let mut smallest = [0.0f32; 3];
+        for component in smallest.iter_mut().rev() {
+            let quantized = packed & SMALLEST_THREE_MAX;
+            packed >>= SMALLEST_THREE_BITS;
+            *component = ((quantized as f32 / SMALLEST_THREE_MAX as f32) * 2.0 - 1.0)
+                * std::f32::consts::FRAC_1_SQRT_2;
+        }
+
+        let largest = packed as usize & 3;
+        let remaining = 1.0 - smallest.iter().map(|c| c * c).sum::<f32>();
+        let mut components = [0.0f32; 4];
+        let mut smallest = smallest.into_iter();
+        for (index, component) in components.iter_mut().enumerate() {
+            *component = if index == largest {
+                remaining.max(0.0).sqrt()
+            } else {
+                smallest.next().unwrap_or_default()
+            };
+        }
+
+        Ok(SmallestThree(
+            bevy::math::Quat::from_array(components).normalize(),
+        ))
+    }
+}
+
+/// Range and precision of fixed-point quantized values
+///
+/// Values are clamped to `[min, max]` and sent as the number of `precision` sized steps above
+/// `min`, using as few bytes as the range allows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixedPoint {
+    pub min: f32,
+    pub max: f32,
+    pub precision: f32,
+}
+
+impl FixedPoint {
+    pub fn new(min: f32, max: f32, precision: f32) -> Self {
+        Self {
+            min,
+            max,
+            precision,
+        }
+    }
+
+    fn steps(&self) -> u32 {
+        ((self.max - self.min) / self.precision)
+            .ceil()
+            .clamp(0.0, u32::MAX as f32) as u32
+    }
+
+    /// Number of bytes used for each value
+    pub fn bytes(&self) -> usize {
+        let bits = u32::BITS - self.steps().leading_zeros();
+        (bits as usize).div_ceil(8).max(1)
+    }
+
+    pub fn quantize(&self, value: f32) -> u32 {
+        (((value.clamp(self.min, self.max) - self.min) / self.precision).round() as u32)
+            .min(self.steps())
+    }
+
+    pub fn dequantize(&self, quantized: u32) -> f32 {
+        (self.min + quantized as f32 * self.precision).min(self.max)
+    }
+
+    pub fn encode_value<E: Encoder>(&self, value: f32, encoder: &mut E) -> Result<(), EncodeError> {
+        write_bytes(encoder, &self.quantize(value).to_le_bytes()[..self.bytes()])
+    }
+
+    pub fn decode_value<D: Decoder>(&self, decoder: &mut D) -> Result<f32, DecodeError> {
+        let mut bytes = [0u8; 4];
+        decoder.reader().read(&mut bytes[..self.bytes()])?;
+        Ok(self.dequantize(u32::from_le_bytes(bytes)))
+    }
+}
+
+/// Values that can be fixed-point quantized with [`Quantized`]
+pub trait Quantize: Sized {
+    fn encode_quantized<E: Encoder>(
+        &self,
+        fixed: &FixedPoint,
+        encoder: &mut E,
+    ) -> Result<(), EncodeError>;
+    fn decode_quantized<D: Decoder>(
+        fixed: &FixedPoint,
+        decoder: &mut D,
+    ) -> Result<Self, DecodeError>;
+}
+
+impl Quantize for f32 {
+    fn encode_quantized<E: Encoder>(
+        &self,
+        fixed: &FixedPoint,
+        encoder: &mut E,
+    ) -> Result<(), EncodeError> {
+        fixed.encode_value(*self, encoder)
+    }
+
+    fn decode_quantized<D: Decoder>(
+        fixed: &FixedPoint,
+        decoder: &mut D,
+    ) -> Result<Self, DecodeError> {
+        fixed.decode_value(decoder)
+    }
+}
+
+macro_rules! impl_quantize {
+    ($type:ty, $($component:ident),+) => {
+        impl Quantize for $type {
+            fn encode_quantized<E: Encoder>(
+                &self,
+                fixed: &FixedPoint,
+                encoder: &mut E,
+            ) -> Result<(), EncodeError> {
+                $(fixed.encode_value(self.$component, encoder)?;)+
+                Ok(())
+            }
+
+            fn decode_quantized<D: Decoder>(
+                fixed: &FixedPoint,
+                decoder: &mut D,
+            ) -> Result<Self, DecodeError> {
+                $(let $component = fixed.decode_value(decoder)?;)+
+                Ok(Self::new($($component),+))
+            }
+        }
+    };
+}
+
+impl_quantize!(bevy::math::Vec2, x, y);
+impl_quantize!(bevy::math::Vec3, x, y, z);
+impl_quantize!(bevy::math::Vec4, x, y, z, w);
+
+/// Wrapper struct for serializing/deserializing fixed-point quantized values
+///
+/// Decoding needs the same [`FixedPoint`] as context, see
+/// [`bincode::decode_from_slice_with_context`].
+pub struct Quantized<T>(pub T, pub FixedPoint);
+
+impl<T: Quantize> Encode for Quantized<T> {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.0.encode_quantized(&self.1, encoder)
+    }
+}
+
+impl<T: Quantize> Decode<FixedPoint> for Quantized<T> {
+    fn decode<D: Decoder<Context = FixedPoint>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let fixed = *decoder.context();
+        Ok(Quantized(T::decode_quantized(&fixed, decoder)?, fixed))
+    }
+}
@@ -0,0 +1,172 @@
+//! Round trips through `#[derive(Replicated)]` components
+
+use bevy::math::{Quat, Vec3};
+use mmoss_proc_macros::BitPacked;
+
+use crate::replication::{ComponentType, Id, Replicated};
+
+const TEST_COMPONENT_TYPE: ComponentType = ComponentType(1000);
+
+#[derive(Debug, PartialEq, Replicated)]
+#[component_type(TEST_COMPONENT_TYPE)]
+enum Shape {
+    Circle {
+        #[replication_id]
+        id: Id,
+        #[replicated]
+        radius: f32,
+        /// Local state, reset when switching variants
+        hits: u32,
+    },
+    Square(#[replication_id] Id, #[replicated] f32),
+}
+
+#[derive(Debug, PartialEq, Replicated)]
+#[component_type(TEST_COMPONENT_TYPE)]
+struct Health(#[replication_id] Id, #[replicated] u32, u8);
+
+#[derive(Debug, PartialEq, Replicated)]
+#[component_type(TEST_COMPONENT_TYPE)]
+#[mmoss(crate = crate)]
+struct Overridden {
+    #[replication_id]
+    id: Id,
+    #[replicated]
+    name: String,
+}
+
+#[derive(Debug, Default, PartialEq, BitPacked)]
+enum Stance {
+    #[default]
+    Standing,
+    Crouching,
+    Prone,
+}
+
+#[derive(Debug, Default, Replicated)]
+#[component_type(TEST_COMPONENT_TYPE)]
+struct Compact {
+    #[replication_id]
+    id: Id,
+    #[replicated(quantize(min = -10.0, max = 10.0, precision = 0.01))]
+    position: Vec3,
+    #[replicated(half)]
+    speed: f32,
+    #[replicated(smallest_three)]
+    rotation: Quat,
+    #[replicated(bits = 1)]
+    grounded: bool,
+    #[replicated(bits = 3)]
+    ammo: u8,
+    #[replicated(bits = 2)]
+    stance: Stance,
+}
+
+fn round_trip(source: &impl Replicated, target: &mut impl Replicated) {
+    let mut data = [0; 64];
+    let len = source.serialize(&mut data).unwrap();
+    assert_eq!(target.replicate(&data[..len]).unwrap(), len);
+}
+
+#[test]
+fn enum_switches_variants() {
+    let mut target = Shape::Circle {
+        id: Id(5),
+        radius: 1.0,
+        hits: 3,
+    };
+
+    round_trip(&Shape::Square(Id(7), 2.0), &mut target);
+    assert_eq!(target, Shape::Square(Id(5), 2.0));
+    assert_eq!(target.id(), Id(5));
+
+    round_trip(
+        &Shape::Circle {
+            id: Id(7),
+            radius: 4.0,
+            hits: 9,
+        },
+        &mut target,
+    );
+    assert_eq!(
+        target,
+        Shape::Circle {
+            id: Id(5),
+            radius: 4.0,
+            hits: 0,
+        }
+    );
+
+    target.set_id(Id(6));
+    assert_eq!(target.id(), Id(6));
+}
+
+#[test]
+fn enum_rejects_unknown_variant() {
+    let mut target = Shape::Square(Id(5), 1.0);
+    assert!(target.replicate(&[2]).is_err());
+}
+
+#[test]
+fn tuple_struct_round_trips() {
+    let mut target = Health(Id(1), 0, 4);
+    round_trip(&Health(Id(2), 80, 9), &mut target);
+    assert_eq!(target, Health(Id(1), 80, 4));
+
+    target.set_id(Id(3));
+    assert_eq!(target.id(), Id(3));
+}
+
+#[test]
+fn crate_path_can_be_overridden() {
+    let mut target = Overridden {
+        id: Id(1),
+        name: String::new(),
+    };
+    round_trip(
+        &Overridden {
+            id: Id(2),
+            name: "Crate".to_string(),
+        },
+        &mut target,
+    );
+    assert_eq!(target.name, "Crate");
+    assert_eq!(target.component_type(), TEST_COMPONENT_TYPE);
+}
+
+#[test]
+fn compact_encodings_round_trip_within_precision() {
+    let source = Compact {
+        id: Id(1),
+        position: Vec3::new(1.234, -5.678, 9.99),
+        speed: 3.217,
+        rotation: Quat::from_euler(bevy::math::EulerRot::XYZ, 0.3, -1.2, 2.0),
+        grounded: true,
+        ammo: 5,
+        stance: Stance::Prone,
+    };
+
+    let mut data = [0; 64];
+    let len = source.serialize(&mut data).unwrap();
+    // One byte of bits, 2 bytes per quantized component, a half and a smallest-three quaternion
+    assert_eq!(len, 1 + 3 * 2 + 2 + 4);
+    assert_eq!(data[0], 1 | (5 << 1) | (2 << 4));
+
+    let mut target = Compact::default();
+    assert_eq!(target.replicate(&data[..len]).unwrap(), len);
+    assert!((target.position - source.position).abs().max_element() <= 0.005 + f32::EPSILON);
+    assert!((target.speed - source.speed).abs() < 0.002);
+    assert!(target.rotation.angle_between(source.rotation) < 0.01);
+    assert!(target.grounded);
+    assert_eq!(target.ammo, 5);
+    assert_eq!(target.stance, Stance::Prone);
+}
+
+#[test]
+fn bit_packed_values_must_fit_their_width() {
+    let source = Compact {
+        ammo: 8,
+        ..Default::default()
+    };
+    assert!(source.serialize(&mut [0; 64]).is_err());
+}
@@ -2,18 +2,42 @@ use anyhow::Result;
 use bevy::ecs::component::Component;
 use bevy_trait_query::queryable;
 use bincode::{Decode, Encode};
-use mmoss_proc_macros::Replicated;
+use mmoss_proc_macros::{BitPacked, Replicated};
 
-use crate::net::transport::{Message as MessageTrait, MessageFactory as MessageFactoryTrait};
+use crate::{
+    net::{
+        buffer,
+        transport::{Message as MessageTrait, MessageFactory as MessageFactoryTrait},
+    },
+    replication::bits::{BitReader, BitWriter},
+};
 
+pub mod allocator;
+pub mod auth;
+pub mod bits;
 pub mod client;
 pub mod convert;
+#[cfg(test)]
+mod derive_tests;
+pub mod plugin;
+pub mod policy;
+pub mod reflect;
+pub mod resource;
 pub mod server;
+pub mod snapshot;
+#[cfg(test)]
+mod tests;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Decode, Encode)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Decode, Encode)]
 #[repr(transparent)]
 pub struct Id(pub u32);
 
+impl Id {
+    /// Placeholder for components whose ID will be allocated by [`server::Manager`], also the
+    /// [`Default`]
+    pub const UNASSIGNED: Id = Id(0);
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Decode, Encode, Component)]
 #[repr(transparent)]
 pub struct MobType(pub u32);
@@ -22,14 +46,28 @@ pub struct MobType(pub u32);
 #[repr(transparent)]
 pub struct ComponentType(pub u32);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Decode, Encode)]
+#[repr(transparent)]
+pub struct ResourceType(pub u32);
+
 #[queryable]
 pub trait Replicated {
     fn id(&self) -> Id;
+    fn set_id(&mut self, id: Id);
     fn replicated_component_type(&self) -> ComponentType;
     fn component_type(&self) -> ComponentType;
 
     fn serialize(&self, data: &mut [u8]) -> Result<usize>;
     fn replicate(&mut self, data: &[u8]) -> Result<usize>;
+
+    /// Expected serialized size, the server grows its buffer if the component turns out larger
+    fn size_hint(&self) -> usize {
+        buffer::DEFAULT_SIZE_HINT
+    }
+
+    fn update_policy(&self) -> policy::UpdatePolicy {
+        policy::UpdatePolicy::default()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Decode, Encode)]
@@ -42,10 +80,21 @@ pub struct UpdateData {
     pub data: Vec<u8>,
 }
 
+#[derive(Debug, Clone, Decode, Encode)]
+pub struct ComponentData {
+    pub component_type: ComponentType,
+    pub replicated_id: Id,
+    pub data: Vec<u8>,
+}
+
+/// A mob together with all of its initial components, applied atomically by the client
 #[derive(Debug, Clone, Decode, Encode)]
 pub struct SpawnData {
     pub mob_type: MobType,
     pub spawn_id: SpawnId,
+    /// Instance data produced by the server's [`server::SpawnPayload`] for this mob type
+    pub payload: Option<Vec<u8>>,
+    pub components: Vec<ComponentData>,
 }
 
 #[derive(Debug, Clone, Decode, Encode)]
@@ -56,20 +105,187 @@ pub struct AddedComponentData {
     pub data: Vec<u8>,
 }
 
+#[derive(Debug, Clone, Decode, Encode)]
+pub struct DespawnData {
+    pub spawn_id: SpawnId,
+}
+
+#[derive(Debug, Clone, Decode, Encode)]
+pub struct ParentData {
+    pub spawn_id: SpawnId,
+    /// Spawn ID of the new parent, `None` if the entity was detached
+    pub parent: Option<SpawnId>,
+    /// Whether the entity's transform is expressed relative to its parent
+    pub relative_transform: bool,
+}
+
+/// Encoded state of a replicated resource, see [`resource`]
+#[derive(Debug, Clone, Decode, Encode)]
+pub struct ResourceData {
+    pub resource_type: ResourceType,
+    pub data: Vec<u8>,
+}
+
+/// Piece of a compressed world snapshot, see [`snapshot`]
+#[derive(Debug, Clone, Decode, Encode)]
+pub struct SnapshotChunkData {
+    pub snapshot_id: u32,
+    pub index: u32,
+    pub count: u32,
+    pub data: Vec<u8>,
+}
+
+/// Token a client logs in with, see [`auth`]
+#[derive(Clone, Decode, Encode)]
+pub struct LoginData {
+    pub token: Vec<u8>,
+}
+
+impl std::fmt::Debug for LoginData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoginData").finish_non_exhaustive()
+    }
+}
+
+/// Server's answer to a [`LoginData`]
+#[derive(Debug, Clone, Decode, Encode)]
+pub struct LoginResultData {
+    pub accepted: bool,
+    /// Why the login was rejected
+    pub reason: Option<String>,
+}
+
 #[derive(Debug, Clone, Decode, Encode)]
 pub enum Message {
     Spawn(SpawnData),
     Update(UpdateData),
     AddComponent(AddedComponentData),
+    SetParent(ParentData),
+    Despawn(DespawnData),
+    Resource(ResourceData),
+    SnapshotChunk(SnapshotChunkData),
+    Login(LoginData),
+    LoginResult(LoginResultData),
+}
+
+/// Variant tag of a [`Message`] on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BitPacked)]
+enum MessageKind {
+    Spawn,
+    Update,
+    AddComponent,
+    SetParent,
+    Despawn,
+    Resource,
+    SnapshotChunk,
+    Login,
+    LoginResult,
+}
+
+const MESSAGE_KIND_BITS: u32 = 4;
+
+/// Room for the variant tag, ids and length prefixes around a message's payloads
+const MESSAGE_HEADER_SIZE: usize = 32;
+
+impl Message {
+    fn kind(&self) -> MessageKind {
+        match self {
+            Message::Spawn(_) => MessageKind::Spawn,
+            Message::Update(_) => MessageKind::Update,
+            Message::AddComponent(_) => MessageKind::AddComponent,
+            Message::SetParent(_) => MessageKind::SetParent,
+            Message::Despawn(_) => MessageKind::Despawn,
+            Message::Resource(_) => MessageKind::Resource,
+            Message::SnapshotChunk(_) => MessageKind::SnapshotChunk,
+            Message::Login(_) => MessageKind::Login,
+            Message::LoginResult(_) => MessageKind::LoginResult,
+        }
+    }
+}
+
+fn encode<T: Encode>(value: &T, data: &mut [u8], cursor: &mut usize) -> Result<()> {
+    *cursor +=
+        bincode::encode_into_slice(value, &mut data[*cursor..], bincode::config::standard())?;
+    Ok(())
+}
+
+fn decode<T: Decode<()>>(data: &[u8], cursor: &mut usize) -> Result<T> {
+    let (value, len) = bincode::decode_from_slice(&data[*cursor..], bincode::config::standard())?;
+    *cursor += len;
+    Ok(value)
 }
 
+/// The variant tag and flags are bit packed ahead of the byte-aligned ids and payloads
+///
+/// Snapshots still store messages with bincode, only the wire encoding is packed.
 impl MessageTrait for Message {
     fn serialize(&self, data: &mut [u8]) -> Result<usize> {
-        Ok(bincode::encode_into_slice(
-            self,
-            data,
-            bincode::config::standard(),
-        )?)
+        let mut bits = BitWriter::new(data);
+        bits.write(&self.kind(), MESSAGE_KIND_BITS)?;
+        match self {
+            Message::Spawn(spawn) => bits.write(&spawn.payload.is_some(), 1)?,
+            Message::SetParent(parent) => {
+                bits.write(&parent.parent.is_some(), 1)?;
+                bits.write(&parent.relative_transform, 1)?;
+            }
+            Message::LoginResult(result) => {
+                bits.write(&result.accepted, 1)?;
+                bits.write(&result.reason.is_some(), 1)?;
+            }
+            _ => {}
+        }
+        let mut cursor = bits.finish();
+
+        match self {
+            Message::Spawn(spawn) => {
+                encode(&spawn.mob_type, data, &mut cursor)?;
+                encode(&spawn.spawn_id, data, &mut cursor)?;
+                if let Some(payload) = &spawn.payload {
+                    encode(payload, data, &mut cursor)?;
+                }
+                encode(&spawn.components, data, &mut cursor)?;
+            }
+            Message::Update(update) => encode(update, data, &mut cursor)?,
+            Message::AddComponent(added) => encode(added, data, &mut cursor)?,
+            Message::SetParent(parent) => {
+                encode(&parent.spawn_id, data, &mut cursor)?;
+                if let Some(parent) = &parent.parent {
+                    encode(parent, data, &mut cursor)?;
+                }
+            }
+            Message::Despawn(despawn) => encode(despawn, data, &mut cursor)?,
+            Message::Resource(resource) => encode(resource, data, &mut cursor)?,
+            Message::SnapshotChunk(chunk) => encode(chunk, data, &mut cursor)?,
+            Message::Login(login) => encode(login, data, &mut cursor)?,
+            Message::LoginResult(result) => {
+                if let Some(reason) = &result.reason {
+                    encode(reason, data, &mut cursor)?;
+                }
+            }
+        }
+
+        Ok(cursor)
+    }
+
+    fn size_hint(&self) -> usize {
+        MESSAGE_HEADER_SIZE
+            + match self {
+                Message::Spawn(spawn) => {
+                    spawn.payload.as_ref().map_or(0, Vec::len)
+                        + spawn
+                            .components
+                            .iter()
+                            .map(|component| component.data.len() + MESSAGE_HEADER_SIZE)
+                            .sum::<usize>()
+                }
+                Message::Update(update) => update.data.len(),
+                Message::AddComponent(added) => added.data.len(),
+                Message::SetParent(_) | Message::Despawn(_) => 0,
+                Message::Resource(resource) => resource.data.len(),
+                Message::SnapshotChunk(chunk) => chunk.data.len(),
+                Message::Login(login) => login.token.len(),
+                Message::LoginResult(result) => result.reason.as_ref().map_or(0, String::len),
+            }
     }
 }
 
@@ -78,10 +294,53 @@ pub struct MessageFactoryNew;
 impl MessageFactoryTrait for MessageFactoryNew {
     type Message = Message;
 
-    fn deserialize(&self, _context: &(), _data: &[u8]) -> Result<(Self::Message, usize)> {
-        Ok(bincode::decode_from_slice(
-            _data,
-            bincode::config::standard(),
-        )?)
+    fn deserialize(&self, _context: &(), data: &[u8]) -> Result<(Self::Message, usize)> {
+        let mut bits = BitReader::new(data);
+        let kind = bits.read::<MessageKind>(MESSAGE_KIND_BITS)?;
+        let (has_payload, has_parent, relative_transform, accepted, has_reason) = match kind {
+            MessageKind::Spawn => (bits.read(1)?, false, false, false, false),
+            MessageKind::SetParent => (false, bits.read(1)?, bits.read(1)?, false, false),
+            MessageKind::LoginResult => (false, false, false, bits.read(1)?, bits.read(1)?),
+            _ => (false, false, false, false, false),
+        };
+        let mut cursor = bits.finish();
+
+        let message = match kind {
+            MessageKind::Spawn => Message::Spawn(SpawnData {
+                mob_type: decode(data, &mut cursor)?,
+                spawn_id: decode(data, &mut cursor)?,
+                payload: if has_payload {
+                    Some(decode(data, &mut cursor)?)
+                } else {
+                    None
+                },
+                components: decode(data, &mut cursor)?,
+            }),
+            MessageKind::Update => Message::Update(decode(data, &mut cursor)?),
+            MessageKind::AddComponent => Message::AddComponent(decode(data, &mut cursor)?),
+            MessageKind::SetParent => Message::SetParent(ParentData {
+                spawn_id: decode(data, &mut cursor)?,
+                parent: if has_parent {
+                    Some(decode(data, &mut cursor)?)
+                } else {
+                    None
+                },
+                relative_transform,
+            }),
+            MessageKind::Despawn => Message::Despawn(decode(data, &mut cursor)?),
+            MessageKind::Resource => Message::Resource(decode(data, &mut cursor)?),
+            MessageKind::SnapshotChunk => Message::SnapshotChunk(decode(data, &mut cursor)?),
+            MessageKind::Login => Message::Login(decode(data, &mut cursor)?),
+            MessageKind::LoginResult => Message::LoginResult(LoginResultData {
+                accepted,
+                reason: if has_reason {
+                    Some(decode(data, &mut cursor)?)
+                } else {
+                    None
+                },
+            }),
+        };
+
+        Ok((message, cursor))
     }
 }
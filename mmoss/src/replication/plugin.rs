@@ -0,0 +1,411 @@
+//! Bevy [`Plugin`]s for running replication inside an [`App`]
+//!
+//! The managers are stored as non-send resources and driven by exclusive systems which block on
+//! a shared tokio runtime. Network tasks, such as receiving messages or accepting connections,
+//! are spawned onto the same runtime.
+//!
+//! An app may itself be run from within a tokio runtime, such as from `#[tokio::main]`. That
+//! runtime has to be multi-threaded, as the systems hand their worker thread off with
+//! [`block_in_place`] before blocking.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use bevy::{
+    app::{App, Plugin, PostUpdate, PreUpdate},
+    ecs::{
+        resource::Resource,
+        schedule::{InternedScheduleLabel, ScheduleLabel},
+        world::World,
+    },
+};
+use log::{error, info};
+use tokio::{
+    runtime::{Handle, Runtime},
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    task::block_in_place,
+};
+
+use crate::{
+    net::transport::{Reliable, Unreliable, memory, tcp},
+    physics::proxy::register_proxy_components,
+    replication::{
+        Message, MessageFactoryNew,
+        auth::{self, Identity, Validator},
+        client::{
+            self, NoopUpdateCallbacks, factory::component::Factory as ComponentFactory,
+            factory::mob::Factory as MobFactory,
+        },
+        server,
+    },
+};
+
+/// Longest wait between attempts to receive after repeated errors
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Tokio runtime shared by the replication plugins
+#[derive(Resource, Clone)]
+pub struct TokioRuntime(pub Arc<Runtime>);
+
+impl TokioRuntime {
+    /// Run `future` to completion from a system, which may be running inside another runtime
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        match Handle::try_current() {
+            Ok(_) => block_in_place(|| self.0.block_on(future)),
+            Err(_) => self.0.block_on(future),
+        }
+    }
+}
+
+fn insert_runtime(app: &mut App, runtime: &Option<Arc<Runtime>>) {
+    if app.world().contains_resource::<TokioRuntime>() {
+        return;
+    }
+
+    let runtime = runtime
+        .clone()
+        .unwrap_or_else(|| Arc::new(Runtime::new().expect("Failed to create tokio runtime")));
+    app.insert_resource(TokioRuntime(runtime));
+}
+
+/// Connection waiting to be added to the [`server::Manager`]
+pub struct NewClient {
+    pub transport: Box<dyn Reliable<Message>>,
+    /// Set if the client logged in through [`auth::authenticate`]
+    pub identity: Option<Identity>,
+}
+
+/// Clients waiting to be handed to the [`server::Manager`]
+///
+/// Connections accepted on other tasks are sent through [`Self::sender`] and added to the
+/// manager before the next serialization.
+#[derive(Resource)]
+pub struct NewClients {
+    pub sender: UnboundedSender<NewClient>,
+    receiver: UnboundedReceiver<NewClient>,
+}
+
+impl NewClients {
+    fn new() -> Self {
+        let (sender, receiver) = unbounded_channel();
+        Self { sender, receiver }
+    }
+}
+
+pub struct ReplicationServerPlugin {
+    schedule: InternedScheduleLabel,
+    runtime: Option<Arc<Runtime>>,
+    tcp_address: Option<String>,
+    validator: Option<Arc<dyn Validator>>,
+}
+
+impl Default for ReplicationServerPlugin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplicationServerPlugin {
+    pub fn new() -> Self {
+        Self {
+            schedule: PostUpdate.intern(),
+            runtime: None,
+            tcp_address: None,
+            validator: None,
+        }
+    }
+
+    /// Schedule to serialize the world in, [`PostUpdate`] by default
+    pub fn in_schedule(mut self, schedule: impl ScheduleLabel) -> Self {
+        self.schedule = schedule.intern();
+        self
+    }
+
+    /// Use an existing runtime instead of creating one
+    pub fn with_runtime(mut self, runtime: Arc<Runtime>) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Accept TCP clients on the given address
+    pub fn with_tcp_listener(mut self, address: impl Into<String>) -> Self {
+        self.tcp_address = Some(address.into());
+        self
+    }
+
+    /// Require accepted clients to log in with a token `validator` accepts before they are added
+    pub fn with_validator(mut self, validator: impl Validator + 'static) -> Self {
+        self.validator = Some(Arc::new(validator));
+        self
+    }
+}
+
+impl Plugin for ReplicationServerPlugin {
+    fn build(&self, app: &mut App) {
+        insert_runtime(app, &self.runtime);
+        register_proxy_components(app.world_mut());
+
+        let new_clients = NewClients::new();
+        if let Some(address) = self.tcp_address.clone() {
+            let sender = new_clients.sender.clone();
+            let validator = self.validator.clone();
+            let runtime = app.world().resource::<TokioRuntime>().0.clone();
+            runtime.spawn(async move {
+                let listener = match tcp::Listener::bind(address.as_str()).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        error!("Failed to bind {}: {}", address, e);
+                        return;
+                    }
+                };
+
+                while !sender.is_closed() {
+                    let (accepted, addr) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            error!("Failed to accept connection: {}", e);
+                            continue;
+                        }
+                    };
+                    info!("Client connected from {}", addr);
+
+                    // Establish and log in on a separate task so a slow client doesn't hold up
+                    // others
+                    let sender = sender.clone();
+                    let validator = validator.clone();
+                    tokio::spawn(async move {
+                        let connection = match accepted.establish(MessageFactoryNew).await {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                error!("Failed to establish connection with {}: {}", addr, e);
+                                return;
+                            }
+                        };
+                        match admit_client(
+                            &sender,
+                            connection,
+                            validator.as_deref(),
+                            auth::DEFAULT_TIMEOUT,
+                        )
+                        .await
+                        {
+                            Ok(Some(identity)) => {
+                                info!("Client {} logged in as {:?}", addr, identity)
+                            }
+                            Ok(None) => {}
+                            Err(e) => error!("Failed to admit client {}: {}", addr, e),
+                        }
+                    });
+                }
+            });
+        }
+
+        app.insert_resource(new_clients)
+            .insert_non_send_resource(server::Manager::new())
+            .add_systems(self.schedule, serialize_world);
+    }
+}
+
+/// Connect a client living in the same process, such as the player hosting a listen server
+///
+/// Requires [`ReplicationServerPlugin`] in `world`. Messages are handed over in memory, pass the
+/// returned transport to [`connect_client`] in the client's world.
+pub fn connect_local_client(world: &World) -> Result<Box<dyn Unreliable<Message>>> {
+    let new_clients = world
+        .get_resource::<NewClients>()
+        .ok_or(anyhow::anyhow!("Replication server plugin not added"))?;
+
+    let (server, client) = memory::direct_pair();
+    new_clients
+        .sender
+        .send(NewClient {
+            transport: Box::new(server),
+            identity: None,
+        })
+        .map_err(|_| anyhow::anyhow!("Replication server stopped"))?;
+    info!("Local client connected");
+    Ok(Box::new(client))
+}
+
+/// Log `connection` in through `validator`, if any, and queue it for the [`server::Manager`]
+///
+/// Clients failing to log in are dropped without ever reaching the manager. Returns the
+/// identity of the logged in client.
+pub async fn admit_client(
+    sender: &UnboundedSender<NewClient>,
+    mut connection: impl Reliable<Message> + 'static,
+    validator: Option<&dyn Validator>,
+    timeout: Duration,
+) -> Result<Option<Identity>> {
+    let identity = match validator {
+        Some(validator) => Some(auth::authenticate(&mut connection, validator, timeout).await?),
+        None => None,
+    };
+
+    sender
+        .send(NewClient {
+            transport: Box::new(connection),
+            identity: identity.clone(),
+        })
+        .map_err(|_| anyhow::anyhow!("Replication server stopped"))?;
+    Ok(identity)
+}
+
+fn add_new_clients(new_clients: &mut NewClients, manager: &mut server::Manager) {
+    while let Ok(client) = new_clients.receiver.try_recv() {
+        match client.identity {
+            Some(identity) => manager.add_authenticated_client(client.transport, identity),
+            None => manager.add_client(client.transport),
+        };
+    }
+}
+
+fn serialize_world(world: &mut World) {
+    let Some(mut manager) = world.remove_non_send_resource::<server::Manager>() else {
+        return;
+    };
+
+    if let Some(mut new_clients) = world.get_resource_mut::<NewClients>() {
+        add_new_clients(&mut new_clients, &mut manager);
+    }
+
+    let runtime = world.resource::<TokioRuntime>().clone();
+    runtime.block_on(manager.serialize(world));
+    world.insert_non_send_resource(manager);
+}
+
+pub struct ReplicationClientPlugin {
+    schedule: InternedScheduleLabel,
+    runtime: Option<Arc<Runtime>>,
+}
+
+impl Default for ReplicationClientPlugin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplicationClientPlugin {
+    pub fn new() -> Self {
+        Self {
+            schedule: PreUpdate.intern(),
+            runtime: None,
+        }
+    }
+
+    /// Schedule to apply replicated state in, [`PreUpdate`] by default
+    pub fn in_schedule(mut self, schedule: impl ScheduleLabel) -> Self {
+        self.schedule = schedule.intern();
+        self
+    }
+
+    /// Use an existing runtime instead of creating one
+    pub fn with_runtime(mut self, runtime: Arc<Runtime>) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+}
+
+impl Plugin for ReplicationClientPlugin {
+    fn build(&self, app: &mut App) {
+        insert_runtime(app, &self.runtime);
+        register_proxy_components(app.world_mut());
+        app.add_systems(self.schedule, update_world);
+    }
+}
+
+/// Start replicating from `transport` into `world`
+///
+/// Requires [`ReplicationClientPlugin`]. Incoming messages are processed on the plugin's runtime
+/// and applied to the world in the plugin's schedule.
+pub fn connect_client(
+    world: &mut World,
+    transport: Box<dyn Unreliable<Message>>,
+    mob_factory: Arc<MobFactory<World>>,
+    component_factory: Arc<ComponentFactory<World>>,
+) {
+    let (manager, mut incoming) = client::Manager::new(transport, mob_factory, component_factory);
+
+    world.resource::<TokioRuntime>().0.spawn(async move {
+        let mut backoff = Duration::ZERO;
+        loop {
+            match incoming.process_incoming().await {
+                Ok(()) => backoff = Duration::ZERO,
+                Err(_) if incoming.is_closed() => break,
+                Err(e) => {
+                    error!("Error processing incoming messages: {}", e);
+                    // A transport failing on every receive would otherwise spin
+                    backoff = (backoff * 2).clamp(Duration::from_millis(10), MAX_RECEIVE_BACKOFF);
+                    tokio::time::sleep(backoff).await;
+                }
+            }
+        }
+    });
+
+    world.insert_non_send_resource(manager);
+}
+
+fn update_world(world: &mut World) {
+    let Some(mut manager) = world.remove_non_send_resource::<client::Manager<World>>() else {
+        return;
+    };
+
+    let runtime = world.resource::<TokioRuntime>().clone();
+    runtime.block_on(manager.update_world(world, &mut NoopUpdateCallbacks));
+    world.insert_non_send_resource(manager);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replication::MessageFactoryNew;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    async fn validate(token: Vec<u8>) -> Result<Identity> {
+        match token.as_slice() {
+            b"alice" => Ok(Identity("alice".to_string())),
+            _ => Err(anyhow::anyhow!("Unknown token")),
+        }
+    }
+
+    #[tokio::test]
+    async fn admits_logged_in_clients() {
+        let mut new_clients = NewClients::new();
+        let (server, mut client) = memory::pair(MessageFactoryNew, MessageFactoryNew);
+        let (admitted, result) = tokio::join!(
+            admit_client(&new_clients.sender, server, Some(&validate), TIMEOUT),
+            auth::login(&mut client, b"alice".to_vec(), TIMEOUT)
+        );
+        result.unwrap();
+        let identity = Identity("alice".to_string());
+        assert_eq!(admitted.unwrap(), Some(identity.clone()));
+
+        let mut manager = server::Manager::new();
+        add_new_clients(&mut new_clients, &mut manager);
+        assert_eq!(manager.identity(server::ClientId(0)), Some(&identity));
+    }
+
+    #[tokio::test]
+    async fn drops_clients_failing_to_log_in() {
+        let mut new_clients = NewClients::new();
+
+        let (server, mut client) = memory::pair(MessageFactoryNew, MessageFactoryNew);
+        let (admitted, result) = tokio::join!(
+            admit_client(&new_clients.sender, server, Some(&validate), TIMEOUT),
+            auth::login(&mut client, b"mallory".to_vec(), TIMEOUT)
+        );
+        assert!(admitted.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Login rejected: Unknown token"
+        );
+
+        let (server, _client) = memory::pair(MessageFactoryNew, MessageFactoryNew);
+        let admitted = admit_client(&new_clients.sender, server, Some(&validate), TIMEOUT).await;
+        assert!(admitted.is_err());
+
+        assert!(new_clients.receiver.try_recv().is_err());
+    }
+}
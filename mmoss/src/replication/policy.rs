@@ -0,0 +1,55 @@
+//! Update policies controlling how [`Replicated`](super::Replicated) components are replicated
+//!
+//! A component declares its policy with `#[update_policy(...)]` on `#[derive(Replicated)]`,
+//! which can be overridden per [`ComponentType`](super::ComponentType) with
+//! [`server::Manager::set_update_policy`](super::server::Manager::set_update_policy). Policies
+//! only apply to updates, spawns always carry the full initial state of a mob.
+
+use std::time::Duration;
+
+/// Transport used for a component's updates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reliability {
+    #[default]
+    Reliable,
+    /// Sent over the client's unreliable transport if it has one, otherwise reliably
+    Unreliable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct UpdatePolicy {
+    /// Minimum time between two updates, dirty components are held back until it has passed
+    pub min_interval: Duration,
+    pub reliability: Reliability,
+    /// Skip updates whose serialized state is identical to the last one sent
+    pub on_change_only: bool,
+    /// Only send updates to the client owning the entity
+    pub owner_only: bool,
+}
+
+impl UpdatePolicy {
+    /// Limit updates to at most `rate` per second
+    pub fn with_max_rate(mut self, rate: f64) -> Self {
+        self.min_interval = if rate > 0.0 {
+            Duration::from_secs_f64(1.0 / rate)
+        } else {
+            Duration::ZERO
+        };
+        self
+    }
+
+    pub fn with_reliability(mut self, reliability: Reliability) -> Self {
+        self.reliability = reliability;
+        self
+    }
+
+    pub fn with_on_change_only(mut self, on_change_only: bool) -> Self {
+        self.on_change_only = on_change_only;
+        self
+    }
+
+    pub fn with_owner_only(mut self, owner_only: bool) -> Self {
+        self.owner_only = owner_only;
+        self
+    }
+}
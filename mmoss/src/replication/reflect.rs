@@ -0,0 +1,230 @@
+//! Replication of [`Reflect`] components without `#[derive(Replicated)]`
+//!
+//! Component types are registered with the same [`ComponentType`] and field list on both
+//! [`server::Manager::register_reflect_component`](super::server::Manager::register_reflect_component)
+//! and [`client::Manager::register_reflect_component`](super::client::Manager::register_reflect_component).
+//! The selected fields are serialized with `bevy_reflect`, and the client inserts a default
+//! constructed component before applying them, so no component factory entry is needed.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use bevy::{
+    ecs::{
+        change_detection::{DetectChanges, Mut},
+        component::{Component, Mutable, Tick},
+        world::{EntityRef, EntityWorldMut},
+    },
+    reflect::{
+        GetTypeRegistration, Reflect, ReflectMut, ReflectRef, TypeInfo, TypeRegistry, Typed,
+        serde::{TypedReflectDeserializer, TypedReflectSerializer},
+    },
+};
+
+use crate::replication::ComponentType;
+
+struct Entry {
+    /// Names of the replicated fields, in serialization order
+    fields: Vec<&'static str>,
+    get: for<'w> fn(EntityRef<'w>) -> Option<&'w dyn Reflect>,
+    /// Tick the component was inserted at
+    added: fn(EntityRef<'_>) -> Option<Tick>,
+    get_mut: for<'a, 'w> fn(&'a mut EntityWorldMut<'w>) -> Option<Mut<'a, dyn Reflect>>,
+    insert_default: fn(&mut EntityWorldMut<'_>),
+}
+
+fn get<'w, T: Component + Reflect>(entity: EntityRef<'w>) -> Option<&'w dyn Reflect> {
+    entity.get::<T>().map(|component| component as &dyn Reflect)
+}
+
+fn added<T: Component>(entity: EntityRef<'_>) -> Option<Tick> {
+    entity.get_ref::<T>().map(|component| component.added())
+}
+
+fn get_mut<'a, T: Component<Mutability = Mutable> + Reflect>(
+    entity: &'a mut EntityWorldMut<'_>,
+) -> Option<Mut<'a, dyn Reflect>> {
+    entity
+        .get_mut::<T>()
+        .map(|component| component.map_unchanged(|component| component as &mut dyn Reflect))
+}
+
+fn insert_default<T: Component + Default>(entity: &mut EntityWorldMut<'_>) {
+    entity.insert(T::default());
+}
+
+/// Reflected component types registered for replication
+pub(crate) struct Registry {
+    types: TypeRegistry,
+    entries: HashMap<ComponentType, Entry>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            types: TypeRegistry::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Register `T`, replicating the given fields or all of them if `fields` is empty
+    pub fn register<T>(&mut self, component_type: ComponentType, fields: &[&str]) -> Result<()>
+    where
+        T: Component<Mutability = Mutable> + Reflect + Typed + GetTypeRegistration + Default,
+    {
+        let TypeInfo::Struct(info) = T::type_info() else {
+            return Err(anyhow::anyhow!(
+                "{} must be a struct to be replicated through reflection",
+                T::type_info().type_path()
+            ));
+        };
+
+        let fields = if fields.is_empty() {
+            info.field_names().to_vec()
+        } else {
+            fields
+                .iter()
+                .map(|name| {
+                    info.field(name).map(|field| field.name()).ok_or_else(|| {
+                        anyhow::anyhow!("{} has no field {}", info.type_path(), name)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        self.types.register::<T>();
+        self.entries.insert(
+            component_type,
+            Entry {
+                fields,
+                get: get::<T>,
+                added: added::<T>,
+                get_mut: get_mut::<T>,
+                insert_default: insert_default::<T>,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn contains(&self, component_type: ComponentType) -> bool {
+        self.entries.contains_key(&component_type)
+    }
+
+    /// Registered component types present on `entity`
+    pub fn component_types(&self, entity: EntityRef<'_>) -> Vec<ComponentType> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| (entry.get)(entity).is_some())
+            .map(|(component_type, _)| *component_type)
+            .collect()
+    }
+
+    /// Registered component types inserted into `entity` after the `since` tick
+    pub fn added_component_types(
+        &self,
+        entity: EntityRef<'_>,
+        since: Tick,
+        this_run: Tick,
+    ) -> Vec<ComponentType> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| {
+                (entry.added)(entity).is_some_and(|added| added.is_newer_than(since, this_run))
+            })
+            .map(|(component_type, _)| *component_type)
+            .collect()
+    }
+
+    fn entry(&self, component_type: ComponentType) -> Result<&Entry> {
+        self.entries.get(&component_type).ok_or_else(|| {
+            anyhow::anyhow!(
+                "No reflected component registered for type {:?}",
+                component_type
+            )
+        })
+    }
+
+    pub fn serialize(
+        &self,
+        component_type: ComponentType,
+        entity: EntityRef<'_>,
+    ) -> Result<Vec<u8>> {
+        let entry = self.entry(component_type)?;
+        let component = (entry.get)(entity).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Entity {:?} has no component of type {:?}",
+                entity.id(),
+                component_type
+            )
+        })?;
+        let ReflectRef::Struct(component) = component.reflect_ref() else {
+            return Err(anyhow::anyhow!("Reflected component is not a struct"));
+        };
+
+        let mut data = Vec::new();
+        for name in &entry.fields {
+            let field = component
+                .field(name)
+                .ok_or_else(|| anyhow::anyhow!("Missing field {}", name))?;
+            data.extend(bincode::serde::encode_to_vec(
+                TypedReflectSerializer::new(field, &self.types),
+                bincode::config::standard(),
+            )?);
+        }
+
+        Ok(data)
+    }
+
+    /// Apply serialized fields to the component on `entity`
+    pub fn apply(
+        &self,
+        component_type: ComponentType,
+        entity: &mut EntityWorldMut<'_>,
+        data: &[u8],
+    ) -> Result<()> {
+        let entry = self.entry(component_type)?;
+        let id = entity.id();
+        let mut component = (entry.get_mut)(entity).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Entity {:?} has no component of type {:?}",
+                id,
+                component_type
+            )
+        })?;
+        let ReflectMut::Struct(component) = component.reflect_mut() else {
+            return Err(anyhow::anyhow!("Reflected component is not a struct"));
+        };
+
+        let mut cursor = 0;
+        for name in &entry.fields {
+            let field = component
+                .field_mut(name)
+                .ok_or_else(|| anyhow::anyhow!("Missing field {}", name))?;
+            let registration = field
+                .get_represented_type_info()
+                .and_then(|info| self.types.get(info.type_id()))
+                .ok_or_else(|| anyhow::anyhow!("Field {} has no type registration", name))?;
+
+            let (value, bytes_read) = bincode::serde::seed_decode_from_slice(
+                TypedReflectDeserializer::new(registration, &self.types),
+                &data[cursor..],
+                bincode::config::standard(),
+            )?;
+            field.try_apply(value.as_ref())?;
+            cursor += bytes_read;
+        }
+
+        Ok(())
+    }
+
+    /// Insert a default constructed component into `entity` and apply serialized fields to it
+    pub fn insert(
+        &self,
+        component_type: ComponentType,
+        entity: &mut EntityWorldMut<'_>,
+        data: &[u8],
+    ) -> Result<()> {
+        (self.entry(component_type)?.insert_default)(entity);
+        self.apply(component_type, entity, data)
+    }
+}
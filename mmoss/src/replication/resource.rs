@@ -0,0 +1,59 @@
+//! Replication of bevy [`Resource`]s as world-global state
+//!
+//! Resources are registered with the same [`ResourceType`] on both
+//! [`server::Manager::register_resource`](super::server::Manager::register_resource) and
+//! [`client::Manager::register_resource`](super::client::Manager::register_resource). The server
+//! sends a resource whenever it is changed to a new encoded state and on every full sync, the client
+//! inserts it into its world. Removing a resource on the server isn't replicated.
+
+use std::{collections::HashMap, marker::PhantomData};
+
+use anyhow::Result;
+use bevy::ecs::{
+    change_detection::DetectChanges, component::Tick, resource::Resource, world::World,
+};
+use bincode::{Decode, Encode};
+
+use crate::replication::ResourceType;
+
+/// Type-erased access to a replicated resource
+pub(crate) trait Entry: Send {
+    /// Encode the resource, `None` if it isn't present in the world or wasn't changed after the
+    /// `since` tick
+    fn serialize(&self, world: &World, since: Option<Tick>) -> Result<Option<Vec<u8>>>;
+    fn insert(&self, world: &mut World, data: &[u8]) -> Result<()>;
+}
+
+struct TypedEntry<R>(PhantomData<fn() -> R>);
+
+impl<R: Resource + Encode + Decode<()>> Entry for TypedEntry<R> {
+    fn serialize(&self, world: &World, since: Option<Tick>) -> Result<Option<Vec<u8>>> {
+        let Some(resource) = world.get_resource_ref::<R>() else {
+            return Ok(None);
+        };
+        if since.is_some_and(|since| {
+            !resource
+                .last_changed()
+                .is_newer_than(since, world.read_change_tick())
+        }) {
+            return Ok(None);
+        }
+        Ok(Some(bincode::encode_to_vec(
+            &*resource,
+            bincode::config::standard(),
+        )?))
+    }
+
+    fn insert(&self, world: &mut World, data: &[u8]) -> Result<()> {
+        let (resource, _): (R, _) = bincode::decode_from_slice(data, bincode::config::standard())?;
+        world.insert_resource(resource);
+        Ok(())
+    }
+}
+
+pub(crate) fn entry<R: Resource + Encode + Decode<()>>() -> Box<dyn Entry> {
+    Box::new(TypedEntry::<R>(PhantomData))
+}
+
+/// Resources registered for replication, by type
+pub(crate) type Registry = HashMap<ResourceType, Box<dyn Entry>>;
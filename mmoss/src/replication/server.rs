@@ -1,32 +1,154 @@
-use std::mem;
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    time::Instant,
+};
 
-use bevy::ecs::{
-    entity::{Entity, EntityHashMap, EntityHashSet},
-    world::{EntityRef, World},
+use anyhow::Result;
+use bevy::{
+    ecs::{
+        change_detection::DetectChanges,
+        component::{Component, Mutable, Tick},
+        entity::{Entity, EntityHashMap, EntityHashSet},
+        hierarchy::{ChildOf, Children},
+        lifecycle::RemovedComponents,
+        query::{Changed, Has, Or},
+        resource::Resource,
+        system::{Query, SystemState},
+        world::{EntityRef, World},
+    },
+    reflect::{GetTypeRegistration, Reflect, Typed},
 };
 use bevy_trait_query::{All, ReadTraits};
-use log::{debug, error, trace};
+use bincode::{Decode, Encode};
+use log::{debug, error, info, trace, warn};
 
 use crate::{
-    net::transport::Reliable,
+    net::{
+        buffer,
+        transport::{Reliable, Unreliable},
+    },
+    physics::RelativeTransform,
     replication::{
-        AddedComponentData, Message, MobType, Replicated, SpawnData, SpawnId, UpdateData,
+        AddedComponentData, ComponentData, ComponentType, DespawnData, Id, Message, MobType,
+        ParentData, Replicated, ResourceData, ResourceType, SpawnData, SpawnId, UpdateData,
+        allocator::Allocator,
+        auth::Identity,
+        policy::{Reliability, UpdatePolicy},
+        reflect, resource, snapshot,
     },
 };
 
+/// Entities whose parent or relative transform was changed or removed, and the children of
+/// each entity
+type HierarchyChanges = (
+    Query<'static, 'static, Entity, Or<(Changed<ChildOf>, Changed<RelativeTransform>)>>,
+    Query<'static, 'static, &'static Children>,
+    RemovedComponents<'static, 'static, ChildOf>,
+    RemovedComponents<'static, 'static, RelativeTransform>,
+);
+
+/// Produces the instance data sent along with a mob's spawn
+///
+/// The payload is handed to the client's mob factory entry when constructing the mob.
+pub trait SpawnPayload: Send {
+    fn payload(&self, entity: EntityRef<'_>) -> Result<Option<Vec<u8>>>;
+}
+
+impl<F: Fn(EntityRef<'_>) -> Result<Option<Vec<u8>>> + Send> SpawnPayload for F {
+    fn payload(&self, entity: EntityRef<'_>) -> Result<Option<Vec<u8>>> {
+        self(entity)
+    }
+}
+
+/// Handle of a client added to a [`Manager`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientId(pub u32);
+
+struct Client {
+    id: ClientId,
+    reliable: Box<dyn Reliable<Message>>,
+    unreliable: Option<Box<dyn Unreliable<Message>>>,
+    identity: Option<Identity>,
+}
+
+impl Client {
+    async fn send(&mut self, message: &Message) -> Result<()> {
+        self.reliable.send(message).await
+    }
+
+    async fn send_with(&mut self, reliability: Reliability, message: &Message) -> Result<()> {
+        match (reliability, self.unreliable.as_mut()) {
+            (Reliability::Unreliable, Some(unreliable)) => unreliable.send(message).await,
+            _ => self.reliable.send(message).await,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.reliable.flush().await?;
+        if let Some(unreliable) = self.unreliable.as_mut() {
+            unreliable.flush().await?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Manager {
     /// All connected clients
-    clients: Vec<Box<dyn Reliable<Message>>>,
+    clients: Vec<Client>,
     /// All connected clients that are pending their first full state sync
-    pending_full_sync: Vec<Box<dyn Reliable<Message>>>,
+    pending_full_sync: Vec<Client>,
+    next_client_id: u32,
+    /// Clients removed because their connection closed, until taken
+    disconnected: Vec<ClientId>,
     /// Newly spawned entities that need to be sent to clients
     newly_spawned: EntityHashSet,
     /// All objects that have changed since the last update
     dirty: EntityHashSet,
+    /// Components whose update was held back by their policy's minimum interval
+    held_back: EntityHashMap<HashSet<Id>>,
+    /// Policies overriding the ones declared by the components
+    update_policies: HashMap<ComponentType, UpdatePolicy>,
+    /// Time of the last update of rate limited components
+    last_sent: HashMap<Id, Instant>,
+    /// Last state sent of components only replicated on change
+    last_data: HashMap<Id, Vec<u8>>,
+    /// Client owning each entity
+    owners: EntityHashMap<ClientId>,
+    /// Components replicated through reflection
+    reflected: reflect::Registry,
+    /// Replication IDs of the reflected components of each entity
+    reflect_ids: EntityHashMap<Vec<(ComponentType, Id)>>,
+    /// Resources registered for replication
+    resources: resource::Registry,
+    /// Last state sent of each replicated resource
+    resource_data: HashMap<ResourceType, Vec<u8>>,
+    /// World change tick the resources were last checked at, `None` to encode all of them
+    resources_checked: Option<Tick>,
+    /// World change tick components were last checked for insertions at
+    components_checked: Option<Tick>,
     /// Map from entity to spawn ID
     entity_spawn_ids: EntityHashMap<SpawnId>,
-    /// Next spawn ID to use
-    next_spawn_id: u32,
+    /// Map from spawn ID to entity
+    spawn_id_entities: HashMap<SpawnId, Entity>,
+    /// Last replicated hierarchy state of each entity with a parent or a relative transform
+    parents: EntityHashMap<ParentData>,
+    /// Hierarchy changes since the previous serialize, created on the first one
+    hierarchy_changes: Option<SystemState<HierarchyChanges>>,
+    /// Replication IDs claimed by the components of each entity
+    entity_ids: EntityHashMap<Vec<Id>>,
+    /// Entity owning each claimed replication ID and the type of its component
+    id_owners: HashMap<Id, (Entity, ComponentType)>,
+    /// Allocator for replication IDs
+    ids: Allocator,
+    /// Allocator for spawn IDs
+    spawn_ids: Allocator,
+    /// Spawn payload hooks by mob type
+    spawn_payloads: HashMap<MobType, Box<dyn SpawnPayload>>,
+    /// ID of the next full sync snapshot
+    next_snapshot_id: u32,
+    /// Maximum size of the compressed data in each snapshot chunk
+    snapshot_chunk_size: usize,
 }
 
 impl Manager {
@@ -34,33 +156,425 @@ impl Manager {
         Self {
             clients: Vec::new(),
             pending_full_sync: Vec::new(),
+            next_client_id: 0,
+            disconnected: Vec::new(),
             newly_spawned: EntityHashSet::new(),
             dirty: EntityHashSet::new(),
+            held_back: EntityHashMap::new(),
+            update_policies: HashMap::new(),
+            last_sent: HashMap::new(),
+            last_data: HashMap::new(),
+            owners: EntityHashMap::new(),
+            reflected: reflect::Registry::new(),
+            reflect_ids: EntityHashMap::new(),
+            resources: resource::Registry::new(),
+            resource_data: HashMap::new(),
+            resources_checked: None,
+            components_checked: None,
             entity_spawn_ids: EntityHashMap::new(),
-            next_spawn_id: 0,
+            spawn_id_entities: HashMap::new(),
+            parents: EntityHashMap::new(),
+            hierarchy_changes: None,
+            entity_ids: EntityHashMap::new(),
+            id_owners: HashMap::new(),
+            ids: Allocator::new(),
+            spawn_ids: Allocator::new(),
+            spawn_payloads: HashMap::new(),
+            next_snapshot_id: 0,
+            snapshot_chunk_size: snapshot::DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    pub fn add_client(&mut self, client: Box<dyn Reliable<Message>>) -> ClientId {
+        self.push_client(client, None, None)
+    }
+
+    /// Add a client with an additional transport for [`Reliability::Unreliable`] updates
+    pub fn add_client_with_unreliable(
+        &mut self,
+        reliable: Box<dyn Reliable<Message>>,
+        unreliable: Box<dyn Unreliable<Message>>,
+    ) -> ClientId {
+        self.push_client(reliable, Some(unreliable), None)
+    }
+
+    /// Add a client that logged in through [`authenticate`](crate::replication::auth::authenticate)
+    pub fn add_authenticated_client(
+        &mut self,
+        client: Box<dyn Reliable<Message>>,
+        identity: Identity,
+    ) -> ClientId {
+        self.push_client(client, None, Some(identity))
+    }
+
+    /// Add a client that logged in through [`authenticate`](crate::replication::auth::authenticate), with an additional transport
+    /// for [`Reliability::Unreliable`] updates
+    pub fn add_authenticated_client_with_unreliable(
+        &mut self,
+        reliable: Box<dyn Reliable<Message>>,
+        unreliable: Box<dyn Unreliable<Message>>,
+        identity: Identity,
+    ) -> ClientId {
+        self.push_client(reliable, Some(unreliable), Some(identity))
+    }
+
+    fn push_client(
+        &mut self,
+        reliable: Box<dyn Reliable<Message>>,
+        unreliable: Option<Box<dyn Unreliable<Message>>>,
+        identity: Option<Identity>,
+    ) -> ClientId {
+        let id = ClientId(self.next_client_id);
+        self.next_client_id += 1;
+        self.pending_full_sync.push(Client {
+            id,
+            reliable,
+            unreliable,
+            identity,
+        });
+        id
+    }
+
+    /// Identity of a connected client, `None` if it was added without authenticating
+    pub fn identity(&self, client: ClientId) -> Option<&Identity> {
+        self.clients
+            .iter()
+            .chain(self.pending_full_sync.iter())
+            .find(|c| c.id == client)
+            .and_then(|c| c.identity.as_ref())
+    }
+
+    /// Clients removed since the last call because their connection was closed, such as by a
+    /// [`KeepAlive`](crate::net::transport::keepalive::KeepAlive) timeout
+    pub fn take_disconnected(&mut self) -> Vec<ClientId> {
+        mem::take(&mut self.disconnected)
+    }
+
+    fn remove_closed_clients(&mut self) {
+        for clients in [&mut self.clients, &mut self.pending_full_sync] {
+            clients.retain(|client| {
+                if !client.reliable.is_closed() {
+                    return true;
+                }
+
+                info!("Client {:?} disconnected", client.id);
+                self.disconnected.push(client.id);
+                false
+            });
         }
     }
 
-    pub fn add_client(&mut self, client: Box<dyn Reliable<Message>>) {
-        self.pending_full_sync.push(client);
+    /// Set the client owning an entity, the only one receiving its owner only updates
+    pub fn set_owner(&mut self, entity: Entity, owner: Option<ClientId>) {
+        match owner {
+            Some(owner) => self.owners.insert(entity, owner),
+            None => self.owners.remove(&entity),
+        };
+    }
+
+    pub fn owner(&self, entity: Entity) -> Option<ClientId> {
+        self.owners.get(&entity).copied()
+    }
+
+    /// Replicate the resource `R` to all clients whenever it changes
+    pub fn register_resource<R: Resource + Encode + Decode<()>>(
+        &mut self,
+        resource_type: ResourceType,
+    ) {
+        self.resources.insert(resource_type, resource::entry::<R>());
+        self.resources_checked = None;
+    }
+
+    /// Replicate components of type `T` through reflection instead of [`Replicated`]
+    ///
+    /// Only the given fields are replicated, or all of them if `fields` is empty. Their policy
+    /// is the default one unless overridden with [`Self::set_update_policy`].
+    pub fn register_reflect_component<T>(
+        &mut self,
+        component_type: ComponentType,
+        fields: &[&str],
+    ) -> Result<()>
+    where
+        T: Component<Mutability = Mutable> + Reflect + Typed + GetTypeRegistration + Default,
+    {
+        self.reflected.register::<T>(component_type, fields)
+    }
+
+    /// Override the update policy of components replicated as the given type, derived or
+    /// reflected
+    ///
+    /// Derived components are matched by the type clients construct, which may differ from
+    /// their own [`Replicated::component_type`].
+    pub fn set_update_policy(&mut self, component_type: ComponentType, policy: UpdatePolicy) {
+        self.update_policies.insert(component_type, policy);
     }
 
     pub fn mark_dirty(&mut self, entity: Entity) {
         self.dirty.insert(entity);
     }
 
+    /// Register an entity for replication
+    ///
+    /// Any of its replicated components with [`Id::UNASSIGNED`] are given an ID on the next
+    /// [`Self::serialize`], as are components inserted later, which are then sent to clients as
+    /// added. The entity is unregistered automatically once it is despawned.
     pub fn register_new_entity(&mut self, entity: Entity) {
-        let spawn_id = SpawnId(self.next_spawn_id);
-        self.next_spawn_id += 1;
-        self.entity_spawn_ids.insert(entity, spawn_id);
-        self.newly_spawned.insert(entity);
+        if self.entity_spawn_ids.contains_key(&entity) {
+            warn!("Entity {:?} is already registered", entity);
+            return;
+        }
+
+        match self.spawn_ids.allocate() {
+            Ok(spawn_id) => {
+                self.entity_spawn_ids.insert(entity, SpawnId(spawn_id));
+                self.spawn_id_entities.insert(SpawnId(spawn_id), entity);
+                self.newly_spawned.insert(entity);
+            }
+            Err(e) => error!("Failed to allocate spawn ID for {:?}: {}", entity, e),
+        }
     }
 
-    async fn serialize_spawned<'a>(
+    /// Register a hook producing the spawn payload for mobs of the given type
+    pub fn register_spawn_payload(
         &mut self,
-        new_only: bool,
-        iter: impl Iterator<Item = (EntityRef<'a>, &'a MobType, ReadTraits<'a, dyn Replicated>)>,
+        mob_type: MobType,
+        payload: impl SpawnPayload + 'static,
     ) {
+        self.spawn_payloads.insert(mob_type, Box::new(payload));
+    }
+
+    /// Set the maximum size of the compressed data in each full sync snapshot chunk
+    pub fn set_snapshot_chunk_size(&mut self, chunk_size: usize) {
+        self.snapshot_chunk_size = chunk_size.max(1);
+    }
+
+    pub fn entity_by_spawn_id(&self, spawn_id: SpawnId) -> Option<Entity> {
+        self.spawn_id_entities.get(&spawn_id).copied()
+    }
+
+    pub fn spawn_id_by_entity(&self, entity: Entity) -> Option<SpawnId> {
+        self.entity_spawn_ids.get(&entity).copied()
+    }
+
+    /// Entity holding the replicated component with the given ID
+    ///
+    /// IDs are only claimed once the entity has gone through a [`Self::serialize`].
+    pub fn entity_by_replicated_id(&self, replicated_id: Id) -> Option<Entity> {
+        self.id_owners
+            .get(&replicated_id)
+            .map(|(entity, _)| *entity)
+    }
+
+    /// Entity holding the component with the given ID and the type it is replicated as
+    ///
+    /// IDs are only claimed once the entity has gone through a [`Self::serialize`].
+    pub fn component_by_replicated_id(&self, replicated_id: Id) -> Option<(Entity, ComponentType)> {
+        self.id_owners.get(&replicated_id).copied()
+    }
+
+    /// Allocate a replication ID ahead of constructing a component
+    ///
+    /// The ID is claimed by whichever entity first carries a component with it.
+    pub fn allocate_id(&mut self) -> Result<Id> {
+        Ok(Id(self.ids.allocate()?))
+    }
+
+    /// Claim the ID of a component for `entity`, assigning a new one if it is unassigned or taken
+    fn claim_id(&mut self, entity: Entity, component: &mut dyn Replicated) -> bool {
+        let id = component.id();
+        let claimed = id != Id::UNASSIGNED
+            && !self.id_owners.contains_key(&id)
+            && (self.ids.is_live(id.0) || self.ids.reserve(id.0));
+
+        if !claimed {
+            if id != Id::UNASSIGNED {
+                warn!(
+                    "Replication ID {:?} on entity {:?} can't be claimed, reassigning",
+                    id, entity
+                );
+            }
+
+            match self.ids.allocate() {
+                Ok(new_id) => component.set_id(Id(new_id)),
+                Err(e) => {
+                    error!("Failed to allocate replication ID: {}", e);
+                    return false;
+                }
+            }
+        }
+
+        self.id_owners.insert(
+            component.id(),
+            (entity, component.replicated_component_type()),
+        );
+        self.entity_ids
+            .entry(entity)
+            .or_default()
+            .push(component.id());
+        true
+    }
+
+    /// Allocate the ID of a reflected component of `entity`
+    fn claim_reflected_id(&mut self, entity: Entity, component_type: ComponentType) -> Option<Id> {
+        let id = match self.ids.allocate() {
+            Ok(id) => Id(id),
+            Err(e) => {
+                error!("Failed to allocate replication ID: {}", e);
+                return None;
+            }
+        };
+
+        self.id_owners.insert(id, (entity, component_type));
+        self.entity_ids.entry(entity).or_default().push(id);
+        self.reflect_ids
+            .entry(entity)
+            .or_default()
+            .push((component_type, id));
+        Some(id)
+    }
+
+    /// Assign IDs to the components of newly spawned entities, and to components inserted into
+    /// registered entities since the previous call
+    ///
+    /// Returns the components added to entities that clients already know about.
+    fn assign_ids(&mut self, world: &mut World) -> Vec<AddedComponentData> {
+        // Insertions from here on are newer than the tick recorded for the next check
+        let since = self
+            .components_checked
+            .replace(world.increment_change_tick());
+        let this_run = world.read_change_tick();
+        let is_added = |added: Tick| since.is_none_or(|since| added.is_newer_than(since, this_run));
+
+        let entities = self.entity_spawn_ids.keys().copied().collect::<Vec<_>>();
+        let mut added = Vec::new();
+        let mut query = world.query::<(Entity, All<&mut dyn Replicated>)>();
+        let mut iter = query.iter_many_mut(world, &entities);
+        while let Some((entity, components)) = iter.fetch_next() {
+            let spawned = self.newly_spawned.contains(&entity);
+            for mut component in components {
+                if !spawned
+                    && (!is_added(component.added())
+                        || self
+                            .entity_ids
+                            .get(&entity)
+                            .is_some_and(|ids| ids.contains(&component.id())))
+                {
+                    continue;
+                }
+
+                if !self.claim_id(entity, &mut *component) || spawned {
+                    continue;
+                }
+
+                let mut buffer = buffer::get();
+                match buffer::serialize(&mut buffer, component.size_hint(), |data| {
+                    component.serialize(data)
+                }) {
+                    Ok(len) => added.push(AddedComponentData {
+                        spawn_id: self.entity_spawn_ids[&entity],
+                        component_type: component.replicated_component_type(),
+                        replicated_id: component.id(),
+                        data: buffer[..len].to_vec(),
+                    }),
+                    Err(e) => error!("Failed to serialize component {:?}: {}", component.id(), e),
+                }
+            }
+        }
+
+        for entity in entities {
+            let Ok(entity_ref) = world.get_entity(entity) else {
+                continue;
+            };
+            let spawned = self.newly_spawned.contains(&entity);
+            let component_types = match (spawned, since) {
+                (false, Some(since)) => self
+                    .reflected
+                    .added_component_types(entity_ref, since, this_run),
+                _ => self.reflected.component_types(entity_ref),
+            };
+
+            for component_type in component_types {
+                if self
+                    .reflect_ids
+                    .get(&entity)
+                    .is_some_and(|ids| ids.iter().any(|(claimed, _)| *claimed == component_type))
+                {
+                    continue;
+                }
+                let Some(id) = self.claim_reflected_id(entity, component_type) else {
+                    continue;
+                };
+                if spawned {
+                    continue;
+                }
+
+                match self.reflected.serialize(component_type, entity_ref) {
+                    Ok(data) => added.push(AddedComponentData {
+                        spawn_id: self.entity_spawn_ids[&entity],
+                        component_type,
+                        replicated_id: id,
+                        data,
+                    }),
+                    Err(e) => error!("Failed to serialize component {:?}: {}", id, e),
+                }
+            }
+        }
+
+        added
+    }
+
+    /// Unregister despawned entities, recycling their IDs and notifying clients
+    async fn serialize_despawned(&mut self, world: &World) {
+        let despawned = self
+            .entity_spawn_ids
+            .iter()
+            .filter(|(entity, _)| world.get_entity(**entity).is_err())
+            .map(|(entity, spawn_id)| (*entity, *spawn_id))
+            .collect::<Vec<_>>();
+
+        for (entity, spawn_id) in despawned {
+            debug!("Entity {:?} despawned, spawn ID {:?}", entity, spawn_id);
+            self.entity_spawn_ids.remove(&entity);
+            self.spawn_id_entities.remove(&spawn_id);
+            self.parents.remove(&entity);
+            self.dirty.remove(&entity);
+            self.held_back.remove(&entity);
+            self.owners.remove(&entity);
+            self.reflect_ids.remove(&entity);
+            self.spawn_ids.free(spawn_id.0);
+            for id in self.entity_ids.remove(&entity).unwrap_or_default() {
+                self.id_owners.remove(&id);
+                self.last_sent.remove(&id);
+                self.last_data.remove(&id);
+                self.ids.free(id.0);
+            }
+
+            // Clients never heard of entities that didn't make it to a serialize
+            if self.newly_spawned.remove(&entity) {
+                continue;
+            }
+
+            let message = Message::Despawn(DespawnData { spawn_id });
+            for client in self.clients.iter_mut() {
+                if let Err(e) = client.send(&message).await {
+                    error!("Failed to send despawn message: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Build the spawn messages for the given entities
+    fn spawn_messages<'a>(
+        &self,
+        iter: impl Iterator<
+            Item = (
+                EntityRef<'a>,
+                &'a MobType,
+                Option<ReadTraits<'a, dyn Replicated>>,
+            ),
+        >,
+    ) -> Vec<Message> {
+        let mut messages = Vec::new();
         for (entity, mob_type, components) in iter {
             let spawn_id = self.entity_spawn_ids.get(&entity.id());
             if spawn_id.is_none() {
@@ -69,27 +583,25 @@ impl Manager {
             }
             let spawn_id = *spawn_id.unwrap();
 
-            let message = Message::Spawn(SpawnData {
-                mob_type: *mob_type,
-                spawn_id,
-            });
-
-            let clients = if new_only {
-                &mut self.pending_full_sync
-            } else {
-                &mut self.clients
+            let payload = match self.spawn_payloads.get(mob_type) {
+                Some(hook) => hook.payload(entity).unwrap_or_else(|e| {
+                    error!(
+                        "Failed to create spawn payload for {:?}: {}",
+                        entity.id(),
+                        e
+                    );
+                    None
+                }),
+                None => None,
             };
-            for client in clients.iter_mut() {
-                if let Err(e) = client.send(&message).await {
-                    error!("Failed to send spawn message: {}", e);
-                    continue;
-                }
-            }
 
-            for comp in components {
-                let mut data = vec![0u8; 512];
+            let mut component_data = Vec::new();
+            // Mobs without replicated components are still spawned, they may gain some later
+            for comp in components.into_iter().flatten() {
+                let mut buffer = buffer::get();
 
-                let result = comp.serialize(&mut data);
+                let result =
+                    buffer::serialize(&mut buffer, comp.size_hint(), |data| comp.serialize(data));
                 if result.is_err() {
                     error!(
                         "Failed to serialize spawn {:?}: {}",
@@ -98,8 +610,7 @@ impl Manager {
                     );
                     continue;
                 }
-                let len = result.unwrap();
-                data.truncate(len);
+                let data = buffer[..result.unwrap()].to_vec();
 
                 trace!(
                     "Add serialized component {:?}: {} bytes",
@@ -107,82 +618,362 @@ impl Manager {
                     data.len()
                 );
 
-                let message = Message::AddComponent(AddedComponentData {
+                component_data.push(ComponentData {
                     component_type: comp.replicated_component_type(),
-                    spawn_id,
                     replicated_id: comp.id(),
                     data,
                 });
+            }
 
-                for client in clients.iter_mut() {
-                    if let Err(e) = client.send(&message).await {
-                        error!("Failed to send add component message: {}", e);
-                        continue;
-                    }
+            for (component_type, id) in self.reflect_ids.get(&entity.id()).into_iter().flatten() {
+                match self.reflected.serialize(*component_type, entity) {
+                    Ok(data) => component_data.push(ComponentData {
+                        component_type: *component_type,
+                        replicated_id: *id,
+                        data,
+                    }),
+                    Err(e) => error!("Failed to serialize spawn {:?}: {}", id, e),
+                }
+            }
+
+            let message = Message::Spawn(SpawnData {
+                mob_type: *mob_type,
+                spawn_id,
+                payload,
+                components: component_data,
+            });
+            messages.push(message);
+        }
+
+        messages
+    }
+
+    /// Detect hierarchy changes on replicated entities and send them to all synced clients
+    async fn serialize_hierarchy(&mut self, world: &mut World, spawned: &EntityHashSet) {
+        let entities = {
+            let changes = self
+                .hierarchy_changes
+                .get_or_insert_with(|| SystemState::new(world));
+            let (changed, children, mut removed_parents, mut removed_relative) =
+                changes.get_mut(world);
+            let mut entities = changed
+                .iter()
+                .chain(removed_parents.read())
+                .chain(removed_relative.read())
+                .collect::<EntityHashSet>();
+            // Children of newly registered entities may now have a parent that can be replicated
+            for entity in spawned.iter() {
+                entities.insert(*entity);
+                if let Ok(children) = children.get(*entity) {
+                    entities.extend(children.iter().copied());
+                }
+            }
+            entities
+        };
+
+        let mut query = world.query::<(Option<&ChildOf>, Has<RelativeTransform>)>();
+        let mut messages = Vec::new();
+        for entity in entities {
+            let Some(spawn_id) = self.entity_spawn_ids.get(&entity) else {
+                continue;
+            };
+            let Ok((child_of, relative_transform)) = query.get(world, entity) else {
+                continue;
+            };
+
+            // Parents that aren't replicated can't be reconstructed on the client
+            let parent =
+                child_of.and_then(|child_of| self.entity_spawn_ids.get(&child_of.parent()));
+            let unchanged = match self.parents.get(&entity) {
+                Some(previous) => {
+                    previous.parent.as_ref() == parent
+                        && previous.relative_transform == relative_transform
+                }
+                None => parent.is_none() && !relative_transform,
+            };
+            if unchanged {
+                continue;
+            }
+
+            messages.push((
+                entity,
+                ParentData {
+                    spawn_id: *spawn_id,
+                    parent: parent.copied(),
+                    relative_transform,
+                },
+            ));
+        }
+
+        for (entity, parent_data) in messages {
+            debug!("Replicating parent change {:?}", parent_data);
+            let message = Message::SetParent(parent_data.clone());
+            for client in self.clients.iter_mut() {
+                if let Err(e) = client.send(&message).await {
+                    error!("Failed to send parent message: {}", e);
+                }
+            }
+
+            if parent_data.parent.is_some() || parent_data.relative_transform {
+                self.parents.insert(entity, parent_data);
+            } else {
+                self.parents.remove(&entity);
+            }
+        }
+    }
+
+    /// Send replicated resources whose state changed since they were last sent
+    async fn serialize_resources(&mut self, world: &mut World) {
+        // Changes made from here on are newer than the tick recorded for the next check
+        let since = self
+            .resources_checked
+            .replace(world.increment_change_tick());
+        for (resource_type, entry) in self.resources.iter() {
+            let data = match entry.serialize(world, since) {
+                Ok(Some(data)) => data,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Failed to serialize resource {:?}: {}", resource_type, e);
+                    continue;
+                }
+            };
+
+            if self.resource_data.get(resource_type) == Some(&data) {
+                continue;
+            }
+
+            let message = Message::Resource(ResourceData {
+                resource_type: *resource_type,
+                data: data.clone(),
+            });
+            for client in self.clients.iter_mut() {
+                if let Err(e) = client.send(&message).await {
+                    error!("Failed to send resource message: {}", e);
                 }
             }
+            self.resource_data.insert(*resource_type, data);
+        }
+    }
+
+    /// Policy of the component with the given ID, the one set for its replicated type or else
+    /// `declared`
+    fn update_policy(&self, id: Id, declared: UpdatePolicy) -> UpdatePolicy {
+        self.id_owners
+            .get(&id)
+            .and_then(|(_, component_type)| self.update_policies.get(component_type))
+            .copied()
+            .unwrap_or(declared)
+    }
+
+    /// Whether a component must wait for its policy's minimum interval, holding it back if so
+    fn hold_back(&mut self, now: Instant, entity: Entity, id: Id, policy: &UpdatePolicy) -> bool {
+        let held_back = self
+            .last_sent
+            .get(&id)
+            .is_some_and(|last_sent| now.duration_since(*last_sent) < policy.min_interval);
+        if held_back {
+            self.held_back.entry(entity).or_default().insert(id);
         }
+        held_back
+    }
+
+    /// Build an update, `None` if its policy skips it
+    fn policy_update(
+        &mut self,
+        now: Instant,
+        entity: Entity,
+        id: Id,
+        policy: UpdatePolicy,
+        data: Vec<u8>,
+    ) -> Option<(Message, UpdatePolicy, Option<ClientId>)> {
+        if policy.on_change_only {
+            if self.last_data.get(&id) == Some(&data) {
+                return None;
+            }
+            self.last_data.insert(id, data.clone());
+        }
+        if !policy.min_interval.is_zero() {
+            self.last_sent.insert(id, now);
+        }
+
+        let owner = self.owners.get(&entity).copied();
+        Some((Message::Update(UpdateData { id, data }), policy, owner))
     }
 
     pub async fn serialize(&mut self, world: &mut World) {
+        self.remove_closed_clients();
+        self.serialize_despawned(world).await;
+        for added in self.assign_ids(world) {
+            let message = Message::AddComponent(added);
+            for client in self.clients.iter_mut() {
+                if let Err(e) = client.send(&message).await {
+                    error!("Failed to send add component message: {}", e);
+                }
+            }
+        }
+
         if !self.dirty.is_empty() {
             trace!("Dirty entities: {:?}", self.dirty.len());
         }
 
-        let mut query = world.query::<&dyn Replicated>();
-        let mut num_replicated = 0;
-        for replicated in query.iter_many(world, &self.dirty) {
+        let now = Instant::now();
+        let held_back = mem::take(&mut self.held_back);
+        let entities = self
+            .dirty
+            .iter()
+            .chain(
+                held_back
+                    .keys()
+                    .filter(|entity| !self.dirty.contains(*entity)),
+            )
+            .copied()
+            .collect::<Vec<_>>();
+
+        let mut query = world.query::<(Entity, &dyn Replicated)>();
+        let mut updates = Vec::new();
+        for (entity, replicated) in query.iter_many(world, &entities) {
             for component in replicated {
-                num_replicated += 1;
-                let message = Message::Update(UpdateData {
-                    id: component.id(),
-                    data: {
-                        let mut data = vec![0u8; 512];
-                        let result = component.serialize(&mut data);
-                        if result.is_err() {
-                            error!(
-                                "Failed to serialize update {:?}: {}",
-                                component.id(),
-                                result.unwrap_err()
-                            );
-                            continue;
-                        }
-                        let len = result.unwrap();
-                        data.truncate(len);
-                        data
-                    },
+                let id = component.id();
+                // Entities only held back replicate just the components that were held back
+                if !self.dirty.contains(&entity)
+                    && !held_back.get(&entity).is_some_and(|ids| ids.contains(&id))
+                {
+                    continue;
+                }
+
+                let policy = self.update_policy(id, component.update_policy());
+
+                if self.hold_back(now, entity, id, &policy) {
+                    continue;
+                }
+
+                let mut buffer = buffer::get();
+                let result = buffer::serialize(&mut buffer, component.size_hint(), |data| {
+                    component.serialize(data)
                 });
+                if result.is_err() {
+                    error!(
+                        "Failed to serialize update {:?}: {}",
+                        id,
+                        result.unwrap_err()
+                    );
+                    continue;
+                }
+                let data = buffer[..result.unwrap()].to_vec();
+
+                updates.extend(self.policy_update(now, entity, id, policy, data));
+            }
+        }
 
-                trace!("Replicating message {:?}", message);
-                for client in &mut self.clients {
-                    client.send(&message).await.unwrap();
+        for entity in entities.iter() {
+            let Some(reflected) = self.reflect_ids.get(entity).cloned() else {
+                continue;
+            };
+            let Ok(entity_ref) = world.get_entity(*entity) else {
+                continue;
+            };
+
+            for (component_type, id) in reflected {
+                if !self.dirty.contains(entity)
+                    && !held_back.get(entity).is_some_and(|ids| ids.contains(&id))
+                {
+                    continue;
+                }
+
+                let policy = self.update_policy(id, UpdatePolicy::default());
+                if self.hold_back(now, *entity, id, &policy) {
+                    continue;
+                }
+
+                match self.reflected.serialize(component_type, entity_ref) {
+                    Ok(data) => updates.extend(self.policy_update(now, *entity, id, policy, data)),
+                    Err(e) => error!("Failed to serialize update {:?}: {}", id, e),
                 }
             }
         }
-        debug!("Replicated {} components", num_replicated);
+        debug!("Replicated {} components", updates.len());
         self.dirty.clear();
 
+        for (message, policy, owner) in updates {
+            trace!("Replicating message {:?}", message);
+            for client in self.clients.iter_mut() {
+                if policy.owner_only && owner != Some(client.id) {
+                    continue;
+                }
+
+                if let Err(e) = client.send_with(policy.reliability, &message).await {
+                    error!("Failed to send update message: {}", e);
+                }
+            }
+        }
+
         // Next, handle any newly spawned entities
-        if !self.newly_spawned.is_empty() {
-            trace!("Newly spawned entities: {:?}", self.newly_spawned.len());
-            let mut query = world.query::<(EntityRef, &MobType, All<&dyn Replicated>)>();
-            let entities = mem::replace(&mut self.newly_spawned, EntityHashSet::new());
-            self.serialize_spawned(false, query.iter_many(world, entities))
-                .await;
-            self.newly_spawned.clear();
+        let spawned = mem::replace(&mut self.newly_spawned, EntityHashSet::new());
+        if !spawned.is_empty() {
+            trace!("Newly spawned entities: {:?}", spawned.len());
+            let mut query = world.query::<(EntityRef, &MobType, Option<All<&dyn Replicated>>)>();
+            for message in self.spawn_messages(query.iter_many(world, &spawned)) {
+                for client in self.clients.iter_mut() {
+                    if let Err(e) = client.send(&message).await {
+                        error!("Failed to send spawn message: {}", e);
+                    }
+                }
+            }
         }
 
+        // Parents are sent after spawns so the client can resolve them
+        self.serialize_hierarchy(world, &spawned).await;
+        self.serialize_resources(world).await;
+
         // Lastly, handle any clients that are pending their first full state sync
         if !self.pending_full_sync.is_empty() {
             trace!(
                 "Clients pending full sync: {}",
                 self.pending_full_sync.len()
             );
-            let mut query = world.query::<(EntityRef, &MobType, All<&dyn Replicated>)>();
-            self.serialize_spawned(true, query.iter(world)).await;
+            let mut query = world.query::<(EntityRef, &MobType, Option<All<&dyn Replicated>>)>();
+            let mut messages = self.spawn_messages(query.iter(world));
+            messages.extend(
+                self.parents
+                    .values()
+                    .map(|parent_data| Message::SetParent(parent_data.clone())),
+            );
+            messages.extend(self.resource_data.iter().map(|(resource_type, data)| {
+                Message::Resource(ResourceData {
+                    resource_type: *resource_type,
+                    data: data.clone(),
+                })
+            }));
+
+            let snapshot_id = self.next_snapshot_id;
+            self.next_snapshot_id = self.next_snapshot_id.wrapping_add(1);
+            match snapshot::split(snapshot_id, messages, self.snapshot_chunk_size) {
+                Ok(chunks) => {
+                    debug!(
+                        "Sending snapshot {} in {} chunks",
+                        snapshot_id,
+                        chunks.len()
+                    );
+                    for chunk in chunks {
+                        let message = Message::SnapshotChunk(chunk);
+                        for client in self.pending_full_sync.iter_mut() {
+                            if let Err(e) = client.send(&message).await {
+                                error!("Failed to send snapshot chunk: {}", e);
+                            }
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to create snapshot {}: {}", snapshot_id, e),
+            }
 
             let mut drained = self.pending_full_sync.drain(..).collect::<Vec<_>>();
             self.clients.append(&mut drained);
         }
+
+        for client in self.clients.iter_mut() {
+            if let Err(e) = client.flush().await {
+                error!("Failed to flush client {:?}: {}", client.id, e);
+            }
+        }
     }
 }
@@ -0,0 +1,209 @@
+//! Compressed world snapshots sent to clients on their first full sync
+//!
+//! The server encodes every message of a full sync into a single blob, compresses it and splits
+//! it into [`SnapshotChunkData`] pieces. The client reassembles the pieces and only releases the
+//! contained messages once the whole snapshot has arrived.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use bincode::{Decode, Encode};
+
+use crate::{
+    net::buffer::MAX_MESSAGE_SIZE,
+    replication::{Message, SnapshotChunkData},
+};
+
+/// Default size of the compressed data carried by each chunk
+pub const DEFAULT_CHUNK_SIZE: usize = 400;
+
+/// Largest snapshot accepted, before and after compression
+pub const MAX_SNAPSHOT_SIZE: usize = 4 * MAX_MESSAGE_SIZE;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Decode, Encode)]
+struct Snapshot {
+    messages: Vec<Message>,
+}
+
+/// Encode and compress `messages`, splitting the result into chunks of at most `chunk_size` bytes
+pub fn split(
+    snapshot_id: u32,
+    messages: Vec<Message>,
+    chunk_size: usize,
+) -> Result<Vec<SnapshotChunkData>> {
+    let encoded = bincode::encode_to_vec(Snapshot { messages }, bincode::config::standard())?;
+    if encoded.len() > MAX_SNAPSHOT_SIZE {
+        return Err(anyhow::anyhow!(
+            "Snapshot of {} bytes exceeds the maximum of {} bytes",
+            encoded.len(),
+            MAX_SNAPSHOT_SIZE
+        ));
+    }
+    let compressed = zstd::bulk::compress(&encoded, COMPRESSION_LEVEL)?;
+
+    let count = compressed.len().div_ceil(chunk_size).max(1) as u32;
+    let mut chunks = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        let start = index as usize * chunk_size;
+        let end = (start + chunk_size).min(compressed.len());
+        chunks.push(SnapshotChunkData {
+            snapshot_id,
+            index,
+            count,
+            data: compressed[start..end].to_vec(),
+        });
+    }
+
+    Ok(chunks)
+}
+
+/// Progress of a snapshot being received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub received: u32,
+    pub total: u32,
+}
+
+/// Reassembles the chunks of a single snapshot
+pub struct Assembler {
+    snapshot_id: u32,
+    count: u32,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    /// Total size of the chunks received so far
+    size: usize,
+}
+
+impl Assembler {
+    /// Start a snapshot announced to arrive in `count` chunks, each carrying at least one byte
+    pub fn new(snapshot_id: u32, count: u32) -> Result<Self> {
+        if count == 0 || count as usize > MAX_SNAPSHOT_SIZE {
+            return Err(anyhow::anyhow!(
+                "Snapshot {} of {} chunks exceeds the maximum of {} bytes",
+                snapshot_id,
+                count,
+                MAX_SNAPSHOT_SIZE
+            ));
+        }
+
+        Ok(Self {
+            snapshot_id,
+            count,
+            chunks: BTreeMap::new(),
+            size: 0,
+        })
+    }
+
+    pub fn snapshot_id(&self) -> u32 {
+        self.snapshot_id
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            received: self.chunks.len() as u32,
+            total: self.count,
+        }
+    }
+
+    /// Add a chunk, returning the snapshot's messages once every chunk has arrived
+    pub fn add(&mut self, chunk: SnapshotChunkData) -> Result<Option<Vec<Message>>> {
+        if chunk.snapshot_id != self.snapshot_id || chunk.count != self.count {
+            return Err(anyhow::anyhow!(
+                "Chunk of snapshot {} doesn't belong to snapshot {}",
+                chunk.snapshot_id,
+                self.snapshot_id
+            ));
+        }
+
+        if chunk.index >= self.count {
+            return Err(anyhow::anyhow!(
+                "Snapshot chunk index {} out of range",
+                chunk.index
+            ));
+        }
+
+        let replaced = self.chunks.get(&chunk.index).map_or(0, Vec::len);
+        let size = self.size - replaced + chunk.data.len();
+        if size > MAX_SNAPSHOT_SIZE {
+            return Err(anyhow::anyhow!(
+                "Snapshot {} exceeds the maximum of {} bytes",
+                self.snapshot_id,
+                MAX_SNAPSHOT_SIZE
+            ));
+        }
+        self.size = size;
+        self.chunks.insert(chunk.index, chunk.data);
+
+        if self.chunks.len() != self.count as usize {
+            return Ok(None);
+        }
+
+        let compressed = std::mem::take(&mut self.chunks)
+            .into_values()
+            .flatten()
+            .collect::<Vec<u8>>();
+        let encoded = zstd::bulk::decompress(&compressed, MAX_SNAPSHOT_SIZE)?;
+        let (snapshot, _): (Snapshot, _) =
+            bincode::decode_from_slice(&encoded, bincode::config::standard())?;
+        Ok(Some(snapshot.messages))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replication::{DespawnData, SpawnId};
+
+    #[test]
+    fn reassembles_chunks_in_any_order() -> Result<()> {
+        let messages = (0..100)
+            .map(|id| {
+                Message::Despawn(DespawnData {
+                    spawn_id: SpawnId(id),
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut chunks = split(7, messages, 16)?;
+        assert!(chunks.len() > 1);
+        chunks.reverse();
+
+        let mut assembler = Assembler::new(7, chunks[0].count)?;
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert!(assembler.add(chunk)?.is_none());
+        }
+        let messages = assembler.add(last)?.unwrap();
+        assert_eq!(messages.len(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_oversized_snapshots() -> Result<()> {
+        assert!(Assembler::new(0, 0).is_err());
+        assert!(Assembler::new(0, u32::MAX).is_err());
+
+        let mut assembler = Assembler::new(0, 2)?;
+        assert!(
+            assembler
+                .add(SnapshotChunkData {
+                    snapshot_id: 0,
+                    index: 0,
+                    count: 2,
+                    data: vec![0; MAX_SNAPSHOT_SIZE + 1],
+                })
+                .is_err()
+        );
+        assert!(
+            assembler
+                .add(SnapshotChunkData {
+                    snapshot_id: 0,
+                    index: 2,
+                    count: 2,
+                    data: vec![0],
+                })
+                .is_err()
+        );
+        Ok(())
+    }
+}
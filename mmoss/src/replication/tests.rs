@@ -0,0 +1,680 @@
+//! Replication between a server and a client manager connected in memory
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bevy::{
+    ecs::{
+        component::Component, entity::Entity, hierarchy::ChildOf, observer::On, resource::Resource,
+        system::ResMut, world::World,
+    },
+    reflect::Reflect,
+};
+use bevy_trait_query::RegisterExt as _;
+use bincode::{Decode, Encode};
+
+use crate::{
+    core::WorldContainer,
+    net::transport::memory,
+    net::transport::{Message as _, MessageFactory as _, Unreliable as _},
+    physics::{
+        RelativeTransform,
+        proxy::{DynamicActorComponentProxy, StaticActorComponentProxy},
+    },
+    replication::{
+        ComponentData, ComponentType, Id, LoginResultData, Message, MessageFactoryNew, MobType,
+        ParentData, Replicated, ResourceType, SpawnData, SpawnId,
+        client::{
+            self, Incoming, NoopUpdateCallbacks, event,
+            factory::{component, mob},
+        },
+        policy::{Reliability, UpdatePolicy},
+        server,
+    },
+};
+
+const MOB_TYPE: MobType = MobType(1);
+
+const STATS_TYPE: ComponentType = ComponentType(1000);
+
+#[derive(Debug, PartialEq, Resource, Encode, Decode)]
+struct Score(u32);
+
+#[derive(Debug, Default, PartialEq, Component, Reflect)]
+struct Stats {
+    health: u32,
+    mana: u32,
+    /// Not replicated
+    seed: u32,
+}
+
+struct EmptyMob;
+
+#[async_trait(?Send)]
+impl mob::Entry<World> for EmptyMob {
+    async fn construct(&self, world: &mut World, _payload: Option<&[u8]>) -> Result<Entity> {
+        Ok(world.world_mut().spawn_empty().id())
+    }
+}
+
+struct Client {
+    id: server::ClientId,
+    manager: client::Manager<World>,
+    incoming: Incoming,
+    world: World,
+}
+
+impl Client {
+    fn new(server: &mut server::Manager) -> Self {
+        let (server_transport, client_transport) =
+            memory::pair(MessageFactoryNew, MessageFactoryNew);
+        let id = server.add_client(Box::new(server_transport));
+
+        let mut world = World::new();
+        let mut mobs = mob::Factory::new();
+        mobs.register_mob(MOB_TYPE, EmptyMob);
+        let mut components = component::Factory::new();
+        component::register_replicated::<DynamicActorComponentProxy, _>(
+            &mut world,
+            &mut components,
+        );
+
+        let (manager, incoming) = client::Manager::new(
+            Box::new(client_transport),
+            Arc::new(mobs),
+            Arc::new(components),
+        );
+        Self {
+            id,
+            manager,
+            incoming,
+            world,
+        }
+    }
+
+    /// Apply everything the server sent so far
+    async fn update(&mut self) {
+        while let Ok(result) =
+            tokio::time::timeout(Duration::from_millis(20), self.incoming.process_incoming()).await
+        {
+            result.unwrap();
+        }
+        self.manager
+            .update_world(&mut self.world, &mut NoopUpdateCallbacks)
+            .await;
+    }
+
+    fn proxy(&self, entity: Entity) -> Option<&DynamicActorComponentProxy> {
+        self.world.get::<DynamicActorComponentProxy>(entity)
+    }
+
+    /// Translation of the client's replica of `entity`
+    fn x(&self, server: &server::Manager, entity: Entity) -> f32 {
+        let spawn_id = server.spawn_id_by_entity(entity).unwrap();
+        let replicated = self.manager.entity_by_spawn_id(spawn_id).unwrap();
+        self.proxy(replicated).unwrap().transform.translation.x
+    }
+
+    fn set_x(&mut self, server: &server::Manager, entity: Entity, x: f32) {
+        let spawn_id = server.spawn_id_by_entity(entity).unwrap();
+        let replicated = self.manager.entity_by_spawn_id(spawn_id).unwrap();
+        set_x(&mut self.world, replicated, x);
+    }
+}
+
+fn set_x(world: &mut World, entity: Entity, x: f32) {
+    world
+        .get_mut::<DynamicActorComponentProxy>(entity)
+        .unwrap()
+        .transform
+        .translation
+        .x = x;
+}
+
+/// Server with a registered mob whose proxy is replicated with `policy`
+fn policy_server(policy: UpdatePolicy) -> (World, server::Manager, Entity) {
+    let mut world = World::new();
+    world.register_component_as::<dyn Replicated, DynamicActorComponentProxy>();
+    let mut server = server::Manager::new();
+    server.set_update_policy(
+        DynamicActorComponentProxy::default().replicated_component_type(),
+        policy,
+    );
+
+    let entity = world
+        .spawn((MOB_TYPE, DynamicActorComponentProxy::default()))
+        .id();
+    server.register_new_entity(entity);
+    (world, server, entity)
+}
+
+#[tokio::test]
+async fn replicates_spawn_update_and_despawn() {
+    let mut world = World::new();
+    world.register_component_as::<dyn Replicated, DynamicActorComponentProxy>();
+    let mut server = server::Manager::new();
+    let mut client = Client::new(&mut server);
+    server.serialize(&mut world).await;
+
+    let entity = world
+        .spawn((MOB_TYPE, DynamicActorComponentProxy::default()))
+        .id();
+    server.register_new_entity(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    let spawn_id = server.spawn_id_by_entity(entity).unwrap();
+    let replicated = client.manager.entity_by_spawn_id(spawn_id).unwrap();
+    let id = world.get::<DynamicActorComponentProxy>(entity).unwrap().id;
+    assert_eq!(client.proxy(replicated).unwrap().id, id);
+    assert_eq!(client.manager.entity_by_replicated_id(id), Some(replicated));
+    let component_type = DynamicActorComponentProxy::default().component_type();
+    assert_eq!(
+        client.manager.component_by_replicated_id(id),
+        Some((replicated, component_type))
+    );
+    assert_eq!(
+        server.component_by_replicated_id(id),
+        Some((entity, component_type))
+    );
+
+    world
+        .get_mut::<DynamicActorComponentProxy>(entity)
+        .unwrap()
+        .transform
+        .translation
+        .x = 4.0;
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(
+        client.proxy(replicated).unwrap().transform.translation.x,
+        4.0
+    );
+
+    world.despawn(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert!(client.world.get_entity(replicated).is_err());
+    assert_eq!(client.manager.entity_by_spawn_id(spawn_id), None);
+    assert_eq!(client.manager.component_by_replicated_id(id), None);
+}
+
+#[tokio::test]
+async fn late_client_receives_existing_entities() {
+    let mut world = World::new();
+    world.register_component_as::<dyn Replicated, DynamicActorComponentProxy>();
+    let mut server = server::Manager::new();
+    let entity = world
+        .spawn((MOB_TYPE, DynamicActorComponentProxy::default()))
+        .id();
+    server.register_new_entity(entity);
+    server.serialize(&mut world).await;
+
+    let mut client = Client::new(&mut server);
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    let spawn_id = server.spawn_id_by_entity(entity).unwrap();
+    let replicated = client.manager.entity_by_spawn_id(spawn_id).unwrap();
+    assert!(client.proxy(replicated).is_some());
+}
+
+#[tokio::test]
+async fn mob_with_unknown_component_is_not_spawned() {
+    let mut world = World::new();
+    world.register_component_as::<dyn Replicated, DynamicActorComponentProxy>();
+    world.register_component_as::<dyn Replicated, StaticActorComponentProxy>();
+    let mut server = server::Manager::new();
+    let mut client = Client::new(&mut server);
+    server.serialize(&mut world).await;
+
+    // The client has no factory entry for the static proxy
+    let entity = world
+        .spawn((
+            MOB_TYPE,
+            DynamicActorComponentProxy::default(),
+            StaticActorComponentProxy::default(),
+        ))
+        .id();
+    server.register_new_entity(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    let spawn_id = server.spawn_id_by_entity(entity).unwrap();
+    let id = world.get::<DynamicActorComponentProxy>(entity).unwrap().id;
+    assert_eq!(client.manager.entity_by_spawn_id(spawn_id), None);
+    assert_eq!(client.manager.entity_by_replicated_id(id), None);
+    assert_eq!(client.world.entities().len(), 0);
+}
+
+#[tokio::test]
+async fn replicates_changed_resources() {
+    let mut world = World::new();
+    let mut server = server::Manager::new();
+    server.register_resource::<Score>(ResourceType(1));
+    let mut client = Client::new(&mut server);
+    client.manager.register_resource::<Score>(ResourceType(1));
+    server.serialize(&mut world).await;
+
+    world.insert_resource(Score(1));
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(client.world.get_resource::<Score>(), Some(&Score(1)));
+
+    // Unchanged resources aren't sent again
+    client.world.insert_resource(Score(0));
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(client.world.get_resource::<Score>(), Some(&Score(0)));
+
+    world.resource_mut::<Score>().0 = 2;
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(client.world.get_resource::<Score>(), Some(&Score(2)));
+}
+
+#[tokio::test]
+async fn replicates_components_inserted_after_spawn() {
+    let mut world = World::new();
+    world.register_component_as::<dyn Replicated, DynamicActorComponentProxy>();
+    let mut server = server::Manager::new();
+    let mut client = Client::new(&mut server);
+
+    let entity = world.spawn(MOB_TYPE).id();
+    server.register_new_entity(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    let spawn_id = server.spawn_id_by_entity(entity).unwrap();
+    let replicated = client.manager.entity_by_spawn_id(spawn_id).unwrap();
+    assert!(client.proxy(replicated).is_none());
+
+    world
+        .entity_mut(entity)
+        .insert(DynamicActorComponentProxy::default());
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    let id = world.get::<DynamicActorComponentProxy>(entity).unwrap().id;
+    assert_ne!(id, Id::UNASSIGNED);
+    assert_eq!(client.proxy(replicated).unwrap().id, id);
+    assert_eq!(client.manager.entity_by_replicated_id(id), Some(replicated));
+}
+
+#[tokio::test]
+async fn replicates_hierarchy_changes() {
+    let mut world = World::new();
+    let mut server = server::Manager::new();
+    let mut client = Client::new(&mut server);
+
+    let parent = world.spawn(MOB_TYPE).id();
+    let child = world.spawn(MOB_TYPE).id();
+    server.register_new_entity(parent);
+    server.register_new_entity(child);
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    let spawn_ids = (
+        server.spawn_id_by_entity(parent).unwrap(),
+        server.spawn_id_by_entity(child).unwrap(),
+    );
+    let replicated = |client: &Client| {
+        (
+            client.manager.entity_by_spawn_id(spawn_ids.0).unwrap(),
+            client.manager.entity_by_spawn_id(spawn_ids.1).unwrap(),
+        )
+    };
+    let (replicated_parent, replicated_child) = replicated(&client);
+
+    world.entity_mut(child).insert(ChildOf(parent));
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(
+        client
+            .world
+            .get::<ChildOf>(replicated_child)
+            .map(ChildOf::parent),
+        Some(replicated_parent)
+    );
+
+    // Root entities replicate their relative transform too
+    world.entity_mut(parent).insert(RelativeTransform);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert!(
+        client
+            .world
+            .get::<RelativeTransform>(replicated_parent)
+            .is_some()
+    );
+
+    // Clients joining later get the hierarchy with their snapshot
+    let mut late = Client::new(&mut server);
+    server.serialize(&mut world).await;
+    late.update().await;
+    let (late_parent, late_child) = replicated(&late);
+    assert_eq!(
+        late.world.get::<ChildOf>(late_child).map(ChildOf::parent),
+        Some(late_parent)
+    );
+
+    world.entity_mut(child).remove::<ChildOf>();
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert!(client.world.get::<ChildOf>(replicated_child).is_none());
+}
+
+#[test]
+fn messages_round_trip() {
+    let messages = [
+        Message::Spawn(SpawnData {
+            mob_type: MOB_TYPE,
+            spawn_id: SpawnId(3),
+            payload: Some(vec![1, 2]),
+            components: vec![ComponentData {
+                component_type: DynamicActorComponentProxy::default().component_type(),
+                replicated_id: Id(7),
+                data: vec![4, 5, 6],
+            }],
+        }),
+        Message::SetParent(ParentData {
+            spawn_id: SpawnId(3),
+            parent: None,
+            relative_transform: true,
+        }),
+        Message::LoginResult(LoginResultData {
+            accepted: false,
+            reason: Some("Banned".to_string()),
+        }),
+    ];
+
+    for message in messages {
+        let mut data = vec![0; message.size_hint()];
+        let len = message.serialize(&mut data).unwrap();
+        let (decoded, read) = MessageFactoryNew.deserialize(&(), &data[..len]).unwrap();
+        assert_eq!(read, len);
+        assert_eq!(format!("{decoded:?}"), format!("{message:?}"));
+    }
+}
+
+#[tokio::test]
+async fn replicates_selected_reflected_fields() {
+    let mut world = World::new();
+    let mut server = server::Manager::new();
+    server
+        .register_reflect_component::<Stats>(STATS_TYPE, &["health", "mana"])
+        .unwrap();
+    let mut client = Client::new(&mut server);
+    client
+        .manager
+        .register_reflect_component::<Stats>(STATS_TYPE, &["health", "mana"])
+        .unwrap();
+
+    let entity = world
+        .spawn((
+            MOB_TYPE,
+            Stats {
+                health: 10,
+                mana: 5,
+                seed: 7,
+            },
+        ))
+        .id();
+    server.register_new_entity(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    let spawn_id = server.spawn_id_by_entity(entity).unwrap();
+    let replicated = client.manager.entity_by_spawn_id(spawn_id).unwrap();
+    assert_eq!(
+        client.world.get::<Stats>(replicated),
+        Some(&Stats {
+            health: 10,
+            mana: 5,
+            seed: 0,
+        })
+    );
+
+    let mut stats = world.get_mut::<Stats>(entity).unwrap();
+    stats.health = 3;
+    stats.seed = 9;
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(
+        client.world.get::<Stats>(replicated),
+        Some(&Stats {
+            health: 3,
+            mana: 5,
+            seed: 0,
+        })
+    );
+}
+
+#[test]
+fn reflected_fields_must_exist() {
+    let mut server = server::Manager::new();
+    let error = server
+        .register_reflect_component::<Stats>(STATS_TYPE, &["health", "stamina"])
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("has no field stamina"),
+        "{error}"
+    );
+}
+
+#[tokio::test]
+async fn holds_back_updates_above_max_rate() {
+    let (mut world, mut server, entity) = policy_server(UpdatePolicy::default().with_max_rate(2.0));
+    let mut client = Client::new(&mut server);
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    set_x(&mut world, entity, 1.0);
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(client.x(&server, entity), 1.0);
+
+    set_x(&mut world, entity, 2.0);
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(client.x(&server, entity), 1.0);
+
+    // Held back updates go out once the interval passed, without being marked dirty again
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(client.x(&server, entity), 2.0);
+}
+
+#[tokio::test]
+async fn skips_unchanged_updates_on_change_only() {
+    let (mut world, mut server, entity) =
+        policy_server(UpdatePolicy::default().with_on_change_only(true));
+    let mut client = Client::new(&mut server);
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    set_x(&mut world, entity, 1.0);
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(client.x(&server, entity), 1.0);
+
+    // Local edits on the client show whether the server sent the same state again
+    client.set_x(&server, entity, 0.0);
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(client.x(&server, entity), 0.0);
+
+    set_x(&mut world, entity, 2.0);
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(client.x(&server, entity), 2.0);
+}
+
+#[tokio::test]
+async fn sends_owner_only_updates_to_the_owner() {
+    let (mut world, mut server, entity) =
+        policy_server(UpdatePolicy::default().with_owner_only(true));
+    let mut owner = Client::new(&mut server);
+    let mut other = Client::new(&mut server);
+    server.set_owner(entity, Some(owner.id));
+    server.serialize(&mut world).await;
+    owner.update().await;
+    other.update().await;
+
+    set_x(&mut world, entity, 1.0);
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    owner.update().await;
+    other.update().await;
+    assert_eq!(owner.x(&server, entity), 1.0);
+    assert_eq!(other.x(&server, entity), 0.0);
+}
+
+#[tokio::test]
+async fn routes_updates_by_reliability() {
+    /// Messages received so far, ignoring the initial snapshot
+    fn updates(transport: &mut memory::Memory<MessageFactoryNew>) -> Vec<Id> {
+        let mut ids = Vec::new();
+        while let Some(message) = transport.try_receive().unwrap() {
+            if let Message::Update(update) = message {
+                ids.push(update.id);
+            }
+        }
+        ids
+    }
+
+    let (mut world, mut server, entity) =
+        policy_server(UpdatePolicy::default().with_reliability(Reliability::Unreliable));
+    let (reliable, mut client_reliable) = memory::pair(MessageFactoryNew, MessageFactoryNew);
+    let (unreliable, mut client_unreliable) = memory::pair(MessageFactoryNew, MessageFactoryNew);
+    server.add_client_with_unreliable(Box::new(reliable), Box::new(unreliable));
+    server.serialize(&mut world).await;
+    let id = world.get::<DynamicActorComponentProxy>(entity).unwrap().id;
+
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    assert_eq!(updates(&mut client_reliable), vec![]);
+    assert_eq!(updates(&mut client_unreliable), vec![id]);
+
+    server.set_update_policy(
+        DynamicActorComponentProxy::default().replicated_component_type(),
+        UpdatePolicy::default(),
+    );
+    server.mark_dirty(entity);
+    server.serialize(&mut world).await;
+    assert_eq!(updates(&mut client_reliable), vec![id]);
+    assert_eq!(updates(&mut client_unreliable), vec![]);
+}
+
+/// Client events in the order they were triggered
+#[derive(Debug, PartialEq)]
+enum Observed {
+    Spawned(SpawnId),
+    ComponentAdded(SpawnId, Id),
+    ComponentUpdated(SpawnId, Id),
+    ParentChanged(SpawnId, Option<Entity>),
+    Despawned(SpawnId),
+}
+
+#[derive(Default, Resource)]
+struct ObservedEvents(Vec<Observed>);
+
+#[tokio::test]
+async fn triggers_client_events() {
+    let mut world = World::new();
+    world.register_component_as::<dyn Replicated, DynamicActorComponentProxy>();
+    let mut server = server::Manager::new();
+    let mut client = Client::new(&mut server);
+    client.world.init_resource::<ObservedEvents>();
+    client.world.add_observer(
+        |event: On<event::Spawned>, mut observed: ResMut<ObservedEvents>| {
+            observed.0.push(Observed::Spawned(event.spawn_id));
+        },
+    );
+    client.world.add_observer(
+        |event: On<event::ComponentAdded>, mut observed: ResMut<ObservedEvents>| {
+            observed.0.push(Observed::ComponentAdded(
+                event.spawn_id,
+                event.replicated_id,
+            ));
+        },
+    );
+    client.world.add_observer(
+        |event: On<event::ComponentUpdated>, mut observed: ResMut<ObservedEvents>| {
+            observed.0.push(Observed::ComponentUpdated(
+                event.spawn_id,
+                event.replicated_id,
+            ));
+        },
+    );
+    client.world.add_observer(
+        |event: On<event::ParentChanged>, mut observed: ResMut<ObservedEvents>| {
+            observed
+                .0
+                .push(Observed::ParentChanged(event.spawn_id, event.parent));
+        },
+    );
+    client.world.add_observer(
+        |event: On<event::Despawned>, mut observed: ResMut<ObservedEvents>| {
+            observed.0.push(Observed::Despawned(event.spawn_id));
+        },
+    );
+    server.serialize(&mut world).await;
+    client.update().await;
+
+    let take =
+        |client: &mut Client| std::mem::take(&mut client.world.resource_mut::<ObservedEvents>().0);
+
+    let parent = world
+        .spawn((MOB_TYPE, DynamicActorComponentProxy::default()))
+        .id();
+    server.register_new_entity(parent);
+    server.serialize(&mut world).await;
+    client.update().await;
+    let parent_spawn_id = server.spawn_id_by_entity(parent).unwrap();
+    let id = world.get::<DynamicActorComponentProxy>(parent).unwrap().id;
+    assert_eq!(
+        take(&mut client),
+        vec![
+            Observed::Spawned(parent_spawn_id),
+            Observed::ComponentAdded(parent_spawn_id, id),
+        ]
+    );
+
+    let child = world.spawn((MOB_TYPE, ChildOf(parent))).id();
+    server.register_new_entity(child);
+    server.serialize(&mut world).await;
+    client.update().await;
+    let child_spawn_id = server.spawn_id_by_entity(child).unwrap();
+    let replicated_parent = client.manager.entity_by_spawn_id(parent_spawn_id);
+    assert_eq!(
+        take(&mut client),
+        vec![
+            Observed::Spawned(child_spawn_id),
+            Observed::ParentChanged(child_spawn_id, replicated_parent),
+        ]
+    );
+
+    set_x(&mut world, parent, 1.0);
+    server.mark_dirty(parent);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(
+        take(&mut client),
+        vec![Observed::ComponentUpdated(parent_spawn_id, id)]
+    );
+
+    world.despawn(child);
+    server.serialize(&mut world).await;
+    client.update().await;
+    assert_eq!(take(&mut client), vec![Observed::Despawned(child_spawn_id)]);
+}